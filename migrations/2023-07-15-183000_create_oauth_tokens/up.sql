@@ -0,0 +1,8 @@
+CREATE TABLE oauth_tokens (
+    -- tokens are issued per trakt application
+    client_id VARCHAR PRIMARY KEY NOT NULL,
+    access_token VARCHAR NOT NULL,
+    refresh_token VARCHAR NOT NULL,
+    created_at DATETIME NOT NULL,
+    expires_at DATETIME NOT NULL
+);
@@ -0,0 +1 @@
+DROP TABLE synced_history
\ No newline at end of file
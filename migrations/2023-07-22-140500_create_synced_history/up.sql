@@ -0,0 +1,11 @@
+-- history entries that trakt has accepted from us
+CREATE TABLE synced_history (
+    kind TEXT CHECK(kind IN ('show', 'season', 'episode')) NOT NULL,
+    -- trakt_id of the show/season/episode
+    trakt_id INTEGER NOT NULL,
+    -- NULL if trakt should use the release date
+    watched_at DATETIME,
+    synced_at DATETIME NOT NULL,
+
+    PRIMARY KEY(kind, trakt_id)
+);
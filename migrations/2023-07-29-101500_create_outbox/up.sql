@@ -0,0 +1,20 @@
+-- local status changes that still need to be sent to trakt
+CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    kind TEXT CHECK(kind IN ('show', 'season', 'episode')) NOT NULL,
+    -- trakt_id of the show/season/episode that changed
+    trakt_id INTEGER NOT NULL,
+    -- see models::OutboxOperation
+    operation TEXT NOT NULL,
+    -- for history entries: NULL if trakt should use the release date
+    watched_at DATETIME,
+    -- JSON body for the request
+    payload TEXT NOT NULL,
+    state TEXT CHECK(state IN ('pending', 'done', 'failed')) NOT NULL,
+    attempts INTEGER NOT NULL,
+    next_attempt_at DATETIME NOT NULL,
+    last_error TEXT,
+    created_at DATETIME NOT NULL
+);
+
+CREATE INDEX outbox_state ON outbox(state, next_attempt_at);
@@ -0,0 +1 @@
+ALTER TABLE seasons DROP COLUMN watched_at;
@@ -0,0 +1,2 @@
+-- the date picked for OTHER_DATE seasons (NULL: let trakt use the time of the sync)
+ALTER TABLE seasons ADD COLUMN watched_at DATETIME;
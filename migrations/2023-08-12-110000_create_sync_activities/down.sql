@@ -0,0 +1,2 @@
+DROP TABLE ratings;
+DROP TABLE sync_activities
\ No newline at end of file
@@ -0,0 +1,17 @@
+-- the last activity time trakt reported for each kind of user data we pull
+CREATE TABLE sync_activities (
+    category TEXT CHECK(category IN ('watched', 'watchlist', 'ratings', 'hidden')) PRIMARY KEY NOT NULL,
+    updated_at DATETIME NOT NULL
+);
+
+-- the user's ratings on trakt
+CREATE TABLE ratings (
+    kind TEXT CHECK(kind IN ('show', 'season', 'episode')) NOT NULL,
+    -- trakt_id of the show/season/episode
+    trakt_id INTEGER NOT NULL,
+    -- 1 to 10
+    rating INTEGER NOT NULL,
+    rated_at DATETIME NOT NULL,
+
+    PRIMARY KEY(kind, trakt_id)
+);
@@ -0,0 +1 @@
+ALTER TABLE trakt_shows DROP COLUMN status_changed_at;
@@ -0,0 +1,2 @@
+-- when the user last set the show's status (NULL if it was never set on purpose)
+ALTER TABLE trakt_shows ADD COLUMN status_changed_at DATETIME;
@@ -0,0 +1,2 @@
+DROP TABLE sync_batch_items;
+DROP TABLE sync_batches
\ No newline at end of file
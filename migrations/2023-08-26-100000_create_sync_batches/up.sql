@@ -0,0 +1,20 @@
+-- every push of local history to trakt, so it can be reverted
+CREATE TABLE sync_batches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    pushed_at DATETIME NOT NULL,
+    -- NULL until the batch is reverted
+    reverted_at DATETIME
+);
+
+-- the history entries a push added to or removed from trakt
+CREATE TABLE sync_batch_items (
+    batch_id INTEGER NOT NULL REFERENCES sync_batches(id),
+    kind TEXT CHECK(kind IN ('show', 'season', 'episode')) NOT NULL,
+    -- trakt_id of the show/season/episode
+    trakt_id INTEGER NOT NULL,
+    change TEXT CHECK(change IN ('added', 'removed')) NOT NULL,
+    -- when it was (or had been) watched, NULL for the release date
+    watched_at DATETIME,
+
+    PRIMARY KEY(batch_id, kind, trakt_id, change)
+);
@@ -0,0 +1,40 @@
+CREATE TABLE seasons_old (
+    id INTEGER PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL,
+    first_aired DATETIME,
+    show_id INTEGER NOT NULL,
+    season_number INTEGER NOT NULL,
+    episode_count INTEGER NOT NULL,
+    user_status TEXT CHECK(user_status IN ('unfilled', 'on_release', 'other_date')) NOT NULL,
+    watched_at DATETIME,
+
+    FOREIGN KEY(show_id) REFERENCES trakt_shows(trakt_id)
+);
+INSERT INTO seasons_old
+SELECT s.id, s.title, s.first_aired, t.trakt_id, s.season_number, s.episode_count,
+       s.user_status, s.watched_at
+FROM seasons s
+JOIN trakt_shows t ON t.imdb_id = s.show_imdb_id
+WHERE t.trakt_id IS NOT NULL;
+
+CREATE TABLE episodes_old (
+    id INTEGER PRIMARY KEY NOT NULL,
+    show_id INTEGER NOT NULL,
+    season_number INTEGER NOT NULL,
+    episode_number INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    first_aired DATETIME,
+    watched_at DATETIME,
+    user_status TEXT CHECK(user_status IN ('unwatched', 'watched')) NOT NULL,
+
+    FOREIGN KEY(show_id) REFERENCES seasons(id)
+);
+INSERT INTO episodes_old
+SELECT id, show_id, season_number, episode_number, title, first_aired, watched_at, user_status
+FROM episodes;
+
+DROP TABLE episodes;
+DROP TABLE seasons;
+ALTER TABLE seasons_old RENAME TO seasons;
+ALTER TABLE episodes_old RENAME TO episodes;
+DROP INDEX trakt_shows_trakt_id;
@@ -0,0 +1,85 @@
+-- seasons hang off their show, episodes off their season.
+-- sqlite can't change the keys of an existing table, so seasons and episodes are rebuilt.
+
+-- a trakt id belongs to one show. older dbs may have the odd duplicate: keep the copy the
+-- user set a status on, or else the first. the others are un-linked (the app logs which).
+UPDATE trakt_shows SET trakt_id = NULL
+WHERE trakt_id IS NOT NULL
+  AND rowid NOT IN (
+    SELECT show_rowid FROM (
+        SELECT rowid AS show_rowid, ROW_NUMBER() OVER (
+            PARTITION BY trakt_id
+            ORDER BY (user_status != 'todo' OR status_changed_at IS NOT NULL) DESC, rowid
+        ) AS copy
+        FROM trakt_shows
+        WHERE trakt_id IS NOT NULL
+    )
+    WHERE copy = 1
+  );
+CREATE UNIQUE INDEX trakt_shows_trakt_id ON trakt_shows(trakt_id);
+
+CREATE TABLE seasons_new (
+    -- trakt_id
+    id INTEGER PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL,
+    first_aired DATETIME,
+    -- (the show's trakt_id comes from joining trakt_shows)
+    show_imdb_id TEXT NOT NULL REFERENCES trakt_shows(imdb_id) ON UPDATE CASCADE ON DELETE CASCADE,
+    season_number INTEGER NOT NULL,
+    episode_count INTEGER NOT NULL,
+    user_status TEXT CHECK(user_status IN ('unfilled', 'on_release', 'other_date')) NOT NULL,
+    -- the date picked for OTHER_DATE seasons (NULL: let trakt use the time of the sync)
+    watched_at DATETIME,
+
+    UNIQUE(show_imdb_id, season_number)
+);
+
+-- seasons of shows we don't have can't be reached anyway. of two copies of a season, keep
+-- the one the user set a status on, or else the newest.
+-- (the app logs how many rows this leaves behind: the pre-migration backup still has them)
+INSERT INTO seasons_new
+SELECT s.id, s.title, s.first_aired, t.imdb_id, s.season_number, s.episode_count,
+       s.user_status, s.watched_at
+FROM (
+    SELECT *, ROW_NUMBER() OVER (
+        PARTITION BY show_id, season_number
+        ORDER BY user_status != 'unfilled' DESC, rowid DESC
+    ) AS copy
+    FROM seasons
+) s
+JOIN trakt_shows t ON t.trakt_id = s.show_id
+WHERE s.copy = 1;
+
+DROP TABLE seasons;
+ALTER TABLE seasons_new RENAME TO seasons;
+
+CREATE TABLE episodes_new (
+    -- trakt_id
+    id INTEGER PRIMARY KEY NOT NULL,
+    season_id INTEGER NOT NULL REFERENCES seasons(id) ON DELETE CASCADE,
+    -- the show's trakt_id and the season's number, kept for lookups
+    show_id INTEGER NOT NULL,
+    season_number INTEGER NOT NULL,
+    episode_number INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    first_aired DATETIME,
+
+    -- datetime for when user watched this episode
+    watched_at DATETIME,
+
+    user_status TEXT CHECK(user_status IN ('unwatched', 'watched')) NOT NULL
+);
+
+-- episodes of seasons that didn't make it are dropped with them
+INSERT INTO episodes_new
+SELECT e.id, s.id, e.show_id, e.season_number, e.episode_number, e.title, e.first_aired,
+       e.watched_at, e.user_status
+FROM episodes e
+JOIN trakt_shows t ON t.trakt_id = e.show_id
+JOIN seasons s ON s.show_imdb_id = t.imdb_id AND s.season_number = e.season_number;
+
+DROP TABLE episodes;
+ALTER TABLE episodes_new RENAME TO episodes;
+
+CREATE INDEX episodes_season_id ON episodes(season_id);
+CREATE INDEX episodes_season_number ON episodes(show_id, season_number);
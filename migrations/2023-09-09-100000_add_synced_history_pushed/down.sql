@@ -0,0 +1 @@
+ALTER TABLE synced_history DROP COLUMN pushed;
@@ -0,0 +1,3 @@
+-- whether we put the entry's plays on trakt, rather than finding them there (e.g. when
+-- importing): only our own plays are ever taken off trakt again
+ALTER TABLE synced_history ADD COLUMN pushed BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+ALTER TABLE sync_batch_items DROP COLUMN previous_watched_at;
+ALTER TABLE sync_batch_items DROP COLUMN previous_watched;
+ALTER TABLE sync_batch_items DROP COLUMN pushed;
@@ -0,0 +1,29 @@
+-- for removed entries: whether their plays were ours, and so taken off trakt (otherwise
+-- they were only forgotten, and trakt still has them)
+ALTER TABLE sync_batch_items ADD COLUMN pushed BOOLEAN NOT NULL DEFAULT 1;
+-- the item's local state before the push, which a revert puts back: watched or not, and
+-- when (NULL for the release date)
+ALTER TABLE sync_batch_items ADD COLUMN previous_watched BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE sync_batch_items ADD COLUMN previous_watched_at DATETIME;
+
+-- what was removed had been watched, and so had what was re-added at another time
+UPDATE sync_batch_items
+SET previous_watched = 1, previous_watched_at = watched_at
+WHERE change = 'removed';
+
+UPDATE sync_batch_items
+SET previous_watched = 1,
+    previous_watched_at = (
+        SELECT removed.watched_at FROM sync_batch_items AS removed
+        WHERE removed.batch_id = sync_batch_items.batch_id
+          AND removed.kind = sync_batch_items.kind
+          AND removed.trakt_id = sync_batch_items.trakt_id
+          AND removed.change = 'removed'
+    )
+WHERE change = 'added' AND EXISTS (
+    SELECT 1 FROM sync_batch_items AS removed
+    WHERE removed.batch_id = sync_batch_items.batch_id
+      AND removed.kind = sync_batch_items.kind
+      AND removed.trakt_id = sync_batch_items.trakt_id
+      AND removed.change = 'removed'
+);
@@ -0,0 +1,237 @@
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use dotenvy::dotenv;
+
+use crate::trakt::t_cassette::CassetteMode;
+
+const DEFAULT_USER_AGENT: &str = "Trakt TV Selector";
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+/// Which trakt deployment to talk to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Profile {
+    #[default]
+    Production,
+    Staging,
+    /// for testing, i've copied over several JSON responses and host them locally.
+    Local,
+}
+
+impl Profile {
+    fn base_url(self) -> &'static str {
+        match self {
+            Profile::Production => "https://api.trakt.tv",
+            Profile::Staging => "https://api-staging.trakt.tv",
+            Profile::Local => "http://127.0.0.1:8080",
+        }
+    }
+
+    // prefix for settings that only apply to this profile, e.g. TRAKT_STAGING_CLIENT_ID
+    fn env_prefix(self) -> &'static str {
+        match self {
+            Profile::Production => "TRAKT_PRODUCTION",
+            Profile::Staging => "TRAKT_STAGING",
+            Profile::Local => "TRAKT_LOCAL",
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "production" | "prod" => Ok(Profile::Production),
+            "staging" => Ok(Profile::Staging),
+            "local" | "mock" => Ok(Profile::Local),
+            other => eyre::bail!(
+                "unknown trakt profile '{}' (expected production, staging or local)",
+                other
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Profile::Production => "production",
+            Profile::Staging => "staging",
+            Profile::Local => "local",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Everything needed to talk to trakt, resolved from the environment (and `.env`).
+///
+/// The profile comes from `--profile` or `TRAKT_PROFILE` (default: production).
+/// Each setting can be given per profile (`TRAKT_STAGING_CLIENT_ID`, `TRAKT_STAGING_URL`) or
+/// for all of them (`CLIENT_ID`, `TRAKT_URL`); the per-profile one wins.
+#[derive(Clone, Debug)]
+pub struct TraktConfig {
+    pub profile: Profile,
+    pub base_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub timeout: Duration,
+    pub user_agent: String,
+    /// record trakt traffic to (or replay it from) this file: `TRAKT_CASSETTE`,
+    /// with `TRAKT_CASSETTE_MODE` set to `record` or `replay` (the default)
+    pub cassette: Option<PathBuf>,
+    pub cassette_mode: CassetteMode,
+}
+
+impl TraktConfig {
+    /// Load the config for `profile`, or whichever one `TRAKT_PROFILE` names.
+    pub fn load(profile: Option<Profile>) -> eyre::Result<TraktConfig> {
+        dotenv().ok();
+        Self::from_vars(profile, |key| env::var(key).ok())
+    }
+
+    fn from_vars(
+        profile: Option<Profile>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> eyre::Result<TraktConfig> {
+        let profile = match profile {
+            Some(p) => p,
+            None => var("TRAKT_PROFILE")
+                .map(|p| p.parse())
+                .transpose()?
+                .unwrap_or_default(),
+        };
+
+        // e.g. TRAKT_STAGING_URL, falling back to TRAKT_URL
+        let setting = |key: &str, shared: &str| {
+            var(&format!("{}_{}", profile.env_prefix(), key))
+                .or_else(|| var(shared))
+                .filter(|v| !v.is_empty())
+        };
+        let required = |key: &str| {
+            // the local mock server doesn't check credentials
+            setting(key, key)
+                .or_else(|| (profile == Profile::Local).then(|| "local".to_string()))
+                .ok_or_else(|| {
+                    eyre::eyre!(
+                        "{} (or {}_{}) must be set for the {} profile",
+                        key,
+                        profile.env_prefix(),
+                        key,
+                        profile
+                    )
+                })
+        };
+
+        let timeout = match setting("TIMEOUT_SECS", "TRAKT_TIMEOUT_SECS") {
+            Some(secs) => Duration::from_secs(secs.parse().map_err(|e| {
+                eyre::eyre!("TRAKT_TIMEOUT_SECS must be a number of seconds: {}", e)
+            })?),
+            None => Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+        };
+
+        let cassette_mode = setting("CASSETTE_MODE", "TRAKT_CASSETTE_MODE")
+            .map(|m| m.parse())
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(TraktConfig {
+            profile,
+            base_url: setting("URL", "TRAKT_URL")
+                .unwrap_or_else(|| profile.base_url().to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            client_id: required("CLIENT_ID")?,
+            client_secret: required("CLIENT_SECRET")?,
+            timeout,
+            user_agent: setting("USER_AGENT", "TRAKT_USER_AGENT")
+                .unwrap_or_else(|| DEFAULT_USER_AGENT.to_string()),
+            cassette: setting("CASSETTE", "TRAKT_CASSETTE").map(PathBuf::from),
+            cassette_mode,
+        })
+    }
+
+    /// Config for a local (mock or replayed) trakt, for tests.
+    #[cfg(test)]
+    pub fn local(base_url: &str) -> TraktConfig {
+        TraktConfig {
+            profile: Profile::Local,
+            base_url: base_url.to_string(),
+            client_id: "local".to_string(),
+            client_secret: "local".to_string(),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            cassette: None,
+            cassette_mode: CassetteMode::Replay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(profile: Option<Profile>, vars: &[(&str, &str)]) -> eyre::Result<TraktConfig> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        TraktConfig::from_vars(profile, |key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn defaults_to_production() {
+        let config = load(None, &[("CLIENT_ID", "id"), ("CLIENT_SECRET", "secret")]).unwrap();
+        assert_eq!(config.profile, Profile::Production);
+        assert_eq!(config.base_url, "https://api.trakt.tv");
+        assert_eq!(config.timeout, Duration::from_secs(DEFAULT_TIMEOUT_SECS));
+        assert_eq!(config.user_agent, DEFAULT_USER_AGENT);
+    }
+
+    #[test]
+    fn profile_settings_win() {
+        let vars = [
+            ("TRAKT_PROFILE", "staging"),
+            ("CLIENT_ID", "id"),
+            ("TRAKT_STAGING_CLIENT_ID", "staging-id"),
+            ("CLIENT_SECRET", "secret"),
+            ("TRAKT_TIMEOUT_SECS", "30"),
+            ("TRAKT_PRODUCTION_URL", "https://example.com"),
+        ];
+        let config = load(None, &vars).unwrap();
+        assert_eq!(config.profile, Profile::Staging);
+        assert_eq!(config.base_url, "https://api-staging.trakt.tv");
+        assert_eq!(config.client_id, "staging-id");
+        assert_eq!(config.client_secret, "secret");
+        assert_eq!(config.timeout, Duration::from_secs(30));
+
+        // an explicit profile (from the command line) overrides TRAKT_PROFILE
+        let config = load(Some(Profile::Production), &vars).unwrap();
+        assert_eq!(config.client_id, "id");
+        assert_eq!(config.base_url, "https://example.com");
+    }
+
+    #[test]
+    fn local_needs_no_credentials() {
+        let config = load(
+            Some(Profile::Local),
+            &[("TRAKT_URL", "http://127.0.0.1:9000/")],
+        )
+        .unwrap();
+        assert_eq!(config.base_url, "http://127.0.0.1:9000");
+        assert_eq!(config.client_id, "local");
+
+        let err = load(Some(Profile::Production), &[]).unwrap_err();
+        assert!(err.to_string().contains("CLIENT_ID"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_unknown_profile() {
+        assert!(load(None, &[("TRAKT_PROFILE", "prdouction")]).is_err());
+        assert_eq!("Mock".parse::<Profile>().unwrap(), Profile::Local);
+    }
+}
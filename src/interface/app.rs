@@ -1,13 +1,35 @@
-use crate::models::{TraktSeason, TraktShow, UserStatusSeason, UserStatusShow};
+use crate::config::TraktConfig;
+use crate::interface::date_picker::DatePicker;
+use crate::models::{
+    RecordedBatch, TraktEpisode, TraktSeason, TraktShow, UserStatusEpisode, UserStatusSeason,
+    UserStatusShow,
+};
+use crate::sources::imdb_reader::{ImportProgress, ImportState};
 use crate::sources::DataManager;
-use crate::trakt::t_api;
+use crate::trakt::t_api::{self, ApiMatch, Pagination, TraktApiError};
+use crate::trakt::t_auth::AuthStatus;
 use crate::trakt::t_db::{self, Database};
+use crate::trakt::t_enrich::{self, EnrichProgress, Enricher};
+use crate::trakt::t_import::{self, PullSummary};
+use crate::trakt::t_outbox::Outbox;
+use crate::trakt::t_reconcile::{self, Classification, LocalState, ReconcileItem, Winner};
+use crate::trakt::t_sync::{self, SyncPreview};
 
+use chrono::{NaiveDateTime, Utc};
 use log::*;
 use ratatui::widgets::{ScrollbarState, TableState};
-use reqwest::Client;
+use tokio::sync::watch;
 use tui_input::Input;
 
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// how often to look for newly aired episodes of ON_RELEASE seasons
+const RELEASE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// how often the show list picks up shows an IMDB import has written since
+const IMPORT_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Different modes for the app.
 #[derive(PartialEq, Eq, Debug, Default)]
 pub enum AppMode {
@@ -16,16 +38,25 @@ pub enum AppMode {
     Initializing,
     /// List of all the shows we find (from IMDB dataset / loaded from DB)
     MainView,
-    /// somewhat of a todo state, i haven't impl'd searching yet
+    /// Typing a title to search trakt for
     Querying,
     /// Show keybindings
     #[allow(dead_code)]
     HelpWindow,
     /// Detailed view of specific season
     SeasonView,
-    // Detailed view of a specific episode
-    // not sure about this one yet
-    // EpisodeView,
+    /// Shows found by searching trakt (for adding ones the IMDB dump doesn't have)
+    SearchResults,
+    /// Episodes of the season selected in season view
+    EpisodeView,
+    /// Picking when a season was watched (popup over season view)
+    DatePicker,
+    /// Settling differences between the local db and trakt
+    Reconcile,
+    /// What a sync would send to trakt, before sending it
+    SyncPreview,
+    /// Past pushes to trakt, for undoing one
+    SyncBatches,
 }
 
 /// inner struct for detailed show views.
@@ -34,9 +65,70 @@ pub struct AppShowView {
     pub seasons: Vec<TraktSeason>,
 
     pub season_table_state: TableState,
-    // unimpl'd yet...
-    // pub episodes: Vec<>,
-    // pub episode_table_state: TableState,
+
+    pub episodes: Vec<TraktEpisode>,
+    pub episode_table_state: TableState,
+}
+
+/// inner struct for the watch date popup.
+#[derive(Debug)]
+pub struct AppDatePicker {
+    pub picker: DatePicker,
+    /// index into `show_view.seasons`
+    pub season: usize,
+    /// what the season's status becomes if the user backs out
+    pub cancel_status: UserStatusSeason,
+}
+
+/// inner struct for the trakt search results view.
+#[derive(Debug, Default)]
+pub struct AppSearch {
+    pub query: String,
+    pub results: Vec<ApiMatch>,
+    pub pagination: Pagination,
+
+    pub table_state: TableState,
+}
+
+/// inner struct for the conflict review view.
+#[derive(Debug, Default)]
+pub struct AppReconcile {
+    /// only the conflicting items
+    pub conflicts: Vec<ReconcileItem>,
+    /// which side to keep, per conflict
+    pub choices: Vec<Option<Winner>>,
+    /// how many items fell into each class (conflicts included)
+    pub counts: HashMap<Classification, usize>,
+    /// the rows the items were built from
+    pub local: LocalState,
+    /// waiting for a `y` before sending our side of several conflicts to trakt
+    pub confirming: bool,
+
+    pub table_state: TableState,
+}
+
+/// inner struct for the sync preview.
+#[derive(Debug, Default)]
+pub struct AppSyncPreview {
+    pub preview: SyncPreview,
+
+    pub table_state: TableState,
+}
+
+impl AppSyncPreview {
+    /// One row per show, plus one per season.
+    pub fn rows(&self) -> usize {
+        self.preview.shows.iter().map(|s| 1 + s.seasons.len()).sum()
+    }
+}
+
+/// inner struct for the list of past pushes.
+#[derive(Debug, Default)]
+pub struct AppSyncBatches {
+    /// newest first
+    pub batches: Vec<RecordedBatch>,
+
+    pub table_state: TableState,
 }
 
 /// Application.
@@ -46,16 +138,37 @@ pub struct App {
     pub running: bool,
 
     pub data_manager: DataManager,
+    pub import_progress: watch::Receiver<ImportProgress>,
+    /// when the show list last picked up imported shows
+    import_reloaded_at: Option<Instant>,
 
     /// for querying trakt
-    pub client: Client,
+    pub client: t_api::TraktClient,
+
+    /// progress of logging in to trakt (shown as a popup while we wait on the user)
+    pub auth_status: watch::Receiver<AuthStatus>,
 
     /// local cache of imdb + trakt data
     pub cache: t_db::PersistentDb,
 
+    /// changes waiting to be sent to trakt
+    pub outbox: Outbox,
+    pub pending_changes: usize,
+
+    /// background lookup of trakt details for the whole catalogue
+    pub enricher: Enricher,
+    pub enrich_progress: watch::Receiver<EnrichProgress>,
+
+    /// result of the last watch history import from trakt
+    pub import_done: Arc<watch::Sender<Option<PullSummary>>>,
+    pub imported: watch::Receiver<Option<PullSummary>>,
+
     /// ui+handling changes based on the app's current view
     pub mode: AppMode,
 
+    /// one-line message for the user (e.g. why a request failed), cleared on the next key press
+    pub message: Option<String>,
+
     /// used in main view
     pub input: Input,
     pub table_state: TableState,
@@ -64,22 +177,70 @@ pub struct App {
 
     // used in season view
     pub show_view: AppShowView,
+
+    // used in search results view
+    pub search: AppSearch,
+
+    // used while picking a season's watch date
+    pub date_picker: Option<AppDatePicker>,
+
+    // used in conflict review view
+    pub reconcile: AppReconcile,
+    // used in sync preview
+    pub sync_preview: AppSyncPreview,
+    // used in the list of past pushes
+    pub sync_batches: AppSyncBatches,
 }
 
 impl App {
     /// Constructs a new instance of [`App`].
-    pub async fn new() -> eyre::Result<Self> {
-        // when a new app is created, begin a bg data manager task
-        // this task will receive a string query, and send back a TraktShow vec
-        let data_manager = DataManager::init().await?;
+    pub async fn new(config: &TraktConfig) -> eyre::Result<Self> {
+        let cache = t_db::PersistentDb::connect().await?;
+
+        // when a new app is created, begin filling the db from the IMDB dump in the background
+        let data_manager = DataManager::init(cache.clone()).await;
+        let import_progress = data_manager.progress();
+
+        let client = t_api::TraktClient::new(cache.clone(), config).await?;
+        let auth_status = client.auth.status();
+
+        let outbox = Outbox::new(cache.clone());
+        outbox.spawn_worker(client.clone());
+
+        let enricher = Enricher::new(cache.clone());
+        let enrich_progress = enricher.progress();
+        enricher.spawn(client.clone());
+
+        let released = cache.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RELEASE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = released.fill_released_episodes().await {
+                    error!("could not mark released episodes: {}", e);
+                }
+            }
+        });
+
+        let (import_done, imported) = watch::channel(None);
 
-        Ok(App {
+        let app = App {
             running: true,
             data_manager,
-
-            client: t_api::establish_http_client(),
-            cache: t_db::PersistentDb::connect().await?,
-
+            import_progress,
+            import_reloaded_at: None,
+
+            client,
+            auth_status,
+            cache,
+            outbox,
+            pending_changes: 0,
+            enricher,
+            enrich_progress,
+            import_done: Arc::new(import_done),
+            imported,
+
+            message: None,
             input: Input::default(),
             mode: AppMode::default(),
             table_state: TableState::default(),
@@ -87,31 +248,117 @@ impl App {
             shows: Vec::new(),
 
             show_view: AppShowView::default(),
-        })
+            search: AppSearch::default(),
+            date_picker: None,
+            reconcile: AppReconcile::default(),
+            sync_preview: AppSyncPreview::default(),
+            sync_batches: AppSyncBatches::default(),
+        };
+
+        if !app.client.auth.is_logged_in().await {
+            app.login();
+        }
+        app.import_history(false);
+
+        Ok(app)
+    }
+
+    /// Start the trakt device-code login in the background.
+    /// The user code shows up in `auth_status` once trakt hands us one.
+    pub fn login(&self) {
+        if matches!(*self.auth_status.borrow(), AuthStatus::AwaitingUser { .. }) {
+            return;
+        }
+
+        let client = self.client.clone();
+        tokio::spawn(async move { client.login().await });
+    }
+
+    /// Pull whatever changed on trakt since the last pull (or everything, if `force` is set)
+    /// in the background, once we're logged in. The show list is reloaded when it's done.
+    pub fn import_history(&self, force: bool) {
+        let client = self.client.clone();
+        let cache = self.cache.clone();
+        let outbox = self.outbox.clone();
+        let done = self.import_done.clone();
+        tokio::spawn(async move {
+            let _ = client
+                .auth
+                .status()
+                .wait_for(|s| *s == AuthStatus::LoggedIn)
+                .await;
+            match t_import::pull_changes(&client, &cache, &outbox, force).await {
+                Ok(summary) => {
+                    done.send_replace(Some(summary));
+                }
+                Err(e) => error!("could not pull from trakt: {}", e),
+            }
+        });
+    }
+
+    /// Reload the show list from the db, keeping the same show selected.
+    async fn reload_shows(&mut self) {
+        let selected = self
+            .table_state
+            .selected()
+            .and_then(|i| self.shows.get(i))
+            .map(|s| s.imdb_id.clone());
+
+        self.shows = self.cache.filtered_shows().await;
+        self.scroll_state = self.scroll_state.content_length(self.shows.len() as u16);
+
+        let i = selected.and_then(|id| self.shows.iter().position(|s| s.imdb_id == id));
+        self.table_state.select(i);
+        self.scroll_state = self.scroll_state.position(i.unwrap_or_default() as u16);
     }
 
     /// Handles the tick event of the terminal.
     pub async fn tick(&mut self) -> eyre::Result<()> {
-        // WIP implementation of query from our data rows
-        // (right now, just pull everything on boot)
-        if self.shows.is_empty() {
-            let items = self
-                .data_manager
-                .query(String::from("spurious"))
-                .await
-                .ok_or_else(|| {
-                    error!("data manager thread panicked!");
-                    eyre::eyre!("data manager thread panicked!")
-                })?;
-
-            self.scroll_state = self.scroll_state.content_length(items.len() as u16);
-            self.shows = items;
-
-            if self.mode == AppMode::Initializing {
+        // the show list is usable once the db has shows (the first IMDB batch, on a fresh
+        // db); it picks up the rest of an import every few seconds, and when it's done
+        let import_changed = self.import_progress.has_changed().unwrap_or(false);
+        let import = self.import_progress.borrow_and_update().clone();
+        let reload_due = self
+            .import_reloaded_at
+            .is_none_or(|at| at.elapsed() >= IMPORT_RELOAD_INTERVAL);
+        if self.mode == AppMode::Initializing {
+            if self.data_manager.has_cached_shows() || import.rows_written > 0 || import.is_done() {
+                self.reload_shows().await;
+                self.import_reloaded_at = Some(Instant::now());
                 self.mode = AppMode::MainView;
             }
+        } else if import_changed && import.state == ImportState::Finished {
+            self.reload_shows().await;
+        } else if self.mode == AppMode::MainView
+            && import.state == ImportState::Running
+            && import.rows_written > self.shows.len()
+            && reload_due
+        {
+            self.reload_shows().await;
+            self.import_reloaded_at = Some(Instant::now());
+        }
+        if import_changed && let ImportState::Failed(e) = import.state {
+            self.message = Some(format!("IMDB import failed: {}", e));
+        }
+
+        if self.imported.has_changed().unwrap_or(false) {
+            let summary = self.imported.borrow_and_update().clone();
+            if let Some(summary) = summary
+                && !summary.pulled.is_empty()
+            {
+                self.reload_shows().await;
+                self.message = Some(match summary.watched {
+                    Some(watched) => format!(
+                        "Imported {} shows ({} episodes) from trakt",
+                        watched.shows, watched.episodes
+                    ),
+                    None => format!("Pulled {:?} from trakt", summary.pulled),
+                });
+            }
         }
 
+        self.pending_changes = self.outbox.pending().await?;
+
         Ok(())
     }
 
@@ -121,6 +368,10 @@ impl App {
     }
 
     pub fn next(&mut self, step: usize) {
+        // e.g. the IMDB import failed on a fresh db
+        if self.shows.is_empty() {
+            return;
+        }
         let i = match self.table_state.selected() {
             Some(i) => std::cmp::min(i + step, self.shows.len() - 1),
             None => 0,
@@ -130,6 +381,9 @@ impl App {
     }
 
     pub fn prev(&mut self, step: usize) {
+        if self.shows.is_empty() {
+            return;
+        }
         let i = match self.table_state.selected() {
             Some(i) => std::cmp::max(i as i32 - step as i32, 0) as usize,
             None => self.shows.len() - 1,
@@ -155,20 +409,484 @@ impl App {
         self.show_view.season_table_state.select(Some(i));
     }
 
+    pub fn episode_next(&mut self, step: usize) {
+        let max = self.show_view.episodes.len().saturating_sub(1);
+        let i = match self.show_view.episode_table_state.selected() {
+            Some(i) => std::cmp::min(i + step, max),
+            None => 0,
+        };
+        self.show_view.episode_table_state.select(Some(i));
+    }
+
+    pub fn episode_prev(&mut self, step: usize) {
+        let i = match self.show_view.episode_table_state.selected() {
+            Some(i) => i.saturating_sub(step),
+            None => 0,
+        };
+        self.show_view.episode_table_state.select(Some(i));
+    }
+
+    pub fn search_next(&mut self, step: usize) {
+        let max = self.search.results.len().saturating_sub(1);
+        let i = match self.search.table_state.selected() {
+            Some(i) => std::cmp::min(i + step, max),
+            None => 0,
+        };
+        self.search.table_state.select(Some(i));
+    }
+
+    pub fn search_prev(&mut self, step: usize) {
+        let i = match self.search.table_state.selected() {
+            Some(i) => i.saturating_sub(step),
+            None => 0,
+        };
+        self.search.table_state.select(Some(i));
+    }
+
+    pub fn reconcile_next(&mut self, step: usize) {
+        let max = self.reconcile.conflicts.len().saturating_sub(1);
+        let i = match self.reconcile.table_state.selected() {
+            Some(i) => std::cmp::min(i + step, max),
+            None => 0,
+        };
+        self.reconcile.table_state.select(Some(i));
+    }
+
+    pub fn reconcile_prev(&mut self, step: usize) {
+        let i = match self.reconcile.table_state.selected() {
+            Some(i) => i.saturating_sub(step),
+            None => 0,
+        };
+        self.reconcile.table_state.select(Some(i));
+    }
+
+    pub fn sync_preview_next(&mut self, step: usize) {
+        let max = self.sync_preview.rows().saturating_sub(1);
+        let i = match self.sync_preview.table_state.selected() {
+            Some(i) => std::cmp::min(i + step, max),
+            None => 0,
+        };
+        self.sync_preview.table_state.select(Some(i));
+    }
+
+    pub fn sync_preview_prev(&mut self, step: usize) {
+        let i = match self.sync_preview.table_state.selected() {
+            Some(i) => i.saturating_sub(step),
+            None => 0,
+        };
+        self.sync_preview.table_state.select(Some(i));
+    }
+
+    /// Work out what a sync would send (without sending anything) and show it.
+    pub async fn preview_sync(&mut self) -> eyre::Result<()> {
+        let preview = t_sync::preview_sync(&self.cache).await?;
+        if preview.plan.is_empty() {
+            self.message = Some("Nothing to sync, trakt is up to date".to_string());
+            return Ok(());
+        }
+
+        self.sync_preview = AppSyncPreview {
+            preview,
+            table_state: TableState::default(),
+        };
+        self.sync_preview.table_state.select(Some(0));
+        self.mode = AppMode::SyncPreview;
+        Ok(())
+    }
+
+    pub fn sync_batches_next(&mut self, step: usize) {
+        let max = self.sync_batches.batches.len().saturating_sub(1);
+        let i = match self.sync_batches.table_state.selected() {
+            Some(i) => std::cmp::min(i + step, max),
+            None => 0,
+        };
+        self.sync_batches.table_state.select(Some(i));
+    }
+
+    pub fn sync_batches_prev(&mut self, step: usize) {
+        let i = match self.sync_batches.table_state.selected() {
+            Some(i) => i.saturating_sub(step),
+            None => 0,
+        };
+        self.sync_batches.table_state.select(Some(i));
+    }
+
+    /// List the past pushes to trakt.
+    pub async fn open_sync_batches(&mut self) -> eyre::Result<()> {
+        let batches = self.cache.sync_batches().await?;
+        if batches.is_empty() {
+            self.message = Some("Nothing has been synced yet".to_string());
+            return Ok(());
+        }
+
+        let selected = self.sync_batches.table_state.selected().unwrap_or(0);
+        self.sync_batches = AppSyncBatches {
+            batches,
+            table_state: TableState::default(),
+        };
+        self.sync_batches.table_state.select(Some(0));
+        self.sync_batches_next(selected);
+        self.mode = AppMode::SyncBatches;
+        Ok(())
+    }
+
+    /// Undo the selected push, on trakt and locally.
+    pub async fn revert_sync_batch(&mut self) -> eyre::Result<()> {
+        let Some(i) = self.sync_batches.table_state.selected() else {
+            return Ok(());
+        };
+        let Some((batch, items)) = self.sync_batches.batches.get(i) else {
+            return Ok(());
+        };
+        if batch.reverted_at.is_some() {
+            self.message = Some("Already reverted".to_string());
+            return Ok(());
+        }
+
+        match t_sync::revert_batch(&self.client, &self.cache, batch, items).await {
+            Ok(summary) => {
+                self.message = Some(format!(
+                    "Reverted: {} episodes removed, {} added back",
+                    summary.episodes_removed, summary.episodes_added
+                ));
+            }
+            Err(e) => {
+                error!("could not revert sync batch {}: {}", batch.id, e);
+                self.message = Some(format!("Could not revert: {}", e));
+            }
+        }
+
+        self.reload_shows().await;
+        self.open_sync_batches().await
+    }
+
+    /// Compare the local db with trakt, and open the conflicts for review.
+    pub async fn start_reconcile(&mut self) -> eyre::Result<()> {
+        let remote = match t_reconcile::fetch_remote(&self.client).await {
+            Ok(remote) => remote,
+            Err(TraktApiError::Unauthorized) => {
+                self.message = Some("Not logged in to trakt".to_string());
+                self.login();
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("could not fetch statuses from trakt: {}", e);
+                self.message = Some(format!("Could not compare with trakt: {}", e));
+                return Ok(());
+            }
+        };
+        let local = self.cache.local_state(remote.imdb_ids()).await?;
+        let items = t_reconcile::reconcile(&local, &remote);
+
+        let mut counts = HashMap::new();
+        for item in items.iter() {
+            *counts.entry(item.class).or_default() += 1;
+        }
+        let conflicts: Vec<ReconcileItem> = items
+            .into_iter()
+            .filter(|i| i.class == Classification::Conflict)
+            .collect();
+        if conflicts.is_empty() {
+            self.message = Some("No conflicts with trakt".to_string());
+            return Ok(());
+        }
+
+        self.reconcile = AppReconcile {
+            choices: vec![None; conflicts.len()],
+            conflicts,
+            counts,
+            local,
+            confirming: false,
+            table_state: TableState::default(),
+        };
+        self.reconcile.table_state.select(Some(0));
+        self.mode = AppMode::Reconcile;
+        Ok(())
+    }
+
+    /// Pick a side for the selected conflict, or for all of them.
+    pub fn choose_winner(&mut self, winner: Option<Winner>, all: bool) {
+        let choices = &mut self.reconcile.choices;
+        if all {
+            choices.iter_mut().for_each(|c| *c = winner);
+        } else if let Some(i) = self.reconcile.table_state.selected()
+            && let Some(choice) = choices.get_mut(i)
+        {
+            *choice = winner;
+            self.reconcile_next(1);
+        }
+    }
+
+    /// Apply the chosen sides. Conflicts without a choice are left as they are.
+    /// Keeping our side of more than one conflict asks for a confirmation first,
+    /// since each of them is sent to trakt.
+    pub async fn apply_reconcile(&mut self) -> eyre::Result<()> {
+        let keeping_local = self
+            .reconcile
+            .choices
+            .iter()
+            .filter(|c| **c == Some(Winner::Local))
+            .count();
+        if keeping_local > 1 && !self.reconcile.confirming {
+            self.reconcile.confirming = true;
+            self.message = Some(format!(
+                "Send our side of {} conflicts to trakt? y: apply  any other key: back",
+                keeping_local
+            ));
+            return Ok(());
+        }
+
+        let review = std::mem::take(&mut self.reconcile);
+        let mut resolved = 0;
+        for (item, choice) in review.conflicts.iter().zip(review.choices) {
+            let Some(winner) = choice else {
+                continue;
+            };
+            t_reconcile::resolve(&self.cache, &self.outbox, &review.local, item, winner).await?;
+            resolved += 1;
+        }
+
+        self.reload_shows().await;
+        self.message = Some(format!(
+            "Resolved {} of {} conflicts",
+            resolved,
+            review.conflicts.len()
+        ));
+        self.mode = AppMode::MainView;
+        Ok(())
+    }
+
+    /// Search trakt for whatever is in the input bar, showing the first page of results.
+    pub async fn search_trakt(&mut self) {
+        let query = self.input.value().trim().to_string();
+        if query.is_empty() {
+            self.mode = AppMode::MainView;
+            return;
+        }
+
+        self.search.query = query;
+        self.search_page(1).await;
+    }
+
+    /// Load another page of results for the current search.
+    pub async fn search_page(&mut self, page: u32) {
+        let last_page = self.search.pagination.page_count.max(1);
+        if page == 0 || (self.mode == AppMode::SearchResults && page > last_page) {
+            return;
+        }
+
+        match t_api::search_shows(&self.client, &self.search.query, page).await {
+            Ok(response) => {
+                self.search.results = response.results;
+                self.search.pagination = response.pagination;
+                self.search
+                    .table_state
+                    .select((!self.search.results.is_empty()).then_some(0));
+                self.mode = AppMode::SearchResults;
+            }
+            Err(TraktApiError::Unauthorized) => {
+                self.message = Some("Not logged in to trakt".to_string());
+                self.login();
+            }
+            Err(e) => {
+                warn!("error searching trakt: {}", e);
+                self.message = Some(format!("Search failed: {}", e));
+            }
+        }
+    }
+
+    /// Add the selected search result to our shows, and jump to it.
+    pub async fn add_search_result(&mut self) -> eyre::Result<()> {
+        let Some(found) = self
+            .search
+            .table_state
+            .selected()
+            .and_then(|i| self.search.results.get(i))
+        else {
+            return Ok(());
+        };
+
+        // shows are keyed by their imdb id, so we can't store ones without one
+        let Some(imdb_id) = found.show.ids.imdb.clone() else {
+            self.message = Some(format!("{} has no IMDB id", found.show.title));
+            return Ok(());
+        };
+
+        let show = TraktShow {
+            imdb_id,
+            trakt_id: Some(found.show.ids.trakt as i32),
+            primary_title: found.show.title.clone(),
+            original_title: found.show.title.clone(),
+            country: None,
+            release_year: found.show.year.map(|y| y as i32),
+            network: None,
+            no_seasons: None,
+            no_episodes: None,
+            overview: None,
+            user_status: UserStatusShow::Todo,
+            status_changed_at: None,
+        };
+        let show = self.cache.add_show(show).await?;
+
+        let i = match self.shows.iter().position(|s| s.imdb_id == show.imdb_id) {
+            Some(i) => {
+                self.shows[i] = show;
+                i
+            }
+            None => {
+                self.shows.push(show);
+                self.scroll_state = self.scroll_state.content_length(self.shows.len() as u16);
+                self.shows.len() - 1
+            }
+        };
+        self.table_state.select(Some(i));
+        self.scroll_state = self.scroll_state.position(i as u16);
+
+        self.mode = AppMode::MainView;
+        Ok(())
+    }
+
     /// Cycle the watch status of a currently-selected season (similar to toggle_watch_status)
+    /// OTHER_DATE asks for the date first.
     pub async fn toggle_season_watch_status(&mut self) -> eyre::Result<()> {
         if let Some(i) = self.show_view.season_table_state.selected() {
-            let season = &mut self.show_view.seasons[i];
+            let season = &self.show_view.seasons[i];
             info!("Currently selected season: {:?}", season);
 
-            season.user_status = match season.user_status {
-                UserStatusSeason::Unfilled => UserStatusSeason::OnRelease,
-                UserStatusSeason::OnRelease => UserStatusSeason::OtherDate,
-                UserStatusSeason::OtherDate => UserStatusSeason::Unfilled,
+            match season.user_status {
+                UserStatusSeason::Unfilled => {
+                    self.set_season_status(i, UserStatusSeason::OnRelease, None)
+                        .await?
+                }
+                // backing out of the picker skips on to UNFILLED
+                UserStatusSeason::OnRelease => self.open_date_picker(i, UserStatusSeason::Unfilled),
+                UserStatusSeason::OtherDate => {
+                    self.set_season_status(i, UserStatusSeason::Unfilled, None)
+                        .await?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pick (or change) the date the selected season was watched.
+    pub fn pick_season_date(&mut self) {
+        if let Some(i) = self.show_view.season_table_state.selected() {
+            let status = self.show_view.seasons[i].user_status.clone();
+            self.open_date_picker(i, status);
+        }
+    }
+
+    fn open_date_picker(&mut self, season: usize, cancel_status: UserStatusSeason) {
+        let start = self.show_view.seasons[season]
+            .watched_at
+            .unwrap_or_else(|| Utc::now().naive_utc());
+
+        self.date_picker = Some(AppDatePicker {
+            picker: DatePicker::new(start),
+            season,
+            cancel_status,
+        });
+        self.mode = AppMode::DatePicker;
+    }
+
+    /// Mark the season as watched at the picked date.
+    pub async fn confirm_date_picker(&mut self) -> eyre::Result<()> {
+        let Some(picked) = &self.date_picker else {
+            return Ok(());
+        };
+        let at = picked.picker.selected;
+        if at > Utc::now().naive_utc() {
+            self.message = Some("Can't have watched it in the future".to_string());
+            return Ok(());
+        }
+
+        let season = picked.season;
+        self.date_picker = None;
+        self.mode = AppMode::SeasonView;
+        self.set_season_status(season, UserStatusSeason::OtherDate, Some(at))
+            .await
+    }
+
+    pub async fn cancel_date_picker(&mut self) -> eyre::Result<()> {
+        self.mode = AppMode::SeasonView;
+        let Some(picked) = self.date_picker.take() else {
+            return Ok(());
+        };
+
+        let season = &self.show_view.seasons[picked.season];
+        if season.user_status != picked.cancel_status {
+            let watched_at = season.watched_at;
+            self.set_season_status(picked.season, picked.cancel_status, watched_at)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn set_season_status(
+        &mut self,
+        i: usize,
+        status: UserStatusSeason,
+        watched_at: Option<NaiveDateTime>,
+    ) -> eyre::Result<()> {
+        let season = &mut self.show_view.seasons[i];
+        let previous = season.clone();
+        season.user_status = status;
+        // only OTHER_DATE seasons have a date of their own
+        season.watched_at =
+            watched_at.filter(|_| season.user_status == UserStatusSeason::OtherDate);
+
+        // Update database
+        self.cache.update_season(season.clone()).await?;
+        self.outbox.season_changed(season, &previous).await
+    }
+
+    /// Mark the selected episode as watched (now) or unwatched
+    pub async fn toggle_episode_watch_status(&mut self) -> eyre::Result<()> {
+        if let Some(i) = self.show_view.episode_table_state.selected() {
+            let episode = &mut self.show_view.episodes[i];
+            let previous = episode.watched_at;
+
+            match episode.user_status {
+                UserStatusEpisode::Unwatched => {
+                    episode.user_status = UserStatusEpisode::Watched;
+                    episode.watched_at = Some(Utc::now().naive_utc());
+                }
+                UserStatusEpisode::Watched => {
+                    episode.user_status = UserStatusEpisode::Unwatched;
+                    episode.watched_at = None;
+                }
+            }
+
+            self.cache.update_episode(episode.clone()).await?;
+            self.outbox.episode_changed(episode, previous).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Switch when the selected (watched) episode was watched, between now and its air date
+    pub async fn toggle_episode_watched_at(&mut self) -> eyre::Result<()> {
+        if let Some(i) = self.show_view.episode_table_state.selected() {
+            let episode = &mut self.show_view.episodes[i];
+            if episode.user_status != UserStatusEpisode::Watched {
+                return Ok(());
+            }
+            let Some(aired) = episode.first_aired else {
+                self.message = Some(format!("{} hasn't aired", episode.title));
+                return Ok(());
+            };
+
+            let previous = episode.watched_at;
+            episode.watched_at = if previous == Some(aired) {
+                Some(Utc::now().naive_utc())
+            } else {
+                Some(aired)
             };
 
-            // Update database
-            self.cache.update_season(season.clone()).await?;
+            self.cache.update_episode(episode.clone()).await?;
+            self.outbox.episode_changed(episode, previous).await?;
         }
 
         Ok(())
@@ -180,22 +898,78 @@ impl App {
             let show = &mut self.shows[i];
             info!("Currently selected show: {:?}", show);
 
+            let previous = show.clone();
             show.user_status = match show.user_status {
                 UserStatusShow::Todo => UserStatusShow::Watched,
                 UserStatusShow::Watched => UserStatusShow::Unwatched,
                 UserStatusShow::Unwatched => UserStatusShow::Todo,
             };
+            show.status_changed_at = Some(Utc::now().naive_utc());
 
-            // update db
-            t_db::PersistentDb::connect()
-                .await?
-                .update_show(show.clone())
-                .await?;
+            self.cache.update_show(show.clone()).await?;
+            self.outbox.show_changed(show, &previous).await?;
         }
 
         Ok(())
     }
 
+    /// Push everything marked as watched (that trakt doesn't know about yet) to trakt's history
+    pub async fn sync_history(&mut self) {
+        match t_sync::push_history(&self.client, &self.cache).await {
+            Ok(summary) => {
+                info!("Synced history: {:?}", summary);
+                self.message = Some(format!(
+                    "Synced: {} episodes added, {} removed",
+                    summary.episodes_added, summary.episodes_removed
+                ));
+            }
+            Err(e) => {
+                error!("could not sync history: {}", e);
+                self.message = Some(format!("Could not sync: {}", e));
+            }
+        }
+    }
+
+    /// Open the episode list of the selected season, fetching it from trakt the first time.
+    pub async fn enter_season_details(&mut self) -> eyre::Result<()> {
+        let Some(i) = self.show_view.season_table_state.selected() else {
+            return Ok(());
+        };
+        let season = self.show_view.seasons[i].clone();
+
+        let mut episodes = self.cache.season_episodes(season.clone()).await?;
+        if episodes.len() < season.episode_count as usize {
+            match t_api::query_episodes(
+                &self.client,
+                &season.show_id.to_string(),
+                season.season_number,
+            )
+            .await
+            {
+                Ok(api_episodes) => {
+                    episodes = self.cache.store_episodes(season, &api_episodes).await?;
+                }
+                Err(TraktApiError::Unauthorized) => {
+                    self.message = Some("Not logged in to trakt".to_string());
+                    self.login();
+                    return Ok(());
+                }
+                // still show whatever we have stored
+                Err(e) => {
+                    warn!("error querying episodes: {}", e);
+                    self.message = Some(format!("Could not fetch episodes: {}", e));
+                }
+            }
+        }
+
+        self.show_view
+            .episode_table_state
+            .select((!episodes.is_empty()).then_some(0));
+        self.show_view.episodes = episodes;
+        self.mode = AppMode::EpisodeView;
+        Ok(())
+    }
+
     pub async fn enter_show_details(&mut self) -> eyre::Result<()> {
         // when a user attempts to view details for a show, we query its details and season info
         // and write back to local
@@ -203,21 +977,12 @@ impl App {
             let show = &mut self.shows[i];
             match t_api::query_detailed(&self.client, &show.imdb_id).await {
                 Ok((show_details, api_seasons)) => {
-                    // update a show's overview
-                    show.overview = Some(show_details.overview.clone());
-                    show.network = Some(show_details.network.clone());
-                    show.no_episodes = Some(show_details.aired_episodes as i32);
-
-                    // update a show's trakt_id in the db if show.trakt_id is currently None
-                    if show.trakt_id == None {
-                        show.trakt_id = Some(show_details.ids.trakt as i32);
-                        // let _ = t_db::update_show(show);
-                    }
-
-                    let _ = self.cache.update_show(show.clone()).await?;
-
-                    // insert the seasons of a show
-                    self.show_view.seasons = self.cache.update_show_with_seasons(show, &api_seasons).await?;
+                    // update a show's trakt_id, overview, etc and insert its seasons
+                    t_enrich::apply_details(show, &show_details, &api_seasons);
+                    self.show_view.seasons = self
+                        .cache
+                        .store_show_details(show.clone(), &api_seasons)
+                        .await?;
 
                     if !api_seasons.is_empty() {
                         self.show_view.season_table_state.select(Some(0));
@@ -225,10 +990,28 @@ impl App {
 
                     self.mode = AppMode::SeasonView;
                 }
-                Err(other) => {
-                    error!("error querying show details: {}", other);
-                    self.quit();
-                    eyre::bail!(other);
+                Err(TraktApiError::NotFound) => {
+                    self.message = Some(format!("{} isn't on trakt", show.primary_title));
+                }
+                Err(TraktApiError::Unauthorized) => {
+                    self.message = Some("Not logged in to trakt".to_string());
+                    self.login();
+                }
+                Err(e) if e.is_retryable() => {
+                    warn!("error querying show details: {}", e);
+                    self.message = Some(format!("{} (try again later)", e));
+
+                    // trakt is out of reach, show what we have of it
+                    let seasons = self.cache.show_seasons(show.clone()).await?;
+                    if !seasons.is_empty() {
+                        self.show_view.seasons = seasons;
+                        self.show_view.season_table_state.select(Some(0));
+                        self.mode = AppMode::SeasonView;
+                    }
+                }
+                Err(e) => {
+                    error!("error querying show details: {}", e);
+                    self.message = Some(e.to_string());
                 }
             }
         }
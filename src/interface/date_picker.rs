@@ -0,0 +1,112 @@
+use chrono::{Datelike, Duration, Months, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+
+/// A date + time being picked on a month calendar. Times are UTC, like everything we store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatePicker {
+    pub selected: NaiveDateTime,
+}
+
+impl DatePicker {
+    pub fn new(selected: NaiveDateTime) -> DatePicker {
+        // whole minutes are plenty
+        let selected = selected
+            .with_second(0)
+            .and_then(|d| d.with_nanosecond(0))
+            .unwrap_or(selected);
+        DatePicker { selected }
+    }
+
+    pub fn move_days(&mut self, days: i64) {
+        self.selected += Duration::days(days);
+    }
+
+    /// Same day in another month (or that month's last day, if it's shorter).
+    pub fn move_months(&mut self, months: i32) {
+        let shifted = if months >= 0 {
+            self.selected
+                .checked_add_months(Months::new(months.unsigned_abs()))
+        } else {
+            self.selected
+                .checked_sub_months(Months::new(months.unsigned_abs()))
+        };
+        if let Some(shifted) = shifted {
+            self.selected = shifted;
+        }
+    }
+
+    /// Change the time of day, staying on the same date.
+    pub fn move_minutes(&mut self, minutes: i64) {
+        let time = self.selected.time() + Duration::minutes(minutes);
+        self.selected = self.selected.date().and_time(time);
+    }
+
+    pub fn date(&self) -> NaiveDate {
+        self.selected.date()
+    }
+
+    pub fn time(&self) -> NaiveTime {
+        self.selected.time()
+    }
+
+    /// Days of the selected month, one row per week (Monday first).
+    /// Days outside the month are `None`.
+    pub fn weeks(&self) -> Vec<[Option<u32>; 7]> {
+        let first = self.date().with_day(1).unwrap();
+        let offset = first.weekday().num_days_from_monday() as usize;
+        let days = first
+            .checked_add_months(Months::new(1))
+            .map(|next| (next - first).num_days() as usize)
+            .unwrap_or(31);
+
+        let mut weeks = vec![];
+        let mut week = [None; 7];
+        for day in 0..days {
+            let slot = (offset + day) % 7;
+            week[slot] = Some(day as u32 + 1);
+            if slot == 6 {
+                weeks.push(std::mem::take(&mut week));
+            }
+        }
+        if week.iter().any(Option::is_some) {
+            weeks.push(week);
+        }
+        weeks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn moves_around() {
+        let mut picker = DatePicker::new(at(2024, 1, 31, 23, 30));
+        picker.move_months(1);
+        assert_eq!(picker.selected, at(2024, 2, 29, 23, 30));
+        picker.move_minutes(45);
+        // the time wraps around without changing the date
+        assert_eq!(picker.selected, at(2024, 2, 29, 0, 15));
+        picker.move_days(-7);
+        picker.move_months(-2);
+        assert_eq!(picker.selected, at(2023, 12, 22, 0, 15));
+    }
+
+    #[test]
+    fn lays_out_weeks() {
+        // august 2023 starts on a tuesday
+        let picker = DatePicker::new(at(2023, 8, 5, 12, 0));
+        let weeks = picker.weeks();
+        assert_eq!(weeks.len(), 5);
+        assert_eq!(weeks[0][0], None);
+        assert_eq!(weeks[0][1], Some(1));
+        assert_eq!(weeks[4][3], Some(31));
+        assert_eq!(weeks[4][4], None);
+    }
+}
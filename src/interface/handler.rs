@@ -1,11 +1,17 @@
 use crate::interface::app::{App, AppMode};
+use crate::interface::date_picker::DatePicker;
+use crate::trakt::t_auth::AuthStatus;
+use crate::trakt::t_reconcile::Winner;
 use crossterm::event::{Event as CrosstermEvent, KeyCode, KeyEvent, KeyModifiers};
 use crossterm::event::{MouseEvent, MouseEventKind};
 
+use chrono::Utc;
 use tui_input::backend::crossterm::EventHandler;
 
 /// Handles the key events and updates the state of [`App`].
 pub async fn handle_key_events(key_event: KeyEvent, app: &mut App) -> eyre::Result<()> {
+    app.message = None;
+
     // Exit application from any mode on `Ctrl-C`
     match key_event.code {
         KeyCode::Char('c') | KeyCode::Char('C') => {
@@ -44,10 +50,10 @@ pub async fn handle_key_events(key_event: KeyEvent, app: &mut App) -> eyre::Resu
                     app.next(20);
                 }
             }
-            KeyCode::Char('g') => {
+            KeyCode::Char('g') if !app.shows.is_empty() => {
                 app.table_state.select(Some(0));
             }
-            KeyCode::Char('G') => {
+            KeyCode::Char('G') if !app.shows.is_empty() => {
                 app.table_state.select(Some(app.shows.len() - 1));
             }
             // switch to query mode (search for shows in input bar)
@@ -57,6 +63,31 @@ pub async fn handle_key_events(key_event: KeyEvent, app: &mut App) -> eyre::Resu
             // cycle through watch status for a show
             KeyCode::Char(' ') => app.toggle_watch_status().await?,
 
+            // push watch statuses to trakt
+            KeyCode::Char('s') => app.sync_history().await,
+
+            // see what a sync would send, without sending it
+            KeyCode::Char('p') => app.preview_sync().await?,
+
+            // list past pushes, to undo one
+            KeyCode::Char('b') => app.open_sync_batches().await?,
+
+            // pull everything from trakt again
+            KeyCode::Char('i') => app.import_history(true),
+
+            // compare with trakt and review the conflicts
+            KeyCode::Char('c') => app.start_reconcile().await?,
+
+            // pause/resume filling in show details from trakt
+            KeyCode::Char('e') => app.enricher.toggle(),
+
+            // retry logging in to trakt (if the previous attempt failed)
+            KeyCode::Char('L') => {
+                if matches!(*app.auth_status.borrow(), AuthStatus::Failed(_)) {
+                    app.login();
+                }
+            }
+
             // open up tv show details view
             KeyCode::Char('l') | KeyCode::Right => {
                 // app will only change its UI if a show is selected.
@@ -66,10 +97,8 @@ pub async fn handle_key_events(key_event: KeyEvent, app: &mut App) -> eyre::Resu
             _ => {}
         },
         AppMode::Querying => match key_event.code {
-            KeyCode::Enter => {
-                // TODO: do something else here? query rows for entered text?
-                app.mode = AppMode::MainView;
-            }
+            // search trakt for the entered title
+            KeyCode::Enter => app.search_trakt().await,
             KeyCode::Tab | KeyCode::Esc => {
                 app.mode = AppMode::MainView;
             }
@@ -82,6 +111,104 @@ pub async fn handle_key_events(key_event: KeyEvent, app: &mut App) -> eyre::Resu
             KeyCode::Char('k') | KeyCode::Up => app.season_prev(1),
             KeyCode::Char('j') | KeyCode::Down => app.season_next(1),
             KeyCode::Char(' ') => app.toggle_season_watch_status().await?,
+            // pick when the season was watched
+            KeyCode::Char('d') => app.pick_season_date(),
+            // open up the season's episodes
+            KeyCode::Char('l') | KeyCode::Right | KeyCode::Enter => {
+                app.enter_season_details().await?
+            }
+            _ => {}
+        },
+        AppMode::EpisodeView => match key_event.code {
+            KeyCode::Left | KeyCode::Char('h') => app.mode = AppMode::SeasonView,
+            KeyCode::Char('k') | KeyCode::Up => app.episode_prev(1),
+            KeyCode::Char('j') | KeyCode::Down => app.episode_next(1),
+            // watched (now) <-> unwatched
+            KeyCode::Char(' ') => app.toggle_episode_watch_status().await?,
+            // watched now <-> watched when it aired
+            KeyCode::Char('r') => app.toggle_episode_watched_at().await?,
+            _ => {}
+        },
+        AppMode::DatePicker => {
+            let Some(picked) = app.date_picker.as_mut() else {
+                return Ok(());
+            };
+            let picker = &mut picked.picker;
+            match key_event.code {
+                KeyCode::Left | KeyCode::Char('h') => picker.move_days(-1),
+                KeyCode::Right | KeyCode::Char('l') => picker.move_days(1),
+                KeyCode::Up | KeyCode::Char('k') => picker.move_days(-7),
+                KeyCode::Down | KeyCode::Char('j') => picker.move_days(7),
+                KeyCode::Char('[') | KeyCode::PageUp => picker.move_months(-1),
+                KeyCode::Char(']') | KeyCode::PageDown => picker.move_months(1),
+                KeyCode::Char('-') => picker.move_minutes(-60),
+                KeyCode::Char('+') | KeyCode::Char('=') => picker.move_minutes(60),
+                KeyCode::Char(',') => picker.move_minutes(-5),
+                KeyCode::Char('.') => picker.move_minutes(5),
+                KeyCode::Char('t') => *picker = DatePicker::new(Utc::now().naive_utc()),
+                KeyCode::Enter => app.confirm_date_picker().await?,
+                KeyCode::Esc => app.cancel_date_picker().await?,
+                _ => {}
+            }
+        }
+        AppMode::SearchResults => match key_event.code {
+            KeyCode::Esc | KeyCode::Left | KeyCode::Char('h') => app.mode = AppMode::MainView,
+            KeyCode::Tab => app.mode = AppMode::Querying,
+            KeyCode::Char('k') | KeyCode::Up => app.search_prev(1),
+            KeyCode::Char('j') | KeyCode::Down => app.search_next(1),
+            // previous/next page of results
+            KeyCode::Char('p') | KeyCode::PageUp => {
+                app.search_page(app.search.pagination.page.saturating_sub(1))
+                    .await
+            }
+            KeyCode::Char('n') | KeyCode::PageDown => {
+                app.search_page(app.search.pagination.page + 1).await
+            }
+            // add the selected show to our list
+            KeyCode::Enter | KeyCode::Char(' ') => app.add_search_result().await?,
+            _ => {}
+        },
+        AppMode::Reconcile if app.reconcile.confirming => match key_event.code {
+            KeyCode::Char('y') => app.apply_reconcile().await?,
+            _ => app.reconcile.confirming = false,
+        },
+        AppMode::Reconcile => match key_event.code {
+            KeyCode::Esc | KeyCode::Char('q') => app.mode = AppMode::MainView,
+            KeyCode::Char('k') | KeyCode::Up => app.reconcile_prev(1),
+            KeyCode::Char('j') | KeyCode::Down => app.reconcile_next(1),
+            // keep our side / trakt's side, for one conflict or (shifted) all of them
+            KeyCode::Char('l') => app.choose_winner(Some(Winner::Local), false),
+            KeyCode::Char('r') => app.choose_winner(Some(Winner::Remote), false),
+            KeyCode::Char('L') => app.choose_winner(Some(Winner::Local), true),
+            KeyCode::Char('R') => app.choose_winner(Some(Winner::Remote), true),
+            KeyCode::Char('u') => app.choose_winner(None, false),
+            KeyCode::Enter => app.apply_reconcile().await?,
+            _ => {}
+        },
+        AppMode::SyncPreview => match key_event.code {
+            KeyCode::Esc | KeyCode::Char('q') => app.mode = AppMode::MainView,
+            KeyCode::Char('k') | KeyCode::Up => app.sync_preview_prev(1),
+            KeyCode::Char('j') | KeyCode::Down => app.sync_preview_next(1),
+            KeyCode::PageUp => app.sync_preview_prev(20),
+            KeyCode::PageDown => app.sync_preview_next(20),
+            // go ahead and send it
+            KeyCode::Char('s') => {
+                app.sync_history().await;
+                app.mode = AppMode::MainView;
+            }
+            _ => {}
+        },
+        AppMode::SyncBatches => match key_event.code {
+            KeyCode::Esc | KeyCode::Char('q') => app.mode = AppMode::MainView,
+            KeyCode::Char('k') | KeyCode::Up => app.sync_batches_prev(1),
+            KeyCode::Char('j') | KeyCode::Down => app.sync_batches_next(1),
+            // undo the selected push
+            KeyCode::Char('u') => app.revert_sync_batch().await?,
+            _ => {}
+        },
+        // nothing to do but wait for the shows to load, or give up
+        AppMode::Initializing => match key_event.code {
+            KeyCode::Esc | KeyCode::Char('q') => app.quit(),
             _ => {}
         },
         _ => unimplemented!(),
@@ -7,6 +7,9 @@ mod event;
 /// Widget renderer.
 mod ui;
 
+/// Calendar for picking when something was watched.
+mod date_picker;
+
 /// Traits for converting db models to tui text/lines/cells
 mod ui_traits;
 
@@ -16,6 +19,7 @@ mod tui;
 /// Event handler.
 mod handler;
 
+use crate::config::TraktConfig;
 use crate::interface::{
     app::App,
     event::{Event, EventHandler},
@@ -26,9 +30,9 @@ use ratatui::backend::CrosstermBackend;
 use ratatui::Terminal;
 use std::io;
 
-pub async fn run() -> eyre::Result<()> {
+pub async fn run(config: TraktConfig) -> eyre::Result<()> {
     // Create an application.
-    let app = App::new().await?;
+    let app = App::new(&config).await?;
 
     // Initialize the terminal user interface.
     let backend = CrosstermBackend::new(io::stderr());
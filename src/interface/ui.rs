@@ -1,16 +1,164 @@
 use ratatui::{
     backend::Backend,
-    layout::{Constraint, Direction, Layout, Rect},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span, Text},
     widgets::{
-        Block, BorderType, Borders, Gauge, Paragraph, Row, Scrollbar, ScrollbarOrientation, Table,
-        Wrap,
+        Block, BorderType, Borders, Clear, Gauge, Paragraph, Row, Scrollbar, ScrollbarOrientation,
+        Table, Wrap,
     },
     Frame,
 };
 
+use chrono::Datelike;
+
 use crate::interface::app::{App, AppMode};
+use crate::models::HistoryChange;
+use crate::trakt::t_auth::AuthStatus;
+use crate::trakt::t_enrich::EnrichState;
+use crate::trakt::t_reconcile::{Classification, Winner};
+
+/// Carve a rect out of the middle of `area` (for popups)
+fn centered_rect(percent_x: u16, percent_y: u16, area: Rect) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Percentage((100 - percent_y) / 2),
+                Constraint::Percentage(percent_y),
+                Constraint::Percentage((100 - percent_y) / 2),
+            ]
+            .as_ref(),
+        )
+        .split(area);
+
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Percentage((100 - percent_x) / 2),
+                Constraint::Percentage(percent_x),
+                Constraint::Percentage((100 - percent_x) / 2),
+            ]
+            .as_ref(),
+        )
+        .split(vertical[1])[1]
+}
+
+/// Render a popup telling the user how to log in to trakt (if we're waiting on them)
+fn render_login_popup<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
+    let lines = match &*app.auth_status.borrow() {
+        AuthStatus::AwaitingUser {
+            user_code,
+            verification_url,
+        } => vec![
+            Line::from(format!("Go to {} and enter the code:", verification_url)),
+            Line::default(),
+            Line::from(Span::styled(
+                user_code.clone(),
+                Style::default().add_modifier(Modifier::BOLD),
+            )),
+        ],
+        AuthStatus::Failed(reason) => vec![
+            Line::from(format!("Could not log in: {}", reason)),
+            Line::default(),
+            Line::from("Press L to try again."),
+        ],
+        AuthStatus::LoggedOut | AuthStatus::LoggedIn => return,
+    };
+
+    let area = centered_rect(60, 30, frame.size());
+    let widget = Paragraph::new(lines).wrap(Wrap { trim: false }).block(
+        Block::default()
+            .title("Log in to trakt")
+            .border_type(BorderType::Rounded)
+            .borders(Borders::ALL)
+            .style(Style::default().fg(Color::Yellow).bg(Color::Black)),
+    );
+
+    frame.render_widget(Clear, area);
+    frame.render_widget(widget, area);
+}
+
+/// Render the calendar popup for picking when a season was watched
+fn render_date_picker<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
+    let Some(picked) = &app.date_picker else {
+        return;
+    };
+    let picker = &picked.picker;
+    let season = &app.show_view.seasons[picked.season];
+
+    let mut lines = vec![
+        Line::from(Span::styled(
+            picker.date().format("%B %Y").to_string(),
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
+        Line::from("Mo Tu We Th Fr Sa Su"),
+    ];
+    let selected_day = picker.date().day();
+    for week in picker.weeks() {
+        let days = week.iter().map(|day| match day {
+            Some(day) if *day == selected_day => Span::styled(
+                format!("{:>2}", day),
+                Style::default().add_modifier(Modifier::REVERSED),
+            ),
+            Some(day) => Span::raw(format!("{:>2}", day)),
+            None => Span::raw("  "),
+        });
+        let mut spans = vec![];
+        for day in days {
+            if !spans.is_empty() {
+                spans.push(Span::raw(" "));
+            }
+            spans.push(day);
+        }
+        lines.push(Line::from(spans));
+    }
+    lines.extend([
+        Line::default(),
+        Line::from(format!("at {} UTC", picker.time().format("%H:%M"))),
+        Line::default(),
+    ]);
+    if let Some(message) = &app.message {
+        lines.push(Line::from(Span::styled(
+            message.clone(),
+            Style::default().fg(Color::Red),
+        )));
+    }
+    lines.extend([
+        Line::from(Span::styled(
+            "arrows: day  [ ]: month",
+            Style::default().fg(Color::DarkGray),
+        )),
+        Line::from(Span::styled(
+            "- +: hour  , .: 5 min  t: now",
+            Style::default().fg(Color::DarkGray),
+        )),
+        Line::from(Span::styled(
+            "enter: save  esc: cancel",
+            Style::default().fg(Color::DarkGray),
+        )),
+    ]);
+
+    let size = frame.size();
+    let (width, height) = (34, lines.len() as u16 + 2);
+    let area = Rect {
+        x: size.width.saturating_sub(width) / 2,
+        y: size.height.saturating_sub(height) / 2,
+        width: width.min(size.width),
+        height: height.min(size.height),
+    };
+    let widget = Paragraph::new(lines).alignment(Alignment::Center).block(
+        Block::default()
+            .title(format!("Watched {}", season.title))
+            .border_type(BorderType::Rounded)
+            .borders(Borders::ALL)
+            .style(Style::default().fg(Color::Yellow).bg(Color::Black)),
+    );
+
+    frame.render_widget(Clear, area);
+    frame.render_widget(widget, area);
+}
 
 /// Render text input widget for querying shows
 fn render_input_area<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>, area: Rect) {
@@ -69,6 +217,11 @@ fn render_shows_table<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>, area:
 
     let rows = app.shows.iter().map(|show| Row::from(show));
 
+    let title = match app.pending_changes {
+        0 => "Shows".to_string(),
+        n => format!("Shows ({} changes waiting to sync)", n),
+    };
+
     frame.render_stateful_widget(
         Table::new(rows)
             .header(
@@ -80,7 +233,7 @@ fn render_shows_table<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>, area:
                 ])
                 .style(Style::default().fg(Color::Yellow)),
             )
-            .block(Block::default().title("Shows").borders(Borders::ALL))
+            .block(Block::default().title(title).borders(Borders::ALL))
             .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
             .highlight_symbol(">> ")
             .widths(&[
@@ -104,18 +257,99 @@ fn render_shows_table<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>, area:
     )
 }
 
+/// Render the one-line message area (errors, etc), falling back to trakt api usage
+fn render_message<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>, area: Rect) {
+    if let Some(message) = &app.message {
+        let widget = Paragraph::new(message.as_str()).style(Style::default().fg(Color::Yellow));
+        frame.render_widget(widget, area);
+        return;
+    }
+
+    let usage = app.client.limiter.usage();
+    let mut text = format!(
+        "trakt: {}/{} calls in last 5m",
+        usage.in_window, usage.budget
+    );
+    if usage.retries > 0 {
+        text += &format!(
+            ", {} retries ({} rate limited)",
+            usage.retries, usage.rate_limited
+        );
+    }
+    let widget = Paragraph::new(text)
+        .style(Style::default().fg(Color::DarkGray))
+        .alignment(Alignment::Right);
+    frame.render_widget(widget, area);
+
+    let import = app.import_progress.borrow().clone();
+    if !import.is_done() {
+        let text = match import.percent() {
+            Some(percent) => format!(
+                "Importing IMDB shows: {} saved ({}%, about {} left)",
+                import.rows_written,
+                percent,
+                format_eta(import.eta())
+            ),
+            None => format!(
+                "Importing IMDB shows: {} saved ({} MB read)",
+                import.rows_written,
+                import.bytes_read / 1_000_000
+            ),
+        };
+        let widget = Paragraph::new(text).style(Style::default().fg(Color::DarkGray));
+        frame.render_widget(widget, area);
+        return;
+    }
+
+    let progress = app.enrich_progress.borrow().clone();
+    let text = match progress.state {
+        EnrichState::Finished => return,
+        EnrichState::Running => format!(
+            "Fetching show details: {}/{} ({}%)",
+            progress.processed(),
+            progress.total,
+            progress.percent()
+        ),
+        EnrichState::Paused => format!(
+            "Fetching show details paused at {}/{} (e to resume)",
+            progress.processed(),
+            progress.total
+        ),
+    };
+    let widget = Paragraph::new(text).style(Style::default().fg(Color::DarkGray));
+    frame.render_widget(widget, area);
+}
+
 /// renders main view (includes search bar)
 fn render_main_view<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
     let outer = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Length(1), Constraint::Min(0)].as_ref())
+        .constraints(
+            [
+                Constraint::Length(1),
+                Constraint::Min(0),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
         .split(frame.size());
 
     render_input_area(app, frame, outer[0]);
     render_shows_table(app, frame, outer[1]);
+    render_message(app, frame, outer[2]);
+    render_login_popup(app, frame);
 }
 
-fn initalize_app<B: Backend>(_app: &mut App, frame: &mut Frame<'_, B>) {
+/// "1m 05s" style durations for ETAs
+fn format_eta(eta: Option<std::time::Duration>) -> String {
+    match eta.map(|d| d.as_secs()) {
+        Some(secs) if secs >= 60 => format!("{}m {:02}s", secs / 60, secs % 60),
+        Some(secs) => format!("{}s", secs),
+        None => "?".to_string(),
+    }
+}
+
+fn initalize_app<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
         .constraints(
@@ -145,20 +379,43 @@ fn initalize_app<B: Backend>(_app: &mut App, frame: &mut Frame<'_, B>) {
         )
         .split(chunks[1]);
 
-    let widget = Paragraph::new("Initializing ...")
-        .style(Style::default())
-        .block(
-            Block::default()
-                .border_type(BorderType::Rounded)
-                .borders(Borders::ALL)
-                .style(Style::default().fg(Color::Gray)),
-        );
+    let import = app.import_progress.borrow().clone();
+    let text = vec![
+        Line::from("Importing shows from the IMDB dataset ..."),
+        Line::from(format!(
+            "{} / {} MB read, {} shows found, {} saved",
+            import.bytes_read / 1_000_000,
+            import
+                .bytes_total
+                .map_or("?".to_string(), |total| (total / 1_000_000).to_string()),
+            import.rows_accepted,
+            import.rows_written
+        )),
+    ];
+    let widget = Paragraph::new(text).style(Style::default()).block(
+        Block::default()
+            .border_type(BorderType::Rounded)
+            .borders(Borders::ALL)
+            .style(Style::default().fg(Color::Gray)),
+    );
 
     frame.render_widget(widget, chunks[1]);
 
-    // doesn't actually track progress of initialization yet.
-    // i load all items at once on startup.
-    let progress = Gauge::default().percent(0).block(
+    let progress = match import.percent() {
+        Some(percent) => Gauge::default().percent(percent).label(format!(
+            "{}% (about {} left)",
+            percent,
+            format_eta(import.eta())
+        )),
+        // size unknown: sweep the bar so it's clear we're still going
+        None => {
+            let elapsed = import.started_at.map_or(0, |s| s.elapsed().as_millis());
+            Gauge::default()
+                .percent((elapsed / 50 % 100) as u16)
+                .label("size unknown")
+        }
+    };
+    let progress = progress.block(
         Block::default()
             .border_type(BorderType::Rounded)
             .borders(Borders::ALL)
@@ -234,6 +491,335 @@ fn render_season_view<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
     unreachable!()
 }
 
+/// Render the episodes of a TV season.
+fn render_episode_view<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
+    let (Some(show), Some(season)) = (
+        app.table_state.selected().map(|i| &app.shows[i]),
+        app.show_view
+            .season_table_state
+            .selected()
+            .map(|i| &app.show_view.seasons[i]),
+    ) else {
+        // can't be called unless there's a season selected in season view.
+        unreachable!()
+    };
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+        .split(frame.size());
+
+    let title = format!(
+        "{}: {} ({} episodes)",
+        show.primary_title, season.title, season.episode_count
+    );
+    let rows = app.show_view.episodes.iter().map(Row::from);
+
+    frame.render_stateful_widget(
+        Table::new(rows)
+            .header(
+                Row::new(vec![
+                    "episode #",
+                    "title",
+                    "aired",
+                    "watch status",
+                    "watched at",
+                ])
+                .style(Style::default().fg(Color::Yellow)),
+            )
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol(">> ")
+            .widths(&[
+                // episode #
+                Constraint::Length(10),
+                // title
+                Constraint::Percentage(40),
+                // aired
+                Constraint::Length(21),
+                // watch status
+                Constraint::Length(13),
+                // watched at
+                Constraint::Length(21),
+            ])
+            .style(Style::default().fg(Color::Cyan).bg(Color::Black)),
+        chunks[0],
+        &mut app.show_view.episode_table_state,
+    );
+
+    render_message(app, frame, chunks[1]);
+}
+
+/// Render the results of a trakt search, for picking a show to add.
+fn render_search_view<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+        .split(frame.size());
+
+    let search = &mut app.search;
+    let title = format!(
+        "Search results for '{}' (page {}/{}, {} shows)",
+        search.query,
+        search.pagination.page,
+        search.pagination.page_count.max(1),
+        search.pagination.item_count
+    );
+
+    let rows = search.results.iter().map(|found| {
+        Row::new(vec![
+            format!("{:.0}", found.score),
+            found.show.title.clone(),
+            found.show.year.map(|y| y.to_string()).unwrap_or_default(),
+            found.show.ids.imdb.clone().unwrap_or_default(),
+            found.show.ids.trakt.to_string(),
+        ])
+    });
+
+    frame.render_stateful_widget(
+        Table::new(rows)
+            .header(
+                Row::new(vec!["score", "title", "year", "imdb", "trakt"])
+                    .style(Style::default().fg(Color::Yellow)),
+            )
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol(">> ")
+            .widths(&[
+                // score
+                Constraint::Length(6),
+                // title
+                Constraint::Percentage(50),
+                // year
+                Constraint::Length(5),
+                // imdb
+                Constraint::Length(11),
+                // trakt
+                Constraint::Length(8),
+            ])
+            .style(Style::default().fg(Color::Cyan).bg(Color::Black)),
+        chunks[0],
+        &mut search.table_state,
+    );
+
+    render_message(app, frame, chunks[1]);
+    render_login_popup(app, frame);
+}
+
+/// Render the conflicts between the local db and trakt, with the side picked for each.
+fn render_reconcile_view<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+        .split(frame.size());
+
+    let review = &mut app.reconcile;
+    let count = |class| review.counts.get(&class).copied().unwrap_or_default();
+    let title = format!(
+        "Conflicts with trakt ({} identical, {} only here, {} only on trakt, {} conflicting)",
+        count(Classification::Identical),
+        count(Classification::LocalOnly),
+        count(Classification::RemoteOnly),
+        count(Classification::Conflict)
+    );
+
+    let rows = review
+        .conflicts
+        .iter()
+        .zip(review.choices.iter())
+        .map(|(item, choice)| {
+            let keep = match choice {
+                Some(Winner::Local) => "local",
+                Some(Winner::Remote) => "trakt",
+                None => "",
+            };
+            Row::new(vec![
+                item.label.clone(),
+                item.local.to_string(),
+                item.remote.to_string(),
+                keep.to_string(),
+            ])
+        });
+
+    frame.render_stateful_widget(
+        Table::new(rows)
+            .header(
+                Row::new(vec!["item", "local", "trakt", "keep"])
+                    .style(Style::default().fg(Color::Yellow)),
+            )
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol(">> ")
+            .widths(&[
+                // item
+                Constraint::Percentage(40),
+                // local
+                Constraint::Length(24),
+                // trakt
+                Constraint::Length(24),
+                // keep
+                Constraint::Length(6),
+            ])
+            .style(Style::default().fg(Color::Cyan).bg(Color::Black)),
+        chunks[0],
+        &mut review.table_state,
+    );
+
+    if app.message.is_some() {
+        render_message(app, frame, chunks[1]);
+    } else {
+        let help = "l/r: keep local/trakt  L/R: keep local/trakt for all  u: undecided  \
+                    enter: apply  esc: cancel";
+        let widget = Paragraph::new(help).style(Style::default().fg(Color::DarkGray));
+        frame.render_widget(widget, chunks[1]);
+    }
+}
+
+/// Render what a sync would send: episodes added/removed per show and season.
+fn render_sync_preview<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+        .split(frame.size());
+
+    let view = &mut app.sync_preview;
+    let plan = &view.preview.plan;
+    let title = format!(
+        "Sync preview ({} add requests, {} entries to take off, nothing sent yet)",
+        plan.add.len(),
+        plan.remove.len()
+    );
+
+    let diff = |added: usize, removed: usize| {
+        let added = if added > 0 {
+            format!("+{}", added)
+        } else {
+            String::new()
+        };
+        let removed = if removed > 0 {
+            format!("-{}", removed)
+        } else {
+            String::new()
+        };
+        (added, removed)
+    };
+
+    let mut rows = vec![];
+    for show in view.preview.shows.iter() {
+        let (added, removed) = diff(show.added, show.removed);
+        rows.push(
+            Row::new(vec![show.title.clone(), added, removed])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        );
+        for season in show.seasons.iter() {
+            let (added, removed) = diff(season.added, season.removed);
+            rows.push(Row::new(vec![
+                format!("    Season {}", season.number),
+                added,
+                removed,
+            ]));
+        }
+    }
+
+    frame.render_stateful_widget(
+        Table::new(rows)
+            .header(
+                Row::new(vec!["show", "added", "removed"])
+                    .style(Style::default().fg(Color::Yellow)),
+            )
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol(">> ")
+            .widths(&[
+                // show / season
+                Constraint::Percentage(60),
+                // episodes added
+                Constraint::Length(8),
+                // episodes removed
+                Constraint::Length(8),
+            ])
+            .style(Style::default().fg(Color::Cyan).bg(Color::Black)),
+        chunks[0],
+        &mut view.table_state,
+    );
+
+    if app.message.is_some() {
+        render_message(app, frame, chunks[1]);
+    } else {
+        let help = "counts are episodes  s: sync now  esc: back";
+        let widget = Paragraph::new(help).style(Style::default().fg(Color::DarkGray));
+        frame.render_widget(widget, chunks[1]);
+    }
+}
+
+/// Render the past pushes to trakt, with how much each one changed.
+fn render_sync_batches<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(1)].as_ref())
+        .split(frame.size());
+
+    let view = &mut app.sync_batches;
+    let rows = view.batches.iter().map(|(batch, items)| {
+        let count = |change| items.iter().filter(|i| i.change == change).count();
+        let reverted = batch
+            .reverted_at
+            .map(|at| format!("reverted {}", at.format("%Y-%m-%d %H:%M")))
+            .unwrap_or_default();
+        let style = if batch.reverted_at.is_some() {
+            Style::default().fg(Color::DarkGray)
+        } else {
+            Style::default()
+        };
+        Row::new(vec![
+            format!("#{}", batch.id),
+            batch.pushed_at.format("%Y-%m-%d %H:%M").to_string(),
+            format!("+{}", count(HistoryChange::Added)),
+            format!("-{}", count(HistoryChange::Removed)),
+            reverted,
+        ])
+        .style(style)
+    });
+
+    frame.render_stateful_widget(
+        Table::new(rows)
+            .header(
+                Row::new(vec!["", "pushed", "added", "removed", ""])
+                    .style(Style::default().fg(Color::Yellow)),
+            )
+            .block(
+                Block::default()
+                    .title("Syncs to trakt")
+                    .borders(Borders::ALL),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol(">> ")
+            .widths(&[
+                // id
+                Constraint::Length(6),
+                // pushed at
+                Constraint::Length(18),
+                // items added
+                Constraint::Length(8),
+                // items removed
+                Constraint::Length(8),
+                // reverted at
+                Constraint::Min(0),
+            ])
+            .style(Style::default().fg(Color::Cyan).bg(Color::Black)),
+        chunks[0],
+        &mut view.table_state,
+    );
+
+    if app.message.is_some() {
+        render_message(app, frame, chunks[1]);
+    } else {
+        let help = "counts are shows/seasons/episodes  u: undo the selected sync  esc: back";
+        let widget = Paragraph::new(help).style(Style::default().fg(Color::DarkGray));
+        frame.render_widget(widget, chunks[1]);
+    }
+}
+
 /// Renders the user interface widgets.
 pub fn render<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
     match app.mode {
@@ -242,6 +828,14 @@ pub fn render<B: Backend>(app: &mut App, frame: &mut Frame<'_, B>) {
         AppMode::Querying => render_main_view(app, frame),
         AppMode::HelpWindow => unimplemented!(),
         AppMode::SeasonView => render_season_view(app, frame),
-        // uh oh, i'm worried i'll need an episode view as well?
+        AppMode::SearchResults => render_search_view(app, frame),
+        AppMode::EpisodeView => render_episode_view(app, frame),
+        AppMode::DatePicker => {
+            render_season_view(app, frame);
+            render_date_picker(app, frame);
+        }
+        AppMode::Reconcile => render_reconcile_view(app, frame),
+        AppMode::SyncPreview => render_sync_preview(app, frame),
+        AppMode::SyncBatches => render_sync_batches(app, frame),
     }
 }
@@ -3,7 +3,7 @@ use ratatui::{
     widgets::Cell,
 };
 
-use crate::models::{TraktSeason, TraktShow};
+use crate::models::{TraktEpisode, TraktSeason, TraktShow};
 
 // implementation of From trait for TraktSeason to a ratatui table Row
 impl From<&TraktSeason> for ratatui::widgets::Row<'_> {
@@ -21,8 +21,29 @@ impl From<&TraktSeason> for ratatui::widgets::Row<'_> {
                     .format("%Y-%m-%d UTC")
                     .to_string(),
             ),
-            // map season watch status to a ratatui Text
-            Cell::from(Text::from(season.user_status.clone())),
+            // map season watch status to a ratatui Text (with the date, if one was picked)
+            match season.watched_at {
+                Some(at) => Cell::from(format!("OTHER_DATE {}", at.format("%Y-%m-%d %H:%M"))),
+                None => Cell::from(Text::from(season.user_status.clone())),
+            },
+        ])
+    }
+}
+
+// implementation of From trait for TraktEpisode to a ratatui table Row
+impl From<&TraktEpisode> for ratatui::widgets::Row<'_> {
+    fn from(episode: &TraktEpisode) -> Self {
+        let date = |d: Option<chrono::NaiveDateTime>| {
+            d.map(|d| d.format("%Y-%m-%d %H:%M UTC").to_string())
+                .unwrap_or_default()
+        };
+
+        ratatui::widgets::Row::new(vec![
+            Cell::from(episode.episode_number.to_string()),
+            Cell::from(episode.title.clone()),
+            Cell::from(date(episode.first_aired)),
+            Cell::from(Text::from(episode.user_status.clone())),
+            Cell::from(date(episode.watched_at)),
         ])
     }
 }
@@ -1,15 +1,70 @@
 #![feature(let_chains)]
 #![feature(type_alias_impl_trait)]
 
+mod config;
 mod interface;
 mod models;
 mod schema;
 mod sources;
 mod trakt;
 
+use config::{Profile, TraktConfig};
 use log::*;
 use simplelog::*;
 use std::fs::File;
+use trakt::t_mock::MockTrakt;
+
+const USAGE: &str = "usage:
+    trakt-tv-updater [--profile <production|staging|local>]
+    trakt-tv-updater sync --dry-run
+    trakt-tv-updater mock-server [--addr <host:port>] [--fail <path>=<status>[x<times>]]...";
+
+// the local profile's default url
+const MOCK_ADDR: &str = "127.0.0.1:8080";
+
+enum Command {
+    /// run the app. `--profile` picks the trakt deployment (overrides TRAKT_PROFILE)
+    Run(Option<Profile>),
+    /// print what a sync would send to trakt, as JSON, without sending it
+    SyncDryRun,
+    /// serve fixture responses in place of trakt, for `--profile local`
+    MockServer(MockTrakt, String),
+}
+
+fn parse_args() -> eyre::Result<Command> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => Ok(Command::Run(None)),
+        [flag, name] if flag == "--profile" || flag == "-p" => {
+            Ok(Command::Run(Some(name.parse()?)))
+        }
+        [command, flag] if command == "sync" && flag == "--dry-run" => Ok(Command::SyncDryRun),
+        [command, rest @ ..] if command == "mock-server" => {
+            let mock = MockTrakt::new()?;
+            let mut addr = MOCK_ADDR.to_string();
+            let mut rest = rest.iter();
+            while let Some(flag) = rest.next() {
+                let value = rest
+                    .next()
+                    .ok_or_else(|| eyre::eyre!("{} needs a value\n{}", flag, USAGE))?;
+                match flag.as_str() {
+                    "--addr" => addr = value.clone(),
+                    // e.g. `--fail /shows/tt0903747=503x2`
+                    "--fail" => {
+                        let (path, status) = value
+                            .split_once('=')
+                            .ok_or_else(|| eyre::eyre!("bad --fail '{}'\n{}", value, USAGE))?;
+                        let (status, times) = status.split_once('x').unwrap_or((status, "1"));
+                        mock.fail(path, status.parse()?, times.parse()?);
+                    }
+                    _ => eyre::bail!(USAGE),
+                }
+            }
+            Ok(Command::MockServer(mock, addr))
+        }
+        _ => eyre::bail!(USAGE),
+    }
+}
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
@@ -25,5 +80,22 @@ async fn main() -> eyre::Result<()> {
     )
     .unwrap();
 
-    interface::run().await
+    match parse_args()? {
+        Command::Run(profile) => {
+            let config = TraktConfig::load(profile)?;
+            info!(
+                "Using trakt {} profile ({})",
+                config.profile, config.base_url
+            );
+
+            interface::run(config).await
+        }
+        Command::SyncDryRun => {
+            let cache = trakt::t_db::PersistentDb::connect().await?;
+            let preview = trakt::t_sync::preview_sync(&cache).await?;
+            println!("{}", serde_json::to_string_pretty(&preview)?);
+            Ok(())
+        }
+        Command::MockServer(mock, addr) => mock.serve(&addr).await,
+    }
 }
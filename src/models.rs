@@ -1,6 +1,9 @@
-use super::schema::{episodes, seasons, trakt_shows};
+use super::schema::{
+    episodes, oauth_tokens, outbox, ratings, seasons, sync_activities, sync_batch_items,
+    sync_batches, synced_history, trakt_shows,
+};
 
-use chrono::{NaiveDateTime, Utc};
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 
 macro_rules! ratatui_line {
@@ -74,17 +77,84 @@ impl From<UserStatusShow> for ratatui::text::Text<'_> {
     }
 }
 
-/// Track the specific datetime at which a user watched an episode.
-// #[derive(Clone, Debug, PartialEq, diesel_derive_enum::DbEnum)]
-#[derive(Clone, Debug, PartialEq)]
-pub struct UserDateTimeStatus {
-    pub imdb_id: String,
-    pub trakt_id: i32,
-    pub watched: chrono::DateTime<Utc>,
+/// Which kind of trakt item a history entry refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, diesel_derive_enum::DbEnum, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncItemKind {
+    Show,
+    Season,
+    Episode,
 }
 
-#[derive(Clone, Debug, Queryable, Selectable, Insertable, PartialEq)]
+/// The kinds of user data trakt reports activity times for (see `/sync/last_activities`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, diesel_derive_enum::DbEnum)]
+pub enum ActivityCategory {
+    Watched,
+    Watchlist,
+    Ratings,
+    Hidden,
+}
+
+impl ActivityCategory {
+    pub const ALL: [ActivityCategory; 4] = [
+        ActivityCategory::Watched,
+        ActivityCategory::Watchlist,
+        ActivityCategory::Ratings,
+        ActivityCategory::Hidden,
+    ];
+}
+
+/// A change to make on trakt, queued in the outbox.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, diesel_derive_enum::DbEnum)]
+pub enum OutboxOperation {
+    AddHistory,
+    /// takes off the plays we added for the item (looked up when it's sent)
+    RemoveHistory,
+    AddWatchlist,
+    RemoveWatchlist,
+    Hide,
+    Unhide,
+}
+
+impl OutboxOperation {
+    /// A pending operation of this kind that becomes pointless once `self` is queued
+    /// (it hasn't reached trakt yet, and `self` would undo it anyway).
+    pub fn cancels(&self) -> Option<OutboxOperation> {
+        match self {
+            OutboxOperation::AddHistory => None,
+            OutboxOperation::RemoveHistory => Some(OutboxOperation::AddHistory),
+            OutboxOperation::AddWatchlist => Some(OutboxOperation::RemoveWatchlist),
+            OutboxOperation::RemoveWatchlist => Some(OutboxOperation::AddWatchlist),
+            OutboxOperation::Hide => Some(OutboxOperation::Unhide),
+            OutboxOperation::Unhide => Some(OutboxOperation::Hide),
+        }
+    }
+
+    /// Whether sending this twice has the same effect as sending it once.
+    /// (adding history twice records two plays on trakt)
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            OutboxOperation::AddHistory => false,
+            OutboxOperation::RemoveHistory
+            | OutboxOperation::AddWatchlist
+            | OutboxOperation::RemoveWatchlist
+            | OutboxOperation::Hide
+            | OutboxOperation::Unhide => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, diesel_derive_enum::DbEnum)]
+pub enum OutboxState {
+    Pending,
+    Done,
+    /// gave up after too many attempts (or trakt rejected it outright)
+    Failed,
+}
+
+#[derive(Clone, Debug, Queryable, Selectable, Insertable, Identifiable, PartialEq)]
 #[diesel(table_name = trakt_shows)]
+#[diesel(primary_key(imdb_id))]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct TraktShow {
     pub imdb_id: String,
@@ -98,26 +168,69 @@ pub struct TraktShow {
     pub no_episodes: Option<i32>,
     pub overview: Option<String>,
     pub user_status: UserStatusShow,
+    /// `None` for shows the user never set a status on (e.g. fresh from the IMDB dump)
+    pub status_changed_at: Option<NaiveDateTime>,
 }
 
-#[derive(Clone, Debug, Queryable, Selectable, Insertable, PartialEq)]
+/// A season, selected along with its show (`seasons` joined with `trakt_shows`).
+#[derive(Clone, Debug, Queryable, Selectable, Identifiable, Associations, PartialEq)]
 #[diesel(table_name = seasons)]
+#[diesel(belongs_to(TraktShow, foreign_key = show_imdb_id))]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct TraktSeason {
     pub id: i32,
     pub title: String,
     pub first_aired: Option<NaiveDateTime>,
+    /// the show's trakt id, which is what trakt wants to hear about
+    #[diesel(select_expression = trakt_shows::trakt_id.assume_not_null())]
+    #[diesel(select_expression_type = diesel::dsl::AssumeNotNull<trakt_shows::trakt_id>)]
     pub show_id: i32,
+    pub show_imdb_id: String,
     pub season_number: i32,
     pub episode_count: i32,
     pub user_status: UserStatusSeason,
+    /// when the user watched the season (only for `OtherDate`)
+    pub watched_at: Option<NaiveDateTime>,
 }
 
-#[derive(Clone, Debug, Queryable, Selectable, Insertable, PartialEq)]
+/// The columns of a `TraktSeason` that live in `seasons`, for inserting it.
+#[derive(Insertable)]
+#[diesel(table_name = seasons)]
+pub struct NewTraktSeason<'a> {
+    pub id: i32,
+    pub title: &'a str,
+    pub first_aired: Option<NaiveDateTime>,
+    pub show_imdb_id: &'a str,
+    pub season_number: i32,
+    pub episode_count: i32,
+    pub user_status: &'a UserStatusSeason,
+    pub watched_at: Option<NaiveDateTime>,
+}
+
+impl<'a> From<&'a TraktSeason> for NewTraktSeason<'a> {
+    fn from(season: &'a TraktSeason) -> Self {
+        NewTraktSeason {
+            id: season.id,
+            title: &season.title,
+            first_aired: season.first_aired,
+            show_imdb_id: &season.show_imdb_id,
+            season_number: season.season_number,
+            episode_count: season.episode_count,
+            user_status: &season.user_status,
+            watched_at: season.watched_at,
+        }
+    }
+}
+
+#[derive(
+    Clone, Debug, Queryable, Selectable, Insertable, Identifiable, Associations, PartialEq,
+)]
 #[diesel(table_name = episodes)]
+#[diesel(belongs_to(TraktSeason, foreign_key = season_id))]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct TraktEpisode {
     pub id: i32,
+    pub season_id: i32,
     pub show_id: i32,
     pub season_number: i32,
     pub episode_number: i32,
@@ -126,3 +239,125 @@ pub struct TraktEpisode {
     pub watched_at: Option<NaiveDateTime>,
     pub user_status: UserStatusEpisode,
 }
+
+/// OAuth credentials for a trakt application, as granted by the device-code flow.
+#[derive(Clone, Debug, Queryable, Selectable, Insertable, PartialEq)]
+#[diesel(table_name = oauth_tokens)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct OAuthToken {
+    pub client_id: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+/// A history entry that trakt accepted from a sync (or already had).
+#[derive(Clone, Debug, Queryable, Selectable, Insertable, PartialEq, serde::Serialize)]
+#[diesel(table_name = synced_history)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct SyncedItem {
+    pub kind: SyncItemKind,
+    pub trakt_id: i32,
+    pub watched_at: Option<NaiveDateTime>,
+    pub synced_at: NaiveDateTime,
+    /// `false` for plays that were on trakt before we saw them, which are never removed
+    pub pushed: bool,
+}
+
+/// Whether a sync put a history entry on trakt or took it off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, diesel_derive_enum::DbEnum)]
+pub enum HistoryChange {
+    Added,
+    Removed,
+}
+
+/// One push of local history to trakt.
+#[derive(Clone, Debug, Queryable, Selectable, PartialEq)]
+#[diesel(table_name = sync_batches)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct SyncBatch {
+    pub id: i32,
+    pub pushed_at: NaiveDateTime,
+    pub reverted_at: Option<NaiveDateTime>,
+}
+
+/// A history entry that a push added to (or removed from) trakt.
+#[derive(Clone, Debug, Queryable, Selectable, Insertable, PartialEq)]
+#[diesel(table_name = sync_batch_items)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct SyncBatchItem {
+    pub batch_id: i32,
+    pub kind: SyncItemKind,
+    pub trakt_id: i32,
+    pub change: HistoryChange,
+    pub watched_at: Option<NaiveDateTime>,
+    /// `false` for a removed entry we never pushed: it was only forgotten, trakt still has it
+    pub pushed: bool,
+    /// the local state before the push (see `SyncBatchItem::previous`)
+    pub previous_watched: bool,
+    pub previous_watched_at: Option<NaiveDateTime>,
+}
+
+impl SyncBatchItem {
+    /// Whether the item was watched (and when, `None` for on release) before the push.
+    pub fn previous(&self) -> Option<Option<NaiveDateTime>> {
+        self.previous_watched.then_some(self.previous_watched_at)
+    }
+}
+
+/// A push along with the history entries it changed.
+pub type RecordedBatch = (SyncBatch, Vec<SyncBatchItem>);
+
+/// When trakt last reported a change in one category of user data, as of our last pull.
+#[derive(Clone, Debug, Queryable, Selectable, Insertable, PartialEq)]
+#[diesel(table_name = sync_activities)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct SyncActivity {
+    pub category: ActivityCategory,
+    pub updated_at: NaiveDateTime,
+}
+
+/// The user's rating (1-10) of a show, season or episode on trakt.
+#[derive(Clone, Debug, Queryable, Selectable, Insertable, PartialEq)]
+#[diesel(table_name = ratings)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Rating {
+    pub kind: SyncItemKind,
+    pub trakt_id: i32,
+    pub rating: i32,
+    pub rated_at: NaiveDateTime,
+}
+
+/// A queued change to send to trakt.
+#[derive(Clone, Debug, Queryable, Selectable, PartialEq)]
+#[diesel(table_name = outbox)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct OutboxItem {
+    pub id: i32,
+    pub kind: SyncItemKind,
+    pub trakt_id: i32,
+    pub operation: OutboxOperation,
+    pub watched_at: Option<NaiveDateTime>,
+    pub payload: String,
+    pub state: OutboxState,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// An outbox entry that hasn't been stored yet (the db assigns its id).
+#[derive(Clone, Debug, Insertable, PartialEq)]
+#[diesel(table_name = outbox)]
+pub struct NewOutboxItem {
+    pub kind: SyncItemKind,
+    pub trakt_id: i32,
+    pub operation: OutboxOperation,
+    pub watched_at: Option<NaiveDateTime>,
+    pub payload: String,
+    pub state: OutboxState,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
@@ -3,6 +3,7 @@
 diesel::table! {
     episodes (id) {
         id -> Integer,
+        season_id -> Integer,
         show_id -> Integer,
         season_number -> Integer,
         episode_number -> Integer,
@@ -13,15 +14,89 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    oauth_tokens (client_id) {
+        client_id -> Text,
+        access_token -> Text,
+        refresh_token -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    outbox (id) {
+        id -> Integer,
+        kind -> crate::models::SyncItemKindMapping,
+        trakt_id -> Integer,
+        operation -> crate::models::OutboxOperationMapping,
+        watched_at -> Nullable<Timestamp>,
+        payload -> Text,
+        state -> crate::models::OutboxStateMapping,
+        attempts -> Integer,
+        next_attempt_at -> Timestamp,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    ratings (kind, trakt_id) {
+        kind -> crate::models::SyncItemKindMapping,
+        trakt_id -> Integer,
+        rating -> Integer,
+        rated_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     seasons (id) {
         id -> Integer,
         title -> Text,
         first_aired -> Nullable<Timestamp>,
-        show_id -> Integer,
+        show_imdb_id -> Text,
         season_number -> Integer,
         episode_count -> Integer,
         user_status -> crate::models::UserStatusSeasonMapping,
+        watched_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    sync_batch_items (batch_id, kind, trakt_id, change) {
+        batch_id -> Integer,
+        kind -> crate::models::SyncItemKindMapping,
+        trakt_id -> Integer,
+        change -> crate::models::HistoryChangeMapping,
+        watched_at -> Nullable<Timestamp>,
+        pushed -> Bool,
+        previous_watched -> Bool,
+        previous_watched_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    sync_batches (id) {
+        id -> Integer,
+        pushed_at -> Timestamp,
+        reverted_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    sync_activities (category) {
+        category -> crate::models::ActivityCategoryMapping,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    synced_history (kind, trakt_id) {
+        kind -> crate::models::SyncItemKindMapping,
+        trakt_id -> Integer,
+        watched_at -> Nullable<Timestamp>,
+        synced_at -> Timestamp,
+        pushed -> Bool,
     }
 }
 
@@ -38,9 +113,23 @@ diesel::table! {
         no_episodes -> Nullable<Integer>,
         overview -> Nullable<Text>,
         user_status -> crate::models::UserStatusShowMapping,
+        status_changed_at -> Nullable<Timestamp>,
     }
 }
 
-diesel::joinable!(episodes -> seasons (show_id));
+diesel::joinable!(episodes -> seasons (season_id));
+diesel::joinable!(seasons -> trakt_shows (show_imdb_id));
+diesel::joinable!(sync_batch_items -> sync_batches (batch_id));
 
-diesel::allow_tables_to_appear_in_same_query!(episodes, seasons, trakt_shows,);
+diesel::allow_tables_to_appear_in_same_query!(
+    episodes,
+    oauth_tokens,
+    outbox,
+    ratings,
+    seasons,
+    sync_activities,
+    sync_batch_items,
+    sync_batches,
+    synced_history,
+    trakt_shows,
+);
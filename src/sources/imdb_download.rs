@@ -0,0 +1,538 @@
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use eyre::Context;
+use log::*;
+use reqwest::{header, StatusCode};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+
+use crate::sources::imdb_reader::{self, ImportProgress};
+use crate::trakt::t_db::PersistentDb;
+
+const DEFAULT_DATASET_URL: &str = "https://datasets.imdbws.com/title.basics.tsv.gz";
+// what the dataset is saved as, in the app's data directory
+pub const DATASET_FILE_NAME: &str = "title.basics.tsv.gz";
+// downloaded chunks waiting on the importer
+const STREAM_AHEAD_CHUNKS: usize = 64;
+// size of the chunks a resumed download's first part is re-read in
+const REPLAY_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Where to download the dataset from: `IMDB_DATASET_URL`, or IMDB's own.
+pub fn dataset_url() -> String {
+    env::var("IMDB_DATASET_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| DEFAULT_DATASET_URL.to_string())
+}
+
+/// The dataset on disk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dataset {
+    pub path: PathBuf,
+    /// the server's ETag for this copy, if it sent one
+    pub etag: Option<String>,
+}
+
+impl Dataset {
+    /// Whatever copy we already have, without asking the server.
+    pub fn on_disk(path: &Path) -> Dataset {
+        Dataset {
+            path: path.to_path_buf(),
+            etag: read_sidecar(path, "etag"),
+        }
+    }
+
+    /// Whether this copy has been imported already. Copies without an ETag never count.
+    pub fn is_imported(&self) -> bool {
+        self.etag.is_some() && read_sidecar(&self.path, "imported") == self.etag
+    }
+
+    /// Remember that this copy has been imported, so it isn't imported again.
+    pub fn mark_imported(&self) -> eyre::Result<()> {
+        write_sidecar(&self.path, "imported", self.etag.as_deref())
+    }
+}
+
+/// What asking the server for the dataset got us.
+#[derive(Debug)]
+pub enum Fetched {
+    /// the copy on disk is still current
+    Current(Dataset),
+    /// a new copy (or the rest of one), to be saved
+    Download(Download),
+}
+
+/// A download that has started (i.e. we have the response headers), but not been saved.
+#[derive(Debug)]
+pub struct Download {
+    url: String,
+    path: PathBuf,
+    response: reqwest::Response,
+    etag: Option<String>,
+    offset: u64,
+    total: Option<u64>,
+}
+
+/// Chunks of a download on their way to the importer. A failed download ends with an error.
+pub type ChunkSender = mpsc::Sender<io::Result<Vec<u8>>>;
+
+/// `path` with `.ext` tacked on (e.g. the ETag kept next to a download).
+fn sidecar(path: &Path, ext: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+fn read_sidecar(path: &Path, ext: &str) -> Option<String> {
+    std::fs::read_to_string(sidecar(path, ext))
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Write (or with `None`, remove) a sidecar file.
+fn write_sidecar(path: &Path, ext: &str, value: Option<&str>) -> eyre::Result<()> {
+    let file = sidecar(path, ext);
+    match value {
+        Some(value) => std::fs::write(&file, value),
+        None => match std::fs::remove_file(&file) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            r => r,
+        },
+    }
+    .wrap_err_with(|| format!("could not write {}", file.display()))
+}
+
+/// `bytes <start>-<end>/<total>` -> (start, total)
+fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _end) = range.split_once('-')?;
+    Some((start.parse().ok()?, total.parse().ok()?))
+}
+
+/// Ask for the dataset at `url`, unless the copy at `path` is still current.
+/// A partial download left by an earlier run is resumed if the server's copy hasn't
+/// changed since (by ETag).
+pub async fn fetch(client: &reqwest::Client, url: &str, path: &Path) -> eyre::Result<Fetched> {
+    let current = Dataset::on_disk(path);
+    let part = sidecar(path, "part");
+    let part_etag = read_sidecar(&part, "etag");
+    let part_len = tokio::fs::metadata(&part).await.map_or(0, |m| m.len());
+
+    let mut request = client.get(url);
+    if path.exists()
+        && let Some(etag) = &current.etag
+    {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    // only resumable if we know which version the partial download is of
+    if part_len > 0
+        && let Some(etag) = &part_etag
+    {
+        request = request
+            .header(header::RANGE, format!("bytes={}-", part_len))
+            .header(header::IF_RANGE, etag);
+    }
+
+    let response = request
+        .send()
+        .await
+        .wrap_err_with(|| format!("could not download {}", url))?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        info!("IMDB dataset at {} hasn't changed", url);
+        return Ok(Fetched::Current(current));
+    }
+    if !response.status().is_success() {
+        eyre::bail!("could not download {}: {}", url, response.status());
+    }
+
+    let etag = response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let (offset, total) = if response.status() == StatusCode::PARTIAL_CONTENT {
+        let range = response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_content_range);
+        match range {
+            Some((start, total)) if start == part_len && etag == part_etag => (start, Some(total)),
+            _ => {
+                // not the rest of what we have: start over next time
+                tokio::fs::remove_file(&part).await?;
+                eyre::bail!("{} resumed a different download than the one on disk", url);
+            }
+        }
+    } else {
+        (0, response.content_length())
+    };
+
+    Ok(Fetched::Download(Download {
+        url: url.to_string(),
+        path: path.to_path_buf(),
+        response,
+        etag,
+        offset,
+        total,
+    }))
+}
+
+impl Download {
+    /// Size of the whole dataset, if the server said.
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+
+    /// Write the download to `<path>.part`, moving it into place once it's complete.
+    /// Every byte of the dataset (including any part downloaded before) also goes to
+    /// `sink`, if there is one.
+    pub async fn save(mut self, sink: Option<ChunkSender>) -> eyre::Result<Dataset> {
+        let part = sidecar(&self.path, "part");
+        if self.offset > 0 {
+            info!("Resuming download of {} at {} bytes", self.url, self.offset);
+        } else {
+            info!("Downloading {} ...", self.url);
+        }
+        write_sidecar(&part, "etag", self.etag.as_deref())?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(self.offset == 0)
+            .open(&part)
+            .await
+            .wrap_err_with(|| format!("could not write {}", part.display()))?;
+
+        let started_at = Instant::now();
+        let mut written = self.offset;
+        let streamed = async {
+            // the importer needs the dataset from the start
+            if let Some(sink) = &sink {
+                let mut replayed = 0;
+                while replayed < self.offset {
+                    let mut chunk = vec![0; REPLAY_CHUNK_BYTES];
+                    let n = file.read(&mut chunk).await?;
+                    if n == 0 {
+                        eyre::bail!("{} is shorter than it was", part.display());
+                    }
+                    chunk.truncate(n.min((self.offset - replayed) as usize));
+                    replayed += chunk.len() as u64;
+                    // the importer stopping isn't a reason to stop downloading
+                    let _ = sink.send(Ok(chunk)).await;
+                }
+            }
+            file.seek(io::SeekFrom::Start(self.offset)).await?;
+
+            while let Some(chunk) = self.response.chunk().await? {
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+                if let Some(sink) = &sink {
+                    let _ = sink.send(Ok(chunk.to_vec())).await;
+                }
+            }
+            if let Some(total) = self.total
+                && written != total
+            {
+                eyre::bail!("stopped at {} of {} bytes", written, total);
+            }
+            eyre::Ok(())
+        }
+        .await;
+        // keep whatever made it, for resuming
+        file.flush().await?;
+        if let Err(e) = streamed {
+            if let Some(sink) = &sink {
+                let _ = sink.send(Err(io::Error::other(e.to_string()))).await;
+            }
+            return Err(e).wrap_err_with(|| format!("download of {} failed", self.url));
+        }
+
+        tokio::fs::rename(&part, &self.path).await?;
+        write_sidecar(&self.path, "etag", self.etag.as_deref())?;
+        write_sidecar(&part, "etag", None)?;
+        info!(
+            "Downloaded {} ({} bytes in {:.1}s)",
+            self.url,
+            written - self.offset,
+            started_at.elapsed().as_secs_f64()
+        );
+
+        Ok(Dataset {
+            path: self.path,
+            etag: self.etag,
+        })
+    }
+}
+
+/// The bytes of a download as they arrive, for reading on a blocking thread.
+pub struct ChunkReader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    pub fn new(chunks: mpsc::Receiver<io::Result<Vec<u8>>>) -> ChunkReader {
+        ChunkReader {
+            chunks,
+            chunk: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Save a (gzipped) download while importing it, decompressing as the bytes arrive.
+pub async fn save_and_import(
+    db: &PersistentDb,
+    download: Download,
+    progress: Arc<watch::Sender<ImportProgress>>,
+) -> eyre::Result<Dataset> {
+    let (sink, chunks) = mpsc::channel(STREAM_AHEAD_CHUNKS);
+    let total = download.total();
+    let (saved, imported) = tokio::join!(
+        download.save(Some(sink)),
+        imdb_reader::import_from(db, ChunkReader::new(chunks), true, total, progress)
+    );
+    let dataset = saved?;
+    imported?;
+    Ok(dataset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trakt::t_db::Database;
+
+    use std::sync::Mutex;
+
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const FIXTURE: &[u8] = include_bytes!("../../fixtures/imdb/title.basics.sample.tsv.gz");
+
+    /// Stand-in for IMDB's dataset server: serves one file, honouring
+    /// If-None-Match, Range and If-Range.
+    #[derive(Clone, Default)]
+    struct MockDataset {
+        state: Arc<Mutex<MockState>>,
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        body: Vec<u8>,
+        etag: String,
+        /// drop the connection after sending this many body bytes (once)
+        cut_after: Option<usize>,
+        /// the headers we care about, per request
+        requests: Vec<Vec<String>>,
+    }
+
+    impl MockDataset {
+        fn publish(&self, body: &[u8], etag: &str) {
+            let mut state = self.state.lock().unwrap();
+            state.body = body.to_vec();
+            state.etag = etag.to_string();
+        }
+
+        async fn start(&self) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!(
+                "http://{}/title.basics.tsv.gz",
+                listener.local_addr().unwrap()
+            );
+            let mock = self.clone();
+            tokio::spawn(async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    let mock = mock.clone();
+                    tokio::spawn(async move { mock.handle(socket).await });
+                }
+            });
+            url
+        }
+
+        async fn handle(&self, socket: TcpStream) -> eyre::Result<()> {
+            let mut reader = BufReader::new(socket);
+            let mut headers = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await?;
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                headers.push(line.to_ascii_lowercase());
+            }
+            let header = |name: &str| {
+                headers
+                    .iter()
+                    .find_map(|h| h.strip_prefix(&format!("{}: ", name)))
+                    .map(str::to_string)
+            };
+
+            let (head, body) = {
+                let mut state = self.state.lock().unwrap();
+                state.requests.push(headers.clone());
+                let etag = format!("\"{}\"", state.etag);
+                let range_start = header("range")
+                    .and_then(|r| r.strip_prefix("bytes=")?.strip_suffix('-')?.parse().ok())
+                    .filter(|_| header("if-range").as_deref() == Some(etag.as_str()));
+
+                if header("if-none-match").as_deref() == Some(etag.as_str()) {
+                    (format!("304 Not Modified\r\nETag: {}", etag), vec![])
+                } else if let Some(start) = range_start {
+                    let len = state.body.len();
+                    (
+                        format!(
+                            "206 Partial Content\r\nETag: {}\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}",
+                            etag, start, len - 1, len, len - start
+                        ),
+                        state.body[start..].to_vec(),
+                    )
+                } else {
+                    let mut body = state.body.clone();
+                    let head =
+                        format!("200 OK\r\nETag: {}\r\nContent-Length: {}", etag, body.len());
+                    if let Some(cut) = state.cut_after.take() {
+                        body.truncate(cut);
+                    }
+                    (head, body)
+                }
+            };
+
+            let mut socket = reader.into_inner();
+            let head = format!("HTTP/1.1 {}\r\nConnection: close\r\n\r\n", head);
+            socket.write_all(head.as_bytes()).await?;
+            socket.write_all(&body).await?;
+            socket.shutdown().await?;
+            Ok(())
+        }
+
+        fn last_request(&self) -> Vec<String> {
+            self.state.lock().unwrap().requests.last().unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(parse_content_range("bytes 100-199/200"), Some((100, 200)));
+        assert_eq!(parse_content_range("bytes 0-99/*"), None);
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("trakt-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(DATASET_FILE_NAME)
+    }
+
+    fn started(fetched: Fetched) -> Download {
+        match fetched {
+            Fetched::Download(download) => download,
+            Fetched::Current(dataset) => panic!("no download, {:?} is current", dataset),
+        }
+    }
+
+    #[tokio::test]
+    async fn downloads_resumes_and_skips_unchanged() {
+        let path = temp_path("imdb-download");
+        let mock = MockDataset::default();
+        mock.publish(FIXTURE, "v1");
+        mock.state.lock().unwrap().cut_after = Some(FIXTURE.len() / 2);
+        let url = mock.start().await;
+        let client = reqwest::Client::new();
+
+        // the connection drops halfway through: nothing is moved into place
+        let download = started(fetch(&client, &url, &path).await.unwrap());
+        assert!(download.save(None).await.is_err());
+        assert!(!path.exists());
+
+        // ... and the next try picks up where that one stopped
+        let download = started(fetch(&client, &url, &path).await.unwrap());
+        let resumed = format!("range: bytes={}-", FIXTURE.len() / 2);
+        assert!(mock.last_request().contains(&resumed));
+        let dataset = download.save(None).await.unwrap();
+        assert_eq!(dataset.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(std::fs::read(&path).unwrap(), FIXTURE);
+        assert!(!dataset.is_imported());
+        dataset.mark_imported().unwrap();
+
+        // unchanged on the server: nothing to download or import
+        let Fetched::Current(dataset) = fetch(&client, &url, &path).await.unwrap() else {
+            panic!("downloaded an unchanged dataset");
+        };
+        assert!(mock
+            .last_request()
+            .contains(&"if-none-match: \"v1\"".to_string()));
+        assert!(dataset.is_imported());
+
+        // a new version is downloaded whole
+        let update = &FIXTURE[..FIXTURE.len() - 1];
+        mock.publish(update, "v2");
+        let dataset = started(fetch(&client, &url, &path).await.unwrap())
+            .save(None)
+            .await
+            .unwrap();
+        assert!(!dataset.is_imported());
+        assert_eq!(std::fs::read(&path).unwrap(), update);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn imports_while_downloading() {
+        let path = temp_path("imdb-stream");
+        let mock = MockDataset::default();
+        mock.publish(FIXTURE, "v1");
+        mock.state.lock().unwrap().cut_after = Some(FIXTURE.len() / 2);
+        let url = mock.start().await;
+        let client = reqwest::Client::new();
+        let cache = PersistentDb::in_memory().unwrap();
+        let progress = Arc::new(watch::channel(ImportProgress::default()).0);
+
+        // a dropped connection fails the import too, rather than ending it early
+        let download = started(fetch(&client, &url, &path).await.unwrap());
+        assert!(save_and_import(&cache, download, progress.clone())
+            .await
+            .is_err());
+
+        // a resumed download is imported from the start of the dataset
+        let download = started(fetch(&client, &url, &path).await.unwrap());
+        let dataset = save_and_import(&cache, download, progress.clone())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&dataset.path).unwrap(), FIXTURE);
+        assert_eq!(cache.count_shows().await, 27);
+        let progress = progress.borrow().clone();
+        assert_eq!(progress.bytes_read, FIXTURE.len() as u64);
+        assert_eq!(progress.bytes_total, Some(FIXTURE.len() as u64));
+        assert_eq!((progress.rows_accepted, progress.rows_written), (27, 27));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
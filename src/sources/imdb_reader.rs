@@ -1,14 +1,23 @@
+use std::fs::File;
+use std::io::{self, Read};
 use std::iter::Iterator;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use eyre::Context;
+use flate2::read::MultiGzDecoder;
 use log::*;
 use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
 
 use crate::models::TraktShow;
+use crate::trakt::t_db::{Database, PersistentDb};
 
-/// currently unimpl'd: will be used to download IMDB dataset on init
-pub fn _download_source() {
-    unimplemented!();
-}
+// shows written to the db per transaction. a full dump has a few hundred thousand
+const IMPORT_BATCH_ROWS: usize = 10_000;
+// batches read ahead of the db writer
+const READ_AHEAD_BATCHES: usize = 2;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -23,47 +32,197 @@ struct ImdbShow {
     pub end_year: Option<i64>,
 }
 
-/// Read shows from IMDB data dump
-fn load_imdb_shows(dump_file_name: &str) -> impl Iterator<Item = ImdbShow> {
-    let reader = csv::ReaderBuilder::new()
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ImportState {
+    #[default]
+    Running,
+    /// the whole dump is in the db (or the db already had it)
+    Finished,
+    Failed(String),
+}
+
+/// How far the IMDB import has got.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportProgress {
+    pub state: ImportState,
+    /// size of the dump file (`None`: the server didn't say)
+    pub bytes_total: Option<u64>,
+    pub bytes_read: u64,
+    /// shows found in the dump so far
+    pub rows_accepted: usize,
+    /// shows committed to the db so far
+    pub rows_written: usize,
+    pub started_at: Option<Instant>,
+}
+
+impl ImportProgress {
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, ImportState::Finished | ImportState::Failed(_))
+    }
+
+    /// How much of the dump has been read, if we know its size.
+    pub fn percent(&self) -> Option<u16> {
+        let total = self.bytes_total?;
+        if total == 0 {
+            return Some(100);
+        }
+        Some((self.bytes_read.min(total) * 100 / total) as u16)
+    }
+
+    /// Time left at the rate we've read so far.
+    pub fn eta(&self) -> Option<Duration> {
+        let elapsed = self.started_at?.elapsed();
+        let total = self.bytes_total?;
+        if self.bytes_read == 0 {
+            return None;
+        }
+        let left = total.saturating_sub(self.bytes_read);
+        Some(elapsed.mul_f64(left as f64 / self.bytes_read as f64))
+    }
+}
+
+/// Counts the bytes the csv reader pulls from the dump.
+struct ProgressReader<R> {
+    inner: R,
+    progress: Arc<watch::Sender<ImportProgress>>,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.send_modify(|p| p.bytes_read += n as u64);
+        Ok(n)
+    }
+}
+
+/// Read shows from an IMDB data dump, skipping rows that do not parse.
+/// Errors reading the dump itself (e.g. a corrupt download) are passed on.
+fn load_imdb_shows(reader: impl Read) -> impl Iterator<Item = csv::Result<ImdbShow>> {
+    csv::ReaderBuilder::new()
         .delimiter(b'\t')
-        .from_path(dump_file_name)
-        .unwrap();
-    reader
+        .quoting(false)
+        .from_reader(reader)
         .into_deserialize()
-        .map(|r| r.unwrap())
-        .filter(|show: &ImdbShow| ["tvSeries", "tvMiniSeries"].contains(&show.title_type.as_str()))
+        .filter_map(|r: csv::Result<ImdbShow>| match r {
+            Err(e) if !e.is_io_error() => {
+                warn!("Skipping unreadable IMDB row: {}", e);
+                None
+            }
+            r => Some(r),
+        })
+        .filter(|r| {
+            r.as_ref().map_or(true, |show| {
+                ["tvSeries", "tvMiniSeries"].contains(&show.title_type.as_str())
+            })
+        })
 }
 
-fn load_show_vec_from_source(dump_file_name: &str) -> Vec<TraktShow> {
-    info!("Loading from datadump ...");
-
-    // arbitrary limit for testing
-    let shows = load_imdb_shows(dump_file_name).take(99);
-
-    info!("Serializing structs...");
-    shows
-        .map(|show| TraktShow {
-            imdb_id: show.tconst,
-            trakt_id: None,
-            primary_title: show.primary_title.unwrap(),
-            original_title: show.original_title.unwrap(),
-            release_year: show.start_year.map(|y| y as i32),
-            no_seasons: None,
-            no_episodes: None,
-            country: None,
-            network: None,
-            overview: None,
-            user_status: crate::models::UserStatusShow::Todo,
+fn load_shows_from_source(reader: impl Read) -> impl Iterator<Item = csv::Result<TraktShow>> {
+    load_imdb_shows(reader).map(|r| {
+        r.map(|show| {
+            let primary_title = show.primary_title.unwrap_or_default();
+            TraktShow {
+                imdb_id: show.tconst,
+                trakt_id: None,
+                original_title: show.original_title.unwrap_or_else(|| primary_title.clone()),
+                primary_title,
+                release_year: show.start_year.map(|y| y as i32),
+                no_seasons: None,
+                no_episodes: None,
+                country: None,
+                network: None,
+                overview: None,
+                user_status: crate::models::UserStatusShow::Todo,
+                status_changed_at: None,
+            }
         })
-        .collect()
+    })
 }
 
-pub fn load_show_vec() -> Vec<TraktShow> {
-    load_show_vec_from_source(DUMP_FILE_NAME)
+/// Stream the dump at `path` (gzipped if it ends in `.gz`) into the db.
+pub async fn import_shows(
+    db: &PersistentDb,
+    path: &Path,
+    progress: Arc<watch::Sender<ImportProgress>>,
+) -> eyre::Result<usize> {
+    info!("Loading from datadump {} ...", path.display());
+    let file = File::open(path)
+        .wrap_err_with(|| format!("could not open IMDB dump {}", path.display()))?;
+    let gzipped = path.extension().is_some_and(|e| e == "gz");
+    let bytes_total = file.metadata()?.len();
+    import_from(db, file, gzipped, Some(bytes_total), progress).await
 }
 
-const DUMP_FILE_NAME: &str = "./title.basics.short.tsv";
+/// Stream a dump of `bytes_total` bytes (if known) from `source` into the db a batch at a time,
+/// publishing how far we've got. The dump is read (and decompressed, if `gzipped`) on a
+/// blocking thread while the previous batch is written.
+pub async fn import_from(
+    db: &PersistentDb,
+    source: impl Read + Send + 'static,
+    gzipped: bool,
+    bytes_total: Option<u64>,
+    progress: Arc<watch::Sender<ImportProgress>>,
+) -> eyre::Result<usize> {
+    let started_at = Instant::now();
+    progress.send_replace(ImportProgress {
+        bytes_total,
+        started_at: Some(started_at),
+        ..ImportProgress::default()
+    });
+
+    let (batches, mut received) = mpsc::channel(READ_AHEAD_BATCHES);
+    let reader = {
+        let progress = progress.clone();
+        tokio::task::spawn_blocking(move || -> eyre::Result<()> {
+            // progress is counted in bytes of the dump, before decompressing
+            let counted = ProgressReader {
+                inner: source,
+                progress: progress.clone(),
+            };
+            let reader: Box<dyn Read> = if gzipped {
+                Box::new(MultiGzDecoder::new(counted))
+            } else {
+                Box::new(counted)
+            };
+            let mut rows = load_shows_from_source(reader).inspect(|r| {
+                if r.is_ok() {
+                    progress.send_modify(|p| p.rows_accepted += 1)
+                }
+            });
+            loop {
+                let batch = rows
+                    .by_ref()
+                    .take(IMPORT_BATCH_ROWS)
+                    .collect::<csv::Result<Vec<TraktShow>>>()
+                    .wrap_err("could not read IMDB dump")?;
+                // stop early if the writer gave up
+                if batch.is_empty() || batches.blocking_send(batch).is_err() {
+                    return Ok(());
+                }
+            }
+        })
+    };
+
+    let mut written = 0;
+    while let Some(batch) = received.recv().await {
+        written += db.prefill_from_imdb(batch).await?;
+        progress.send_modify(|p| p.rows_written = written);
+        debug!(
+            "Imported {} shows ({:.0} rows/s)",
+            written,
+            written as f64 / started_at.elapsed().as_secs_f64().max(f64::EPSILON)
+        );
+    }
+    reader.await.wrap_err("IMDB reader panicked")??;
+
+    info!(
+        "Imported {} shows in {:.1}s ({:.0} rows/s).",
+        written,
+        started_at.elapsed().as_secs_f64(),
+        written as f64 / started_at.elapsed().as_secs_f64().max(f64::EPSILON)
+    );
+    Ok(written)
+}
 
 #[cfg(test)]
 mod tests {
@@ -71,6 +230,45 @@ mod tests {
 
     #[test]
     fn can_load_sample_file() {
-        let _shows = load_show_vec_from_source("title.basics.randomsample");
+        let file = File::open("title.basics.randomsample").unwrap();
+        let shows: Vec<_> = load_shows_from_source(file)
+            .collect::<csv::Result<_>>()
+            .unwrap();
+        assert_eq!(shows.len(), 27);
+        assert!(shows.iter().all(|show| show.imdb_id.starts_with("tt")));
+    }
+
+    #[tokio::test]
+    async fn import_reports_progress() {
+        let cache = PersistentDb::in_memory().unwrap();
+        let progress = Arc::new(watch::channel(ImportProgress::default()).0);
+
+        let written = import_shows(
+            &cache,
+            Path::new("title.basics.randomsample"),
+            progress.clone(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(written, 27);
+        assert_eq!(cache.count_shows().await, 27);
+        let progress = progress.borrow().clone();
+        assert_eq!(Some(progress.bytes_read), progress.bytes_total);
+        assert_eq!(progress.percent(), Some(100));
+        assert_eq!(progress.eta(), Some(Duration::ZERO));
+        assert_eq!((progress.rows_accepted, progress.rows_written), (27, 27));
+    }
+
+    #[test]
+    fn unknown_size_has_no_percent_or_eta() {
+        let progress = ImportProgress {
+            bytes_total: None,
+            bytes_read: 1_000,
+            started_at: Some(Instant::now()),
+            ..ImportProgress::default()
+        };
+        assert_eq!(progress.percent(), None);
+        assert_eq!(progress.eta(), None);
     }
 }
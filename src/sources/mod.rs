@@ -1,56 +1,98 @@
+use std::sync::Arc;
+
 use log::*;
+use tokio::sync::watch;
 
-use crate::models::TraktShow;
 use crate::trakt::t_db::{self, Database};
 
+pub mod imdb_download;
 pub mod imdb_reader;
 
-// i have an idea once the db reading is more fleshed out:
-// 1. chunk DB reads so they happen faster
-// 2. let our manager tell the app when it has updates (e.g. send an int)
-// 3. whenever app.ticks and/or queries, it will see there are new updates
-// 4. receive updates?
-// not sure about this workflow, maybe i could just create a bounded(0) channel
-// over which to send the rows.
-// either way, maybe an enum of possible send values (u32 / vec) could be helpful
-// pub enum DataResult {}
-
-/// Load all shows from imdb data dump and db.
-/// TODO: for now, assume that first startup fills all rows into db.
-/// Eventually, we may have to update this to update db on startup from a new data dump.
-async fn load_combined_data_sources(db: &mut t_db::PersistentDb) -> eyre::Result<Vec<TraktShow>> {
-    // load all shows, and fill db if db is empty
-    // let items = imdb_reader::load_show_vec();
-
-    let row_count = db.count_shows().await;
-    info!("row count: {}", row_count);
-
-    if row_count < 100 {
-        // if we dont have many rows in db (clean env or devel), load from imdb data
-        let items = imdb_reader::load_show_vec();
-        // TODO: put this on a thread, once i figure out borrowing?
-        db.prefill_from_imdb(items.clone()).await.map(|()| items)
-    } else {
-        // query everything from db
-        Ok(db.filtered_shows().await)
-    }
-}
+use imdb_download::{Dataset, Fetched};
+use imdb_reader::{ImportProgress, ImportState};
+
+// fewer shows than this in the db, and it's treated as empty (clean env or devel)
+const MIN_CACHED_SHOWS: usize = 100;
 
+/// Keeps the db's shows in step with the IMDB dataset, in the background.
 #[derive(Debug)]
 pub struct DataManager {
-    items: Vec<TraktShow>,
+    progress: Arc<watch::Sender<ImportProgress>>,
+    /// shows the db had before any import
+    cached_shows: usize,
 }
 
 impl DataManager {
-    pub async fn init() -> eyre::Result<DataManager> {
-        let mut db = t_db::PersistentDb::connect().await?;
-        let items = load_combined_data_sources(&mut db).await?;
+    /// Start fetching the IMDB dataset, and importing it if it's new to the db.
+    /// Shows can be read from the db as soon as the first batch has been written.
+    pub async fn init(db: t_db::PersistentDb) -> DataManager {
+        let progress = Arc::new(watch::channel(ImportProgress::default()).0);
 
-        Ok(DataManager { items })
+        let cached_shows = db.count_shows().await;
+        info!("row count: {}", cached_shows);
+
+        tokio::spawn({
+            let progress = progress.clone();
+            async move {
+                let state = match refresh(&db, cached_shows, progress.clone()).await {
+                    Ok(()) => ImportState::Finished,
+                    Err(e) => {
+                        error!("could not import IMDB dataset: {:?}", e);
+                        ImportState::Failed(e.to_string())
+                    }
+                };
+                progress.send_modify(|p| p.state = state);
+            }
+        });
+
+        DataManager {
+            progress,
+            cached_shows,
+        }
     }
 
-    /// Returns `None` if the servicing thread has died.
-    pub async fn query(&self, _q: String) -> Option<Vec<TraktShow>> {
-        Some(self.items.clone())
+    pub fn progress(&self) -> watch::Receiver<ImportProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Whether the db had shows to list before this run's import.
+    pub fn has_cached_shows(&self) -> bool {
+        self.cached_shows >= MIN_CACHED_SHOWS
+    }
+}
+
+/// Download the dataset if it changed, importing it as it arrives. Otherwise, import the
+/// copy on disk unless the db already has it.
+async fn refresh(
+    db: &t_db::PersistentDb,
+    cached_shows: usize,
+    progress: Arc<watch::Sender<ImportProgress>>,
+) -> eyre::Result<()> {
+    let path = t_db::data_dir()?.join(imdb_download::DATASET_FILE_NAME);
+    let url = imdb_download::dataset_url();
+
+    let dataset = match imdb_download::fetch(&reqwest::Client::new(), &url, &path).await {
+        Ok(Fetched::Download(download)) => {
+            let dataset = imdb_download::save_and_import(db, download, progress).await?;
+            return dataset.mark_imported();
+        }
+        Ok(Fetched::Current(dataset)) => dataset,
+        // e.g. offline: make do with the copy we have
+        Err(e) if path.exists() => {
+            warn!(
+                "could not refresh the IMDB dataset, using the one on disk: {:?}",
+                e
+            );
+            Dataset::on_disk(&path)
+        }
+        Err(e) => return Err(e),
+    };
+
+    // if we dont have many rows in db (clean env or devel), load from imdb data
+    if cached_shows >= MIN_CACHED_SHOWS && dataset.is_imported() {
+        info!("IMDB dataset unchanged since it was imported");
+        return Ok(());
     }
+    imdb_reader::import_shows(db, &dataset.path, progress).await?;
+    dataset.mark_imported()
 }
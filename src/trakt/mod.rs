@@ -1,5 +1,32 @@
 /// query the trakt api
 pub mod t_api;
 
+/// log in to trakt (oauth device-code flow)
+pub mod t_auth;
+
+/// record/replay trakt traffic for tests
+pub mod t_cassette;
+
+/// fill in trakt details for shows seeded from IMDB, in the background
+pub mod t_enrich;
+
+/// pull the user's watch history, watchlist, hidden shows and ratings from trakt
+pub mod t_import;
+
+/// rate limiting for requests to trakt
+pub mod t_limit;
+
+/// stand-in trakt server for development and tests
+pub mod t_mock;
+
+/// queue of local changes waiting to be sent to trakt
+pub mod t_outbox;
+
+/// compare local statuses with trakt's, and settle the conflicts
+pub mod t_reconcile;
+
 /// store data from trakt in a local db
 pub mod t_db;
+
+/// push local watch statuses to trakt
+pub mod t_sync;
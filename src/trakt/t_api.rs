@@ -1,64 +1,219 @@
+use crate::config::TraktConfig;
+use crate::models::{ActivityCategory, SyncItemKind};
+use crate::trakt::t_auth::Authenticator;
+use crate::trakt::t_cassette::{Cassette, CassetteMode, RecordedRequest, RecordedResponse};
+use crate::trakt::t_db::PersistentDb;
+use crate::trakt::t_limit::{self, TraktLimiter};
+
 use chrono::{DateTime, Utc};
-use std::{
-    env, thread,
-    time::{self, Duration},
-};
-
-use diesel::prelude::*;
-use dotenvy::dotenv;
-use governor::{Quota, RateLimiter};
-use nonzero_ext::*;
-use reqwest::{header, Client};
-use serde::{Deserialize, Serialize};
-
-const APP_USER_AGENT: &str = "Trakt TV Selector";
-// 1/sec -> 300 per 5min
-const RATE_LIMIT: u32 = 3u32;
-const TIME_STEP: time::Duration = time::Duration::from_millis(100);
-
-// for testing, i've copied over several JSON responses and host them locally.
-const TRAKT_URL: &str = "http://127.0.0.1:8080";
-// const TRAKT_URL: &str = "https://api.trakt.tv/api";
-// const TRAKT_URL: &str = "https://api-staging.trakt.tv/api";
+use std::{sync::Arc, time::Duration};
 
-#[derive(Serialize, Deserialize, Debug)]
+use log::*;
+use reqwest::{header, Client, Method, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+// retries after a 429/5xx/timeout before giving up on a request
+const MAX_RETRIES: u32 = 3;
+// search results per page
+const SEARCH_LIMIT: u32 = 20;
+// items per page when we want all of a list
+const LIST_LIMIT: u32 = 100;
+
+// how much of an undecodable response body to keep around for error messages
+const SNIPPET_LEN: usize = 200;
+
+/// Everything that can go wrong talking to trakt, so callers can react to each case.
+#[derive(Debug)]
+pub enum TraktApiError {
+    /// 404: trakt doesn't know this item (e.g. an IMDB id it hasn't imported)
+    NotFound,
+    /// 401/403: we aren't logged in, the token was revoked, or the client id is wrong
+    Unauthorized,
+    /// 429: too many requests. trakt says how long to back off for in `Retry-After`
+    RateLimited { retry_after: Option<Duration> },
+    /// 5xx: trakt (or cloudflare in front of it) is having problems
+    Server(StatusCode),
+    /// any other unexpected status
+    Status(StatusCode),
+    /// the request took longer than the client's timeout
+    Timeout,
+    /// couldn't reach trakt at all
+    Network(reqwest::Error),
+    /// the response wasn't the JSON we expected
+    Decode {
+        error: serde_json::Error,
+        snippet: String,
+    },
+    /// replaying a cassette, and it has no response for this request
+    Unrecorded(String),
+}
+
+impl TraktApiError {
+    fn from_status(status: StatusCode, headers: &header::HeaderMap) -> TraktApiError {
+        match status {
+            StatusCode::NOT_FOUND => TraktApiError::NotFound,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => TraktApiError::Unauthorized,
+            StatusCode::TOO_MANY_REQUESTS => TraktApiError::RateLimited {
+                retry_after: headers
+                    .get(header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse::<u64>().ok())
+                    .map(Duration::from_secs),
+            },
+            s if s.is_server_error() => TraktApiError::Server(s),
+            s => TraktApiError::Status(s),
+        }
+    }
+
+    /// Whether the same request might succeed if we try again later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            TraktApiError::RateLimited { .. }
+                | TraktApiError::Server(_)
+                | TraktApiError::Timeout
+                | TraktApiError::Network(_)
+        )
+    }
+
+    /// Whether a write can safely be sent again. Only when trakt turned it away (429) or
+    /// we never got through to it: after a timeout or server error it may have been applied.
+    pub fn is_retryable_write(&self) -> bool {
+        match self {
+            TraktApiError::RateLimited { .. } => true,
+            TraktApiError::Network(e) => e.is_connect(),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for TraktApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraktApiError::NotFound => write!(f, "not found on trakt"),
+            TraktApiError::Unauthorized => write!(f, "not authorized (log in to trakt again)"),
+            TraktApiError::RateLimited {
+                retry_after: Some(after),
+            } => write!(f, "rate limited by trakt, retry in {}s", after.as_secs()),
+            TraktApiError::RateLimited { retry_after: None } => write!(f, "rate limited by trakt"),
+            TraktApiError::Server(status) => write!(f, "trakt server error: {}", status),
+            TraktApiError::Status(status) => {
+                write!(f, "unexpected response from trakt: {}", status)
+            }
+            TraktApiError::Timeout => write!(f, "request to trakt timed out"),
+            TraktApiError::Network(e) => write!(f, "could not reach trakt: {}", e),
+            TraktApiError::Decode { error, snippet } => {
+                write!(
+                    f,
+                    "could not decode trakt response ({}): {}",
+                    error, snippet
+                )
+            }
+            TraktApiError::Unrecorded(request) => {
+                write!(f, "no recorded response for {}", request)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TraktApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TraktApiError::Network(e) => Some(e),
+            TraktApiError::Decode { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for TraktApiError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            TraktApiError::Timeout
+        } else {
+            TraktApiError::Network(e)
+        }
+    }
+}
+
+/// Parse a JSON response body, keeping the start of the body around if it doesn't match.
+pub fn decode<T: DeserializeOwned>(text: &str) -> Result<T, TraktApiError> {
+    serde_json::from_str::<T>(text).map_err(|error| TraktApiError::Decode {
+        error,
+        snippet: text.chars().take(SNIPPET_LEN).collect(),
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ApiIDs {
     pub trakt: u32,
-    slug: Option<String>,
-    imdb: Option<String>,
+    pub slug: Option<String>,
+    pub imdb: Option<String>,
     // skipping other unimportant IDs
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ApiShow {
-    title: String,
-    year: Option<u32>,
-    ids: ApiIDs,
+    pub title: String,
+    pub year: Option<u32>,
+    pub ids: ApiIDs,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// search/show?query=<text>
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ApiMatch {
     #[serde(rename = "type")]
     pub _type: String,
-    pub score: u32,
+    pub score: f32,
     pub show: ApiShow,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// X-Pagination-* headers sent with paginated responses.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pagination {
+    pub page: u32,
+    pub limit: u32,
+    pub page_count: u32,
+    pub item_count: u32,
+}
+
+impl Pagination {
+    fn from_headers(headers: &header::HeaderMap) -> Pagination {
+        let get = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or_default()
+        };
+
+        Pagination {
+            page: get("x-pagination-page"),
+            limit: get("x-pagination-limit"),
+            page_count: get("x-pagination-page-count"),
+            item_count: get("x-pagination-item-count"),
+        }
+    }
+}
+
+/// One page of search results, best match first.
+#[derive(Clone, Debug, Default)]
 pub struct ApiResponse {
     pub results: Vec<ApiMatch>,
+    pub pagination: Pagination,
 }
 
 // shows/<id>?extended=full
+// (unreleased or obscure shows can be missing most of these)
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ApiShowDetails {
     pub title: String,
-    pub year: u32,
+    pub year: Option<u32>,
     pub ids: ApiIDs,
-    pub overview: String,
-    pub first_aired: DateTime<Utc>,
-    pub network: String,
-    pub country: String,
+    pub overview: Option<String>,
+    pub first_aired: Option<DateTime<Utc>>,
+    pub network: Option<String>,
+    pub country: Option<String>,
     // pub language: String,
     pub aired_episodes: u32,
 }
@@ -70,124 +225,649 @@ pub struct ApiSeasonDetails {
     pub ids: ApiIDs,
     pub episode_count: usize,
     pub title: String,
-    pub first_aired: DateTime<Utc>,
+    pub first_aired: Option<DateTime<Utc>>,
     pub overview: Option<String>,
-    pub network: String,
+    pub network: Option<String>,
+    // only sent with extended=episodes
+    #[serde(default)]
+    pub episodes: Vec<ApiEpisodeDetails>,
 }
 
-/// Creates a single HTTP client to use for trakt.tv requests
-pub fn establish_http_client() -> Client {
-    // TODO: eyre/error handle this (as part of app startup?)
-    dotenv().ok();
+// shows/<id>/seasons/<n>?extended=full
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiEpisodeDetails {
+    pub season: usize,
+    pub number: usize,
+    // trakt leaves titles out for episodes it doesn't know much about yet
+    pub title: Option<String>,
+    pub ids: ApiIDs,
+    pub overview: Option<String>,
+    pub first_aired: Option<DateTime<Utc>>,
+}
+
+// sync/history/<type>/<id>?extended=full, one play each
+// (only episodes are asked for, and only what's needed to pick out our own plays)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiHistoryItem {
+    pub id: u64,
+    pub watched_at: DateTime<Utc>,
+    /// "watch" for plays added through sync/history, "scrobble" or "checkin" otherwise
+    pub action: String,
+    pub episode: Option<ApiEpisodeDetails>,
+}
 
+// sync/watched/shows?extended=full
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiWatchedShow {
+    pub plays: u32,
+    pub last_watched_at: Option<DateTime<Utc>>,
+    pub show: ApiShowDetails,
+    #[serde(default)]
+    pub seasons: Vec<ApiWatchedSeason>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiWatchedSeason {
+    pub number: usize,
+    pub episodes: Vec<ApiWatchedEpisode>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiWatchedEpisode {
+    pub number: usize,
+    pub plays: u32,
+    pub last_watched_at: Option<DateTime<Utc>>,
+}
+
+// sync/last_activities (trakt sends more categories than these, e.g. movies and comments)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiLastActivities {
+    pub all: DateTime<Utc>,
+    pub episodes: ApiItemActivities,
+    pub seasons: ApiItemActivities,
+    pub shows: ApiItemActivities,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ApiItemActivities {
+    pub watched_at: Option<DateTime<Utc>>,
+    pub rated_at: Option<DateTime<Utc>>,
+    pub watchlisted_at: Option<DateTime<Utc>>,
+    pub hidden_at: Option<DateTime<Utc>>,
+}
+
+impl ApiLastActivities {
+    /// When anything in one of the categories we pull last changed.
+    pub fn updated_at(&self, category: ActivityCategory) -> Option<DateTime<Utc>> {
+        let items = [&self.shows, &self.seasons, &self.episodes];
+        let latest = |f: fn(&ApiItemActivities) -> Option<DateTime<Utc>>| {
+            items.iter().filter_map(|i| f(i)).max()
+        };
+
+        match category {
+            ActivityCategory::Watched => latest(|i| i.watched_at),
+            ActivityCategory::Watchlist => latest(|i| i.watchlisted_at),
+            ActivityCategory::Ratings => latest(|i| i.rated_at),
+            ActivityCategory::Hidden => latest(|i| i.hidden_at),
+        }
+    }
+}
+
+// sync/watchlist/shows
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiListedShow {
+    pub listed_at: DateTime<Utc>,
+    pub show: ApiShow,
+}
+
+// users/hidden/{progress_watched,recommendations}?type=show
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiHiddenShow {
+    pub hidden_at: DateTime<Utc>,
+    pub show: ApiShow,
+}
+
+// sync/ratings (movies are skipped)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiRating {
+    pub rated_at: DateTime<Utc>,
+    pub rating: u8,
+    #[serde(rename = "type")]
+    pub _type: String,
+    pub show: Option<ApiRatedItem>,
+    pub season: Option<ApiRatedItem>,
+    pub episode: Option<ApiRatedItem>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiRatedItem {
+    pub ids: ApiIDs,
+}
+
+impl ApiRating {
+    /// What was rated. `None` for movies.
+    pub fn item(&self) -> Option<(SyncItemKind, u32)> {
+        match self._type.as_str() {
+            "show" => Some((SyncItemKind::Show, self.show.as_ref()?.ids.trakt)),
+            "season" => Some((SyncItemKind::Season, self.season.as_ref()?.ids.trakt)),
+            "episode" => Some((SyncItemKind::Episode, self.episode.as_ref()?.ids.trakt)),
+            _ => None,
+        }
+    }
+}
+
+/// Creates a single HTTP client to use for trakt.tv requests
+pub fn establish_http_client(config: &TraktConfig) -> eyre::Result<Client> {
     let mut headers = header::HeaderMap::new();
-    headers.insert(
-        "User-Agent",
-        header::HeaderValue::from_static(APP_USER_AGENT),
-    );
     headers.insert(
         "Content-Type",
         header::HeaderValue::from_static("application/json"),
     );
     headers.insert("trakt-api-version", header::HeaderValue::from_static("2"));
-
-    let client_id = env::var("CLIENT_ID").expect("CLIENT_ID must be set.");
     headers.insert(
         "trakt-api-key",
-        header::HeaderValue::from_str(&client_id).unwrap(),
+        header::HeaderValue::from_str(&config.client_id)
+            .map_err(|_| eyre::eyre!("client id isn't a valid header value"))?,
     );
 
-    // TODO - don't need auth for searching shows.
-    // i'll continue using the unauth'd API for now
-    // will need to figure out an oauth flow once i get to updating values
-    // let bearer = format!("Bearer {}", env::var("OAUTH_TOKEN").expect("OAUTH_TOKEN must be set."));
-    // headers.insert(
-    //     "Authorization",
-    //     header::HeaderValue::from_str(&bearer).unwrap(),
-    // );
-
-    // requests from within a CLI app probably happen too slowly to hit rate limit,
-    // so we might just let the app manage itself?
-    reqwest::Client::builder()
-        .user_agent(APP_USER_AGENT)
+    // the Authorization header is added per-request by `TraktClient`, since the
+    // access token can be refreshed while the app is running
+
+    // rate limiting is handled by `TraktClient`, which every request goes through
+    let client = reqwest::Client::builder()
+        .user_agent(config.user_agent.as_str())
         .default_headers(headers)
-        .timeout(Duration::from_secs(5))
-        .build()
-        .unwrap()
+        .timeout(config.timeout)
+        .build()?;
+    Ok(client)
+}
+
+/// HTTP client for trakt.tv that authenticates every request with the user's oauth token.
+/// All clones share one rate limiter.
+#[derive(Clone, Debug)]
+pub struct TraktClient {
+    pub http: Client,
+    pub base_url: String,
+    pub auth: Authenticator,
+    pub limiter: Arc<TraktLimiter>,
+    /// recorded traffic to replay instead of calling trakt, or to record into
+    pub cassette: Option<Arc<Cassette>>,
+}
+
+impl TraktClient {
+    pub async fn new(cache: PersistentDb, config: &TraktConfig) -> eyre::Result<TraktClient> {
+        let auth = Authenticator::new(
+            cache,
+            &config.base_url,
+            &config.client_id,
+            &config.client_secret,
+        )
+        .await?;
+
+        let cassette = match &config.cassette {
+            Some(path) => {
+                info!(
+                    "Using cassette {} ({:?})",
+                    path.display(),
+                    config.cassette_mode
+                );
+                Some(Arc::new(Cassette::open(config.cassette_mode, path)?))
+            }
+            None => None,
+        };
+
+        Ok(TraktClient {
+            http: establish_http_client(config)?,
+            base_url: config.base_url.clone(),
+            auth,
+            limiter: Arc::new(TraktLimiter::default()),
+            cassette,
+        })
+    }
+
+    /// Run the oauth device-code flow (see [`Authenticator::login`]).
+    pub async fn login(&self) -> eyre::Result<()> {
+        self.auth.login(&self.http).await
+    }
 }
 
-async fn do_req(client: &reqwest::Client, endpoint: &str) -> eyre::Result<String> {
-    let search_url = format!("{}/{}", TRAKT_URL, endpoint);
+/// Authenticate and send a request, turning any non-2xx status into a [`TraktApiError`].
+/// Requests wait on the shared rate limiter, and are retried (with backoff) if trakt
+/// rate limits us or has a temporary problem. Writes are only retried if they can't have
+/// been applied (see [`TraktApiError::is_retryable_write`]).
+async fn send(
+    client: &TraktClient,
+    request: reqwest::RequestBuilder,
+) -> Result<RecordedResponse, TraktApiError> {
+    let token = client.auth.access_token(&client.http).await.map_err(|e| {
+        warn!("no usable oauth token: {}", e);
+        TraktApiError::Unauthorized
+    })?;
+
+    let request = request.bearer_auth(token).build()?;
+    let is_write = request.method() != Method::GET;
+
+    let mut attempt = 0;
+    loop {
+        let err = match execute(client, &request, is_write).await {
+            Ok(response) => {
+                let headers = response.header_map();
+                client.limiter.observe(&headers);
+                let status = StatusCode::from_u16(response.status)
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                if status.is_success() {
+                    return Ok(response);
+                }
+                TraktApiError::from_status(status, &headers)
+            }
+            Err(e) => e,
+        };
+
+        let retryable = if is_write {
+            err.is_retryable_write()
+        } else {
+            err.is_retryable()
+        };
+        if !retryable || attempt >= MAX_RETRIES {
+            return Err(err);
+        }
+
+        let retry_after = match err {
+            TraktApiError::RateLimited { retry_after } => retry_after,
+            _ => None,
+        };
+        let delay = t_limit::retry_delay(attempt, retry_after);
+        warn!(
+            "{} {}: {}, retrying in {:.1}s",
+            request.method(),
+            request.url(),
+            err,
+            delay.as_secs_f32()
+        );
+
+        client
+            .limiter
+            .record_retry(matches!(err, TraktApiError::RateLimited { .. }));
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Send a single request (no retries), or answer it from the cassette if we're replaying one.
+async fn execute(
+    client: &TraktClient,
+    request: &reqwest::Request,
+    is_write: bool,
+) -> Result<RecordedResponse, TraktApiError> {
+    let recorded = client.cassette.as_ref().map(|cassette| {
+        let url = request.url().as_str();
+        let path = url.strip_prefix(client.base_url.as_str()).unwrap_or(url);
+        let body = request.body().and_then(|b| b.as_bytes());
+        (
+            cassette,
+            RecordedRequest::new(request.method().as_str(), path, body),
+        )
+    });
+
+    if let Some((cassette, recorded)) = &recorded
+        && cassette.mode() == CassetteMode::Replay
+    {
+        return cassette
+            .replay(recorded)
+            .ok_or_else(|| TraktApiError::Unrecorded(recorded.to_string()));
+    }
+
+    client.limiter.until_ready(is_write).await;
+    let attempt_request = request
+        .try_clone()
+        .expect("trakt requests don't have streaming bodies");
+    let response = client.http.execute(attempt_request).await?;
+
+    let status = response.status().as_u16();
+    let headers = response.headers().clone();
+    let response = RecordedResponse::new(status, &headers, &response.text().await?);
 
-    let response = client.get(search_url).send().await?;
-    match response.status() {
-        reqwest::StatusCode::OK => Ok(response.text().await?),
-        _ => unimplemented!(),
+    if let Some((cassette, recorded)) = recorded
+        && let Err(e) = cassette.record(recorded, response.clone())
+    {
+        error!("could not record response: {}", e);
     }
+
+    Ok(response)
+}
+
+async fn do_req(client: &TraktClient, endpoint: &str) -> Result<String, TraktApiError> {
+    let search_url = format!("{}/{}", client.base_url, endpoint);
+    Ok(send(client, client.http.get(search_url)).await?.body)
+}
+
+/// POST a JSON body to an (authenticated) trakt endpoint, returning the response text.
+pub async fn do_post<T: Serialize + ?Sized>(
+    client: &TraktClient,
+    endpoint: &str,
+    body: &T,
+) -> Result<String, TraktApiError> {
+    let url = format!("{}/{}", client.base_url, endpoint);
+    Ok(send(client, client.http.post(url).json(body)).await?.body)
+}
+
+/// Search trakt for shows by title. Pages start at 1.
+pub async fn search_shows(
+    client: &TraktClient,
+    query: &str,
+    page: u32,
+) -> Result<ApiResponse, TraktApiError> {
+    let url = format!("{}/search/show", client.base_url);
+    let request = client.http.get(url).query(&[
+        ("query", query.to_string()),
+        ("page", page.to_string()),
+        ("limit", SEARCH_LIMIT.to_string()),
+    ]);
+
+    let response = send(client, request).await?;
+    let mut results = decode::<Vec<ApiMatch>>(&response.body)?;
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    Ok(ApiResponse {
+        results,
+        pagination: Pagination::from_headers(&response.header_map()),
+    })
 }
 
 async fn query_show_info(
-    client: &reqwest::Client,
+    client: &TraktClient,
     imdb_id: &String,
-) -> eyre::Result<ApiShowDetails> {
+) -> Result<ApiShowDetails, TraktApiError> {
     let text = do_req(client, &format!("shows/{}?extended=full", imdb_id)).await?;
-    Ok(serde_json::from_str::<ApiShowDetails>(&text)?)
+    decode::<ApiShowDetails>(&text)
 }
 
 async fn query_season_info(
-    client: &reqwest::Client,
+    client: &TraktClient,
     imdb_id: &String,
-) -> eyre::Result<Vec<ApiSeasonDetails>> {
+) -> Result<Vec<ApiSeasonDetails>, TraktApiError> {
     let text = do_req(client, &format!("shows/{}/seasons?extended=full", imdb_id)).await?;
-    Ok(serde_json::from_str::<Vec<ApiSeasonDetails>>(&text)?)
+    decode::<Vec<ApiSeasonDetails>>(&text)
+}
+
+/// Gets every episode of one season of a show (by trakt id, slug or IMDB id)
+pub async fn query_episodes(
+    client: &TraktClient,
+    show_id: &str,
+    season_number: i32,
+) -> Result<Vec<ApiEpisodeDetails>, TraktApiError> {
+    let text = do_req(
+        client,
+        &format!("shows/{}/seasons/{}?extended=full", show_id, season_number),
+    )
+    .await?;
+    decode::<Vec<ApiEpisodeDetails>>(&text)
+}
+
+/// Gets every season of a show, each with its episodes (in one request)
+pub async fn query_seasons_with_episodes(
+    client: &TraktClient,
+    show_id: &str,
+) -> Result<Vec<ApiSeasonDetails>, TraktApiError> {
+    let text = do_req(
+        client,
+        &format!("shows/{}/seasons?extended=full,episodes", show_id),
+    )
+    .await?;
+    decode::<Vec<ApiSeasonDetails>>(&text)
+}
+
+/// Gets every show the user has watched something of, down to the episode
+pub async fn query_watched_shows(
+    client: &TraktClient,
+) -> Result<Vec<ApiWatchedShow>, TraktApiError> {
+    let text = do_req(client, "sync/watched/shows?extended=full").await?;
+    decode::<Vec<ApiWatchedShow>>(&text)
+}
+
+/// Gets every play of a show, season or episode (`item_type` is "shows", "seasons"
+/// or "episodes"), newest first
+pub async fn query_history(
+    client: &TraktClient,
+    item_type: &str,
+    trakt_id: i32,
+) -> Result<Vec<ApiHistoryItem>, TraktApiError> {
+    let endpoint = format!("sync/history/{}/{}?extended=full", item_type, trakt_id);
+    query_all_pages(client, &endpoint).await
+}
+
+/// Gets when each kind of the user's data last changed on trakt
+pub async fn query_last_activities(
+    client: &TraktClient,
+) -> Result<ApiLastActivities, TraktApiError> {
+    let text = do_req(client, "sync/last_activities").await?;
+    decode::<ApiLastActivities>(&text)
+}
+
+/// Gets every page of a paginated list
+async fn query_all_pages<T: DeserializeOwned>(
+    client: &TraktClient,
+    endpoint: &str,
+) -> Result<Vec<T>, TraktApiError> {
+    let url = format!("{}/{}", client.base_url, endpoint);
+    let mut items = vec![];
+    let mut page = 1;
+    loop {
+        let request = client.http.get(&url).query(&[
+            ("page", page.to_string()),
+            ("limit", LIST_LIMIT.to_string()),
+        ]);
+        let response = send(client, request).await?;
+        items.extend(decode::<Vec<T>>(&response.body)?);
+
+        let pagination = Pagination::from_headers(&response.header_map());
+        if page >= pagination.page_count {
+            return Ok(items);
+        }
+        page += 1;
+    }
+}
+
+/// Gets the shows on the user's watchlist
+pub async fn query_watchlist(client: &TraktClient) -> Result<Vec<ApiListedShow>, TraktApiError> {
+    query_all_pages(client, "sync/watchlist/shows").await
+}
+
+/// Gets the shows the user has hidden from their progress or recommendations
+/// (once per show, at the latest time it was hidden)
+pub async fn query_hidden(client: &TraktClient) -> Result<Vec<ApiHiddenShow>, TraktApiError> {
+    let mut hidden: Vec<ApiHiddenShow> = vec![];
+    for section in ["progress_watched", "recommendations"] {
+        let endpoint = format!("users/hidden/{}?type=show", section);
+        for item in query_all_pages::<ApiHiddenShow>(client, &endpoint).await? {
+            match hidden
+                .iter_mut()
+                .find(|h| h.show.ids.trakt == item.show.ids.trakt)
+            {
+                Some(seen) => seen.hidden_at = seen.hidden_at.max(item.hidden_at),
+                None => hidden.push(item),
+            }
+        }
+    }
+    Ok(hidden)
+}
+
+/// Gets everything the user has rated
+pub async fn query_ratings(client: &TraktClient) -> Result<Vec<ApiRating>, TraktApiError> {
+    let text = do_req(client, "sync/ratings").await?;
+    decode::<Vec<ApiRating>>(&text)
 }
 
 /// Gets detailed show results from searching trakt for an IMDB id (this should be unambiguous)
 /// Does two API calls: one for the show info, one for season info
 pub async fn query_detailed(
-    client: &reqwest::Client,
+    client: &TraktClient,
     imdb_id: &String,
-) -> eyre::Result<(ApiShowDetails, Vec<ApiSeasonDetails>)> {
+) -> Result<(ApiShowDetails, Vec<ApiSeasonDetails>), TraktApiError> {
     let show_info = query_show_info(client, imdb_id).await?;
     let season_info = query_season_info(client, imdb_id).await?;
     Ok((show_info, season_info))
 }
 
-#[allow(unused)]
-pub async fn fill_trakt_db_from_imdb(_ctx: &mut SqliteConnection, _imdb_id: u32) {
-    let lim = RateLimiter::direct(Quota::per_second(nonzero!(RATE_LIMIT)));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OAuthToken;
+    use crate::trakt::t_db::Database;
+    use crate::trakt::t_mock::{start_logged_in, MockTrakt};
 
-    // until_ready should block until the limiter is ready to submit another job, right?
-    // but it doesn't, so instead i'm doing this wacky loop{} construction
-    // lim.until_ready().await;
-    //
-    // keeping this around so i remember how to use it
-    loop {
-        if lim.check().is_ok() {
-            // for api_show in query_trakt_api(&client, tmdb_id).await {
-            //     println!("querying...");
-            //     // shows.push(api_show)
-            //     write_trakt_db(ctx, api_show);
-            // }
-
-            break;
-        }
+    use std::path::{Path, PathBuf};
+
+    const CASSETTES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/cassettes");
+
+    async fn cassette_client(base_url: &str, mode: CassetteMode, path: &Path) -> TraktClient {
+        let mut config = TraktConfig::local(base_url);
+        config.cassette = Some(path.to_path_buf());
+        config.cassette_mode = mode;
 
-        thread::sleep(TIME_STEP);
+        // replays need a stored token, since there's no trakt to log in to
+        let cache = PersistentDb::in_memory().unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        cache
+            .store_token(OAuthToken {
+                client_id: config.client_id.clone(),
+                access_token: "access".to_string(),
+                refresh_token: "refresh".to_string(),
+                created_at: now,
+                expires_at: now + chrono::Duration::days(90),
+            })
+            .await
+            .unwrap();
+
+        TraktClient::new(cache, &config).await.unwrap()
     }
 
-    // this code isn't fully impl'd right now - panic if it's called
-    // we'll finalize it once i figure out the right way to poll data from trakt
-    // and fill our db
-    unimplemented!();
-}
+    #[tokio::test]
+    async fn replays_query_detailed() {
+        let path = PathBuf::from(CASSETTES).join("query_detailed.json");
+        let client = cassette_client("https://api.trakt.tv", CassetteMode::Replay, &path).await;
+
+        let (show, seasons) = query_detailed(&client, &"tt0903747".to_string())
+            .await
+            .unwrap();
+        assert_eq!(show.title, "Breaking Bad");
+        assert_eq!(show.aired_episodes, 62);
+        assert_eq!(seasons.len(), 6);
+        assert_eq!(seasons[5].episode_count, 16);
+        // the recorded 503 was retried
+        assert_eq!(client.limiter.usage().retries, 1);
+        assert_eq!(client.limiter.usage().remaining, Some(997));
+
+        let err = query_detailed(&client, &"tt0000404".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TraktApiError::NotFound));
+
+        // every response has been used up
+        let err = query_detailed(&client, &"tt0903747".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TraktApiError::Unrecorded(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn records_then_replays() {
+        let mock = MockTrakt::new().unwrap();
+        let base_url = mock.start("127.0.0.1:0").await.unwrap();
+        let path =
+            std::env::temp_dir().join(format!("records_then_replays-{}.json", std::process::id()));
+
+        let recorder = cassette_client(&base_url, CassetteMode::Record, &path).await;
+        let recorded = query_detailed(&recorder, &"tt11280740".to_string())
+            .await
+            .unwrap();
+
+        // replaying doesn't need the server at all
+        let player = cassette_client("http://unreachable", CassetteMode::Replay, &path).await;
+        let replayed = query_detailed(&player, &"tt11280740".to_string())
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recorded.0.ids.trakt, replayed.0.ids.trakt);
+        assert_eq!(recorded.1.len(), replayed.1.len());
+    }
+
+    #[tokio::test]
+    async fn searches_shows() {
+        let (_mock, _, client) = start_logged_in().await;
+
+        let response = search_shows(&client, "breaking bad", 1).await.unwrap();
+
+        assert_eq!(response.pagination.page, 1);
+        assert_eq!(response.pagination.item_count, 1);
+        assert_eq!(response.results.len(), 1);
+        assert_eq!(
+            response.results[0].show.ids.imdb.as_deref(),
+            Some("tt0903747")
+        );
+
+        let response = search_shows(&client, "no such show", 1).await.unwrap();
+        assert!(response.results.is_empty());
+        assert_eq!(response.pagination.page_count, 1);
+    }
+
+    #[tokio::test]
+    async fn fetches_episodes() {
+        let (_mock, _, client) = start_logged_in().await;
+
+        let episodes = query_episodes(&client, "1388", 1).await.unwrap();
+        assert_eq!(episodes.len(), 7);
+        assert_eq!(episodes[0].title.as_deref(), Some("Pilot"));
+        assert!(episodes.iter().all(|e| e.season == 1));
+
+        // seasons without copied episodes get placeholders
+        let episodes = query_episodes(&client, "tt0903747", 5).await.unwrap();
+        assert_eq!(episodes.len(), 16);
+        assert_eq!(episodes[15].number, 16);
+
+        let err = query_episodes(&client, "1388", 9).await.unwrap_err();
+        assert!(matches!(err, TraktApiError::NotFound));
+    }
 
-#[cfg(tests)]
-mod tests {
     #[test]
-    fn check_rate_limit() {
-        use super::RATE_LIMIT;
-        let total = RATE_LIMIT * 60 * 5;
-        assert!(total < 1000u32);
+    fn classifies_statuses() {
+        let mut headers = header::HeaderMap::new();
+        assert!(matches!(
+            TraktApiError::from_status(StatusCode::NOT_FOUND, &headers),
+            TraktApiError::NotFound
+        ));
+        assert!(matches!(
+            TraktApiError::from_status(StatusCode::UNAUTHORIZED, &headers),
+            TraktApiError::Unauthorized
+        ));
+        assert!(matches!(
+            TraktApiError::from_status(StatusCode::BAD_GATEWAY, &headers),
+            TraktApiError::Server(StatusCode::BAD_GATEWAY)
+        ));
+        assert!(matches!(
+            TraktApiError::from_status(StatusCode::CONFLICT, &headers),
+            TraktApiError::Status(StatusCode::CONFLICT)
+        ));
+
+        headers.insert(header::RETRY_AFTER, header::HeaderValue::from_static("7"));
+        let err = TraktApiError::from_status(StatusCode::TOO_MANY_REQUESTS, &headers);
+        assert!(err.is_retryable());
+        assert!(matches!(
+            err,
+            TraktApiError::RateLimited { retry_after: Some(d) } if d == Duration::from_secs(7)
+        ));
+    }
+
+    #[test]
+    fn decode_error_keeps_snippet() {
+        let body = format!("<html>{}</html>", "x".repeat(500));
+        match decode::<ApiShowDetails>(&body) {
+            Err(TraktApiError::Decode { snippet, .. }) => {
+                assert_eq!(snippet.len(), SNIPPET_LEN);
+                assert!(snippet.starts_with("<html>"));
+            }
+            other => panic!("expected decode error, got {:?}", other),
+        }
     }
 }
@@ -0,0 +1,378 @@
+use crate::models::OAuthToken;
+use crate::trakt::t_db::{Database, PersistentDb};
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use log::*;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Mutex};
+
+// refresh tokens a bit before they actually expire, so in-flight requests don't race the expiry
+const REFRESH_MARGIN_HOURS: i64 = 1;
+
+// trakt requires this for device-code apps when refreshing
+const REDIRECT_URI: &str = "urn:ietf:wg:oauth:2.0:oob";
+
+// oauth/device/code
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceCode {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_url: String,
+    /// seconds until `device_code` expires
+    pub expires_in: u64,
+    /// seconds to wait between polls
+    pub interval: u64,
+}
+
+// oauth/device/token and oauth/token
+#[derive(Serialize, Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    expires_in: i64,
+    created_at: i64,
+}
+
+impl TokenResponse {
+    fn into_token(self, client_id: &str) -> eyre::Result<OAuthToken> {
+        let created_at = Utc
+            .timestamp_opt(self.created_at, 0)
+            .single()
+            .ok_or_else(|| eyre::eyre!("invalid token timestamp: {}", self.created_at))?
+            .naive_utc();
+
+        Ok(OAuthToken {
+            client_id: client_id.to_string(),
+            access_token: self.access_token,
+            refresh_token: self.refresh_token,
+            created_at,
+            expires_at: created_at + chrono::Duration::seconds(self.expires_in),
+        })
+    }
+}
+
+/// Where we are in the login flow. The TUI watches this to show the user code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthStatus {
+    LoggedOut,
+    AwaitingUser {
+        user_code: String,
+        verification_url: String,
+    },
+    LoggedIn,
+    Failed(String),
+}
+
+/// Start the device-code flow: trakt gives us a code for the user to enter on its website.
+pub async fn request_device_code(
+    http: &Client,
+    base_url: &str,
+    client_id: &str,
+) -> eyre::Result<DeviceCode> {
+    let response = http
+        .post(format!("{}/oauth/device/code", base_url))
+        .json(&serde_json::json!({ "client_id": client_id }))
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => Ok(response.json::<DeviceCode>().await?),
+        other => eyre::bail!("could not request device code: {}", other),
+    }
+}
+
+/// Poll trakt until the user has authorized (or rejected) our device code.
+pub async fn poll_for_token(
+    http: &Client,
+    base_url: &str,
+    client_id: &str,
+    client_secret: &str,
+    code: &DeviceCode,
+) -> eyre::Result<OAuthToken> {
+    let deadline = Instant::now() + Duration::from_secs(code.expires_in);
+    let mut interval = Duration::from_secs(code.interval);
+
+    let body = serde_json::json!({
+        "code": code.device_code,
+        "client_id": client_id,
+        "client_secret": client_secret,
+    });
+
+    while Instant::now() < deadline {
+        tokio::time::sleep(interval).await;
+
+        let response = http
+            .post(format!("{}/oauth/device/token", base_url))
+            .json(&body)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => {
+                return response
+                    .json::<TokenResponse>()
+                    .await?
+                    .into_token(client_id)
+            }
+            // user hasn't entered the code yet
+            StatusCode::BAD_REQUEST => continue,
+            // polling too quickly
+            StatusCode::TOO_MANY_REQUESTS => interval += Duration::from_secs(1),
+            StatusCode::NOT_FOUND => eyre::bail!("invalid device code"),
+            StatusCode::CONFLICT => eyre::bail!("device code was already used"),
+            StatusCode::GONE => eyre::bail!("device code expired"),
+            StatusCode::IM_A_TEAPOT => eyre::bail!("user denied access"),
+            other => eyre::bail!("unexpected status while polling for token: {}", other),
+        }
+    }
+
+    eyre::bail!("device code expired before it was authorized")
+}
+
+/// Exchange a refresh token for a new access token.
+pub async fn refresh_token(
+    http: &Client,
+    base_url: &str,
+    client_secret: &str,
+    token: &OAuthToken,
+) -> eyre::Result<OAuthToken> {
+    let response = http
+        .post(format!("{}/oauth/token", base_url))
+        .json(&serde_json::json!({
+            "refresh_token": token.refresh_token,
+            "client_id": token.client_id,
+            "client_secret": client_secret,
+            "redirect_uri": REDIRECT_URI,
+            "grant_type": "refresh_token",
+        }))
+        .send()
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => response
+            .json::<TokenResponse>()
+            .await?
+            .into_token(&token.client_id),
+        other => eyre::bail!("could not refresh token: {}", other),
+    }
+}
+
+fn needs_refresh(token: &OAuthToken, now: NaiveDateTime) -> bool {
+    token.expires_at - chrono::Duration::hours(REFRESH_MARGIN_HOURS) <= now
+}
+
+/// Hands out access tokens for trakt requests. Tokens are persisted in the local cache and
+/// refreshed transparently before they expire.
+#[derive(Clone)]
+pub struct Authenticator {
+    base_url: String,
+    client_id: String,
+    client_secret: String,
+    cache: PersistentDb,
+    token: Arc<Mutex<Option<OAuthToken>>>,
+    status: Arc<watch::Sender<AuthStatus>>,
+}
+
+impl std::fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Authenticator { ... }")
+    }
+}
+
+impl Authenticator {
+    /// Create an authenticator, picking up a previously stored token if there is one.
+    pub async fn new(
+        cache: PersistentDb,
+        base_url: &str,
+        client_id: &str,
+        client_secret: &str,
+    ) -> eyre::Result<Authenticator> {
+        let token = cache.load_token(client_id.to_string()).await?;
+        let status = match token {
+            Some(_) => AuthStatus::LoggedIn,
+            None => AuthStatus::LoggedOut,
+        };
+
+        Ok(Authenticator {
+            base_url: base_url.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            cache,
+            token: Arc::new(Mutex::new(token)),
+            status: Arc::new(watch::channel(status).0),
+        })
+    }
+
+    pub fn status(&self) -> watch::Receiver<AuthStatus> {
+        self.status.subscribe()
+    }
+
+    pub async fn is_logged_in(&self) -> bool {
+        self.token.lock().await.is_some()
+    }
+
+    /// Run the device-code flow and store the resulting token.
+    /// While waiting on the user, the status carries the code they need to enter.
+    pub async fn login(&self, http: &Client) -> eyre::Result<()> {
+        let result = self.login_inner(http).await;
+        if let Err(e) = &result {
+            error!("login failed: {}", e);
+            self.status.send_replace(AuthStatus::Failed(e.to_string()));
+        }
+        result
+    }
+
+    async fn login_inner(&self, http: &Client) -> eyre::Result<()> {
+        let code = request_device_code(http, &self.base_url, &self.client_id).await?;
+        info!("Waiting for user to enter code {}", code.user_code);
+        self.status.send_replace(AuthStatus::AwaitingUser {
+            user_code: code.user_code.clone(),
+            verification_url: code.verification_url.clone(),
+        });
+
+        let token = poll_for_token(
+            http,
+            &self.base_url,
+            &self.client_id,
+            &self.client_secret,
+            &code,
+        )
+        .await?;
+        self.cache.store_token(token.clone()).await?;
+        *self.token.lock().await = Some(token);
+
+        self.status.send_replace(AuthStatus::LoggedIn);
+        Ok(())
+    }
+
+    /// Get a valid access token, refreshing (and storing) it first if it is about to expire.
+    pub async fn access_token(&self, http: &Client) -> eyre::Result<String> {
+        let mut guard = self.token.lock().await;
+        let token = guard
+            .as_mut()
+            .ok_or_else(|| eyre::eyre!("not logged in to trakt"))?;
+
+        if needs_refresh(token, Utc::now().naive_utc()) {
+            info!("Refreshing oauth token");
+            let refreshed = refresh_token(http, &self.base_url, &self.client_secret, token).await?;
+            self.cache.store_token(refreshed.clone()).await?;
+            *token = refreshed;
+        }
+
+        Ok(token.access_token.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Minimal stand-in for trakt's oauth endpoints: answers each request with whatever
+    /// `route` returns for its path and the number of requests seen so far.
+    async fn stand_in(route: fn(&str, usize) -> (u16, String)) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seen = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let n = seen.fetch_add(1, Ordering::SeqCst);
+
+                let mut buf = vec![0u8; 4096];
+                let len = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+                let (status, body) = route(path, n);
+                let response = format!(
+                    "HTTP/1.1 {} STAND-IN\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    fn device_code_json() -> String {
+        r#"{"device_code":"dev123","user_code":"ABCD1234","verification_url":"https://trakt.tv/activate","expires_in":600,"interval":0}"#.to_string()
+    }
+
+    fn token_json() -> String {
+        r#"{"access_token":"access","token_type":"bearer","expires_in":7776000,"refresh_token":"refresh","scope":"public","created_at":1689436800}"#.to_string()
+    }
+
+    #[tokio::test]
+    async fn device_code_flow() {
+        let base_url = stand_in(|path, n| match path {
+            "/oauth/device/code" => (200, device_code_json()),
+            // first poll is still pending, second succeeds
+            "/oauth/device/token" if n < 2 => (400, String::new()),
+            "/oauth/device/token" => (200, token_json()),
+            _ => (404, String::new()),
+        })
+        .await;
+
+        let http = Client::new();
+        let code = request_device_code(&http, &base_url, "client")
+            .await
+            .unwrap();
+        assert_eq!(code.user_code, "ABCD1234");
+
+        let token = poll_for_token(&http, &base_url, "client", "secret", &code)
+            .await
+            .unwrap();
+        assert_eq!(token.access_token, "access");
+        assert_eq!(token.refresh_token, "refresh");
+        assert_eq!(
+            token.expires_at - token.created_at,
+            chrono::Duration::seconds(7776000)
+        );
+    }
+
+    #[tokio::test]
+    async fn denied_device_code() {
+        let base_url = stand_in(|path, _| match path {
+            "/oauth/device/code" => (200, device_code_json()),
+            _ => (418, String::new()),
+        })
+        .await;
+
+        let http = Client::new();
+        let code = request_device_code(&http, &base_url, "client")
+            .await
+            .unwrap();
+        let err = poll_for_token(&http, &base_url, "client", "secret", &code)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "user denied access");
+    }
+
+    #[test]
+    fn refresh_before_expiry() {
+        let now = Utc::now().naive_utc();
+        let mut token = OAuthToken {
+            client_id: "client".to_string(),
+            access_token: "access".to_string(),
+            refresh_token: "refresh".to_string(),
+            created_at: now,
+            expires_at: now + chrono::Duration::days(90),
+        };
+        assert!(!needs_refresh(&token, now));
+
+        token.expires_at = now + chrono::Duration::minutes(5);
+        assert!(needs_refresh(&token, now));
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use eyre::Context;
+use log::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// response headers we act on, so they're worth keeping in a cassette
+const RECORDED_HEADERS: &[&str] = &[
+    "retry-after",
+    "x-ratelimit",
+    "x-pagination-page",
+    "x-pagination-limit",
+    "x-pagination-page-count",
+    "x-pagination-item-count",
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// send requests to trakt and save every request/response pair
+    Record,
+    /// answer requests from the cassette only, failing on anything that wasn't recorded
+    #[default]
+    Replay,
+}
+
+impl FromStr for CassetteMode {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "record" => Ok(CassetteMode::Record),
+            "replay" => Ok(CassetteMode::Replay),
+            other => eyre::bail!(
+                "unknown cassette mode '{}' (expected record or replay)",
+                other
+            ),
+        }
+    }
+}
+
+/// A request, minus anything that changes between runs (base url, auth headers).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// path and query, relative to the base url
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+}
+
+impl RecordedRequest {
+    pub fn new(method: &str, path: &str, body: Option<&[u8]>) -> RecordedRequest {
+        RecordedRequest {
+            method: method.to_string(),
+            path: path.trim_start_matches('/').to_string(),
+            // compare bodies as JSON, so key order doesn't matter
+            body: body.map(|b| {
+                serde_json::from_slice(b)
+                    .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(b).to_string()))
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for RecordedRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} /{}", self.method, self.path)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+impl RecordedResponse {
+    pub fn new(status: u16, headers: &HeaderMap, body: &str) -> RecordedResponse {
+        let headers = RECORDED_HEADERS
+            .iter()
+            .filter_map(|name| {
+                let value = headers.get(*name)?.to_str().ok()?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
+
+        RecordedResponse {
+            status,
+            headers,
+            body: body.to_string(),
+        }
+    }
+
+    pub fn header_map(&self) -> HeaderMap {
+        self.headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    HeaderName::from_str(name).ok()?,
+                    HeaderValue::from_str(value).ok()?,
+                ))
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Interaction {
+    request: RecordedRequest,
+    response: RecordedResponse,
+    #[serde(skip)]
+    used: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct CassetteFile {
+    interactions: Vec<Interaction>,
+}
+
+/// Recorded trakt traffic, so `t_api` can be tested against real responses with no network.
+///
+/// When replaying, each recorded interaction answers one matching request, in the order
+/// they were recorded. A request with nothing left to answer it is an error.
+#[derive(Debug)]
+pub struct Cassette {
+    mode: CassetteMode,
+    path: PathBuf,
+    interactions: Mutex<Vec<Interaction>>,
+}
+
+impl Cassette {
+    /// Load a cassette to replay, or start a new (empty) one to record into.
+    pub fn open(mode: CassetteMode, path: &Path) -> eyre::Result<Cassette> {
+        let interactions = match mode {
+            CassetteMode::Record => vec![],
+            CassetteMode::Replay => {
+                let text = fs::read_to_string(path)
+                    .wrap_err_with(|| format!("could not read cassette {}", path.display()))?;
+                serde_json::from_str::<CassetteFile>(&text)
+                    .wrap_err_with(|| format!("invalid cassette {}", path.display()))?
+                    .interactions
+            }
+        };
+
+        Ok(Cassette {
+            mode,
+            path: path.to_path_buf(),
+            interactions: Mutex::new(interactions),
+        })
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// The recorded response for `request`, if there's an unused one left.
+    pub fn replay(&self, request: &RecordedRequest) -> Option<RecordedResponse> {
+        let mut interactions = self.interactions.lock().unwrap();
+        let interaction = interactions
+            .iter_mut()
+            .find(|i| !i.used && i.request == *request)?;
+
+        interaction.used = true;
+        Some(interaction.response.clone())
+    }
+
+    /// Add an interaction and save the cassette.
+    pub fn record(&self, request: RecordedRequest, response: RecordedResponse) -> eyre::Result<()> {
+        let mut interactions = self.interactions.lock().unwrap();
+        interactions.push(Interaction {
+            request,
+            response,
+            used: false,
+        });
+
+        let file = CassetteFile {
+            interactions: interactions.clone(),
+        };
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&file)?)
+            .wrap_err_with(|| format!("could not write cassette {}", self.path.display()))?;
+
+        debug!(
+            "Recorded {} interactions to {}",
+            interactions.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_each_interaction_once() {
+        let path = std::env::temp_dir().join(format!("cassette-{}.json", std::process::id()));
+        let request = RecordedRequest::new("POST", "/sync/history", Some(br#"{"b":1,"a":2}"#));
+
+        let recorder = Cassette::open(CassetteMode::Record, &path).unwrap();
+        for status in [500, 201] {
+            let response = RecordedResponse::new(status, &HeaderMap::new(), "{}");
+            recorder.record(request.clone(), response).unwrap();
+        }
+
+        let player = Cassette::open(CassetteMode::Replay, &path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // same body with its keys in a different order
+        let same = RecordedRequest::new("POST", "sync/history", Some(br#"{"a":2,"b":1}"#));
+        assert_eq!(player.replay(&same).unwrap().status, 500);
+        assert_eq!(player.replay(&same).unwrap().status, 201);
+        assert_eq!(player.replay(&same), None);
+
+        let other = RecordedRequest::new("GET", "shows/tt0903747", None);
+        assert_eq!(player.replay(&other), None);
+    }
+}
@@ -1,13 +1,30 @@
-use crate::models::{TraktSeason, TraktShow, UserStatusSeason, UserStatusShow};
-use crate::schema::{seasons, trakt_shows};
-use crate::trakt::t_api::ApiSeasonDetails;
+use crate::models::{
+    NewOutboxItem, NewTraktSeason, OAuthToken, OutboxItem, OutboxState, Rating, RecordedBatch,
+    SyncActivity, SyncBatch, SyncBatchItem, SyncItemKind, SyncedItem, TraktEpisode, TraktSeason,
+    TraktShow, UserStatusEpisode, UserStatusSeason, UserStatusShow,
+};
+use crate::schema::{
+    episodes, oauth_tokens, outbox, ratings, seasons, sync_activities, sync_batch_items,
+    sync_batches, synced_history, trakt_shows,
+};
+use crate::trakt::t_api::{ApiEpisodeDetails, ApiSeasonDetails};
+use crate::trakt::t_import::WatchedHistory;
+use crate::trakt::t_reconcile::LocalState;
+use crate::trakt::t_sync::LocalHistory;
 
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::ffi::OsString;
 use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use chrono::prelude::*;
+use diesel::connection::SimpleConnection;
+use diesel::migration::MigrationSource;
 use diesel::prelude::*;
+use diesel::sqlite::Sqlite;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use dotenvy::dotenv;
 use eyre::Context;
 use futures::FutureExt;
@@ -27,16 +44,124 @@ pub trait Database {
     /// Update the database status of a show.
     fn update_show(&self, show: TraktShow) -> Self::Fut<eyre::Result<()>>;
 
+    /// Update the status of a season, along with its episodes:
+    /// `OnRelease` marks every aired episode as watched when it aired, `Unfilled` clears them.
     fn update_season(&self, season: TraktSeason) -> Self::Fut<eyre::Result<()>>;
 
-    fn update_show_with_seasons(
+    /// Add a show found on trakt (e.g. by searching), returning the stored row.
+    /// If the show is already in the db, only its trakt id is filled in.
+    fn add_show(&self, show: TraktShow) -> Self::Fut<eyre::Result<TraktShow>>;
+
+    /// Get up to `limit` shows that haven't been matched to trakt yet, in imdb id order,
+    /// starting after `after`.
+    fn unenriched_shows(
         &self,
-        show: &TraktShow,
+        after: Option<String>,
+        limit: i64,
+    ) -> Self::Fut<eyre::Result<Vec<TraktShow>>>;
+
+    /// Count shows that haven't been matched to trakt yet.
+    fn count_unenriched_shows(&self) -> Self::Fut<eyre::Result<usize>>;
+
+    /// Store the details trakt has for a show, together with its seasons.
+    fn store_show_details(
+        &self,
+        show: TraktShow,
         api_seasons: &[ApiSeasonDetails],
     ) -> Self::Fut<eyre::Result<Vec<TraktSeason>>>;
 
-    /// Fill database with shows loaded from the IMDB dump.
-    fn prefill_from_imdb(&self, rows: Vec<TraktShow>) -> Self::Fut<eyre::Result<()>>;
+    /// Get the stored seasons of a show, in order.
+    fn show_seasons(&self, show: TraktShow) -> Self::Fut<eyre::Result<Vec<TraktSeason>>>;
+
+    /// Get the stored episodes of a season, in order.
+    fn season_episodes(&self, season: TraktSeason) -> Self::Fut<eyre::Result<Vec<TraktEpisode>>>;
+
+    /// Store the episodes trakt has for a season, returning the stored rows.
+    /// Episodes we already have keep their watch status.
+    fn store_episodes(
+        &self,
+        season: TraktSeason,
+        api_episodes: &[ApiEpisodeDetails],
+    ) -> Self::Fut<eyre::Result<Vec<TraktEpisode>>>;
+
+    /// Update the watch status (and watch date) of an episode.
+    fn update_episode(&self, episode: TraktEpisode) -> Self::Fut<eyre::Result<()>>;
+
+    /// Mark episodes of `OnRelease` seasons that have aired since we last looked as watched.
+    /// Returns how many were marked.
+    fn fill_released_episodes(&self) -> Self::Fut<eyre::Result<usize>>;
+
+    /// Store what trakt says the user has watched of a show, and remember it as synced.
+    /// Watched items overwrite local statuses, anything else is left as it is.
+    fn import_watched(&self, history: WatchedHistory) -> Self::Fut<eyre::Result<()>>;
+
+    /// Get the shows in `imdb_ids`, and every show the user has set a status on.
+    fn shows_to_merge(&self, imdb_ids: Vec<String>) -> Self::Fut<eyre::Result<Vec<TraktShow>>>;
+
+    /// Replace all stored ratings with the ones from trakt.
+    fn replace_ratings(&self, ratings: Vec<Rating>) -> Self::Fut<eyre::Result<()>>;
+
+    /// Get the trakt activity times we saw at the last pull of each category.
+    fn sync_activities(&self) -> Self::Fut<eyre::Result<Vec<SyncActivity>>>;
+
+    /// Remember that a category has been pulled up to `updated_at`.
+    fn record_activity(&self, activity: SyncActivity) -> Self::Fut<eyre::Result<()>>;
+
+    /// Fill database with a batch of shows from the IMDB dump, in one transaction.
+    /// Returns how many were written.
+    fn prefill_from_imdb(&self, rows: Vec<TraktShow>) -> Self::Fut<eyre::Result<usize>>;
+
+    /// Load the stored OAuth token for a trakt application, if we have one.
+    fn load_token(&self, client_id: String) -> Self::Fut<eyre::Result<Option<OAuthToken>>>;
+
+    /// Store (or replace) the OAuth token for a trakt application.
+    fn store_token(&self, token: OAuthToken) -> Self::Fut<eyre::Result<()>>;
+
+    /// Get every show, season and episode the user has marked as watched.
+    fn local_history(&self) -> Self::Fut<eyre::Result<LocalHistory>>;
+
+    /// Get everything to compare with trakt: the shows in `imdb_ids`, shows the user set a
+    /// status on or has seasons of, and every stored season and episode.
+    fn local_state(&self, imdb_ids: Vec<String>) -> Self::Fut<eyre::Result<LocalState>>;
+
+    /// Get the history entries trakt has already accepted from us.
+    fn synced_items(&self) -> Self::Fut<eyre::Result<Vec<SyncedItem>>>;
+
+    /// Record history entries that trakt accepted.
+    fn record_synced(&self, items: Vec<SyncedItem>) -> Self::Fut<eyre::Result<()>>;
+
+    /// Forget that a show/season/episode was synced (after removing it from trakt's history).
+    fn forget_synced(&self, kind: SyncItemKind, trakt_id: i32) -> Self::Fut<eyre::Result<()>>;
+
+    /// Record a push to trakt and the history entries it changed, returning the batch id.
+    /// (the items' `batch_id` is filled in)
+    fn record_sync_batch(
+        &self,
+        pushed_at: NaiveDateTime,
+        items: Vec<SyncBatchItem>,
+    ) -> Self::Fut<eyre::Result<i32>>;
+
+    /// Get every recorded push with its items, newest first.
+    fn sync_batches(&self) -> Self::Fut<eyre::Result<Vec<RecordedBatch>>>;
+
+    /// Remember that a push has been undone.
+    fn mark_batch_reverted(
+        &self,
+        batch_id: i32,
+        reverted_at: NaiveDateTime,
+    ) -> Self::Fut<eyre::Result<()>>;
+
+    /// Queue a change for trakt, dropping pending changes it makes redundant.
+    fn enqueue_outbox(&self, item: NewOutboxItem) -> Self::Fut<eyre::Result<()>>;
+
+    /// Get all pending outbox items, oldest first.
+    fn pending_outbox(&self) -> Self::Fut<eyre::Result<Vec<OutboxItem>>>;
+
+    /// Save the state/attempts/error of an outbox item after trying to send it.
+    fn update_outbox(&self, item: OutboxItem) -> Self::Fut<eyre::Result<()>>;
+
+    /// Count outbox items that haven't been sent yet.
+    fn count_pending_outbox(&self) -> Self::Fut<eyre::Result<usize>>;
 }
 
 /// Handle to sqlite-backed persistent database. Provides an async interface
@@ -77,39 +202,320 @@ impl Database for PersistentDb {
         self.on_blocking_task(move |conn| Self::update_season_impl(conn, &season))
     }
 
-    fn update_show_with_seasons(
+    fn add_show(&self, show: TraktShow) -> Self::Fut<eyre::Result<TraktShow>> {
+        self.on_blocking_task(move |conn| Self::add_show_impl(conn, &show))
+    }
+
+    fn unenriched_shows(
         &self,
-        show: &TraktShow,
+        after: Option<String>,
+        limit: i64,
+    ) -> Self::Fut<eyre::Result<Vec<TraktShow>>> {
+        self.on_blocking_task(move |conn| Self::unenriched_shows_impl(conn, after, limit))
+    }
+
+    fn count_unenriched_shows(&self) -> Self::Fut<eyre::Result<usize>> {
+        self.on_blocking_task(Self::count_unenriched_shows_impl)
+    }
+
+    fn store_show_details(
+        &self,
+        show: TraktShow,
         api_seasons: &[ApiSeasonDetails],
     ) -> Self::Fut<eyre::Result<Vec<TraktSeason>>> {
-        let trakt_seasons: Vec<_> = api_seasons
-            .iter()
-            .map(|s| TraktSeason {
-                id: s.ids.trakt as i32,
-                title: s.title.clone(),
-                first_aired: Some(s.first_aired.naive_utc()),
-                show_id: show.trakt_id.unwrap() as i32,
-                season_number: s.number as i32,
-                episode_count: s.episode_count as i32,
-                user_status: UserStatusSeason::Unfilled,
-            })
-            .collect();
+        let trakt_seasons = seasons_from_api(&show, api_seasons);
+        self.on_blocking_task(move |conn| Self::store_show_details_impl(conn, &show, trakt_seasons))
+    }
+
+    fn show_seasons(&self, show: TraktShow) -> Self::Fut<eyre::Result<Vec<TraktSeason>>> {
+        self.on_blocking_task(move |conn| Self::show_seasons_impl(conn, &show))
+    }
+
+    fn season_episodes(&self, season: TraktSeason) -> Self::Fut<eyre::Result<Vec<TraktEpisode>>> {
+        self.on_blocking_task(move |conn| Self::season_episodes_impl(conn, &season))
+    }
+
+    fn store_episodes(
+        &self,
+        season: TraktSeason,
+        api_episodes: &[ApiEpisodeDetails],
+    ) -> Self::Fut<eyre::Result<Vec<TraktEpisode>>> {
+        let trakt_episodes = episodes_from_api(&season, api_episodes);
+        self.on_blocking_task(move |conn| Self::store_episodes_impl(conn, &season, &trakt_episodes))
+    }
+
+    fn update_episode(&self, episode: TraktEpisode) -> Self::Fut<eyre::Result<()>> {
+        self.on_blocking_task(move |conn| Self::update_episode_impl(conn, &episode))
+    }
 
-        self.on_blocking_task(move |conn| Self::update_show_with_seasons_impl(conn, trakt_seasons))
+    fn fill_released_episodes(&self) -> Self::Fut<eyre::Result<usize>> {
+        self.on_blocking_task(|conn| {
+            Self::fill_released_episodes_impl(conn, Utc::now().naive_utc())
+        })
+    }
+
+    fn import_watched(&self, history: WatchedHistory) -> Self::Fut<eyre::Result<()>> {
+        self.on_blocking_task(move |conn| Self::import_watched_impl(conn, &history))
+    }
+
+    fn shows_to_merge(&self, imdb_ids: Vec<String>) -> Self::Fut<eyre::Result<Vec<TraktShow>>> {
+        self.on_blocking_task(move |conn| Self::shows_to_merge_impl(conn, &imdb_ids))
+    }
+
+    fn replace_ratings(&self, ratings: Vec<Rating>) -> Self::Fut<eyre::Result<()>> {
+        self.on_blocking_task(move |conn| Self::replace_ratings_impl(conn, &ratings))
     }
 
-    fn prefill_from_imdb(&self, rows: Vec<TraktShow>) -> PersistentDbFuture<eyre::Result<()>> {
+    fn sync_activities(&self) -> Self::Fut<eyre::Result<Vec<SyncActivity>>> {
+        self.on_blocking_task(Self::sync_activities_impl)
+    }
+
+    fn record_activity(&self, activity: SyncActivity) -> Self::Fut<eyre::Result<()>> {
+        self.on_blocking_task(move |conn| Self::record_activity_impl(conn, &activity))
+    }
+
+    fn prefill_from_imdb(&self, rows: Vec<TraktShow>) -> PersistentDbFuture<eyre::Result<usize>> {
         self.on_blocking_task(move |conn| Self::prefill_from_imdb_impl(conn, &rows))
     }
+
+    fn load_token(&self, client_id: String) -> Self::Fut<eyre::Result<Option<OAuthToken>>> {
+        self.on_blocking_task(move |conn| Self::load_token_impl(conn, &client_id))
+    }
+
+    fn store_token(&self, token: OAuthToken) -> Self::Fut<eyre::Result<()>> {
+        self.on_blocking_task(move |conn| Self::store_token_impl(conn, &token))
+    }
+
+    fn local_history(&self) -> Self::Fut<eyre::Result<LocalHistory>> {
+        self.on_blocking_task(Self::local_history_impl)
+    }
+
+    fn local_state(&self, imdb_ids: Vec<String>) -> Self::Fut<eyre::Result<LocalState>> {
+        self.on_blocking_task(move |conn| Self::local_state_impl(conn, &imdb_ids))
+    }
+
+    fn synced_items(&self) -> Self::Fut<eyre::Result<Vec<SyncedItem>>> {
+        self.on_blocking_task(Self::synced_items_impl)
+    }
+
+    fn record_synced(&self, items: Vec<SyncedItem>) -> Self::Fut<eyre::Result<()>> {
+        self.on_blocking_task(move |conn| Self::record_synced_impl(conn, &items))
+    }
+
+    fn forget_synced(&self, kind: SyncItemKind, trakt_id: i32) -> Self::Fut<eyre::Result<()>> {
+        self.on_blocking_task(move |conn| Self::forget_synced_impl(conn, kind, trakt_id))
+    }
+
+    fn record_sync_batch(
+        &self,
+        pushed_at: NaiveDateTime,
+        items: Vec<SyncBatchItem>,
+    ) -> Self::Fut<eyre::Result<i32>> {
+        self.on_blocking_task(move |conn| Self::record_sync_batch_impl(conn, pushed_at, items))
+    }
+
+    fn sync_batches(&self) -> Self::Fut<eyre::Result<Vec<RecordedBatch>>> {
+        self.on_blocking_task(Self::sync_batches_impl)
+    }
+
+    fn mark_batch_reverted(
+        &self,
+        batch_id: i32,
+        reverted_at: NaiveDateTime,
+    ) -> Self::Fut<eyre::Result<()>> {
+        self.on_blocking_task(move |conn| {
+            Self::mark_batch_reverted_impl(conn, batch_id, reverted_at)
+        })
+    }
+
+    fn enqueue_outbox(&self, item: NewOutboxItem) -> Self::Fut<eyre::Result<()>> {
+        self.on_blocking_task(move |conn| Self::enqueue_outbox_impl(conn, &item))
+    }
+
+    fn pending_outbox(&self) -> Self::Fut<eyre::Result<Vec<OutboxItem>>> {
+        self.on_blocking_task(Self::pending_outbox_impl)
+    }
+
+    fn update_outbox(&self, item: OutboxItem) -> Self::Fut<eyre::Result<()>> {
+        self.on_blocking_task(move |conn| Self::update_outbox_impl(conn, &item))
+    }
+
+    fn count_pending_outbox(&self) -> Self::Fut<eyre::Result<usize>> {
+        self.on_blocking_task(Self::count_pending_outbox_impl)
+    }
+}
+
+pub fn seasons_from_api(show: &TraktShow, api_seasons: &[ApiSeasonDetails]) -> Vec<TraktSeason> {
+    api_seasons
+        .iter()
+        .map(|s| TraktSeason {
+            id: s.ids.trakt as i32,
+            title: s.title.clone(),
+            first_aired: s.first_aired.map(|d| d.naive_utc()),
+            show_id: show.trakt_id.unwrap(),
+            show_imdb_id: show.imdb_id.clone(),
+            season_number: s.number as i32,
+            episode_count: s.episode_count as i32,
+            user_status: UserStatusSeason::Unfilled,
+            watched_at: None,
+        })
+        .collect()
+}
+
+pub fn episodes_from_api(
+    season: &TraktSeason,
+    api_episodes: &[ApiEpisodeDetails],
+) -> Vec<TraktEpisode> {
+    api_episodes
+        .iter()
+        .map(|e| TraktEpisode {
+            id: e.ids.trakt as i32,
+            season_id: season.id,
+            show_id: season.show_id,
+            season_number: e.season as i32,
+            episode_number: e.number as i32,
+            title: e
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Episode {}", e.number)),
+            first_aired: e.first_aired.map(|d| d.naive_utc()),
+            watched_at: None,
+            user_status: UserStatusEpisode::Unwatched,
+        })
+        .collect()
+}
+
+/// Every migration in `migrations/`, built into the binary.
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+// the db goes in here, under the user's data directory (e.g. ~/.local/share on linux)
+const DATA_DIR_NAME: &str = "trakt-tv-updater";
+const DB_FILE_NAME: &str = "trakt.db";
+
+// prefill batches go in as multi-row upserts small enough (12 columns each) to stay under
+// sqlite's 999 variable limit
+const PREFILL_STATEMENT_ROWS: usize = 80;
+
+/// This app's directory under the user's data directory (created if needed).
+pub fn data_dir() -> eyre::Result<PathBuf> {
+    let dir = dirs::data_dir()
+        .ok_or_else(|| eyre::eyre!("no data directory for this user, set DATABASE_URL instead"))?
+        .join(DATA_DIR_NAME);
+    std::fs::create_dir_all(&dir)
+        .wrap_err_with(|| format!("could not create {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Where the db lives: `DATABASE_URL` if it's set, otherwise a file in [`data_dir`].
+fn database_url() -> eyre::Result<String> {
+    if let Ok(url) = env::var("DATABASE_URL") {
+        return Ok(url);
+    }
+
+    Ok(data_dir()?
+        .join(DB_FILE_NAME)
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Bring a db up to this binary's schema. If there's anything to apply to a db that
+/// already has a schema, it is backed up next to the original first.
+/// A db with migrations this binary doesn't know about (i.e. written by a newer version)
+/// is refused rather than touched.
+fn migrate(conn: &mut SqliteConnection, database_url: &str) -> eyre::Result<()> {
+    let known: HashSet<String> = MigrationSource::<Sqlite>::migrations(&MIGRATIONS)
+        .map_err(|e| eyre::eyre!(e))?
+        .iter()
+        .map(|m| m.name().version().to_string())
+        .collect();
+    let applied = conn.applied_migrations().map_err(|e| eyre::eyre!(e))?;
+    if let Some(newer) = applied.iter().find(|v| !known.contains(&v.to_string())) {
+        eyre::bail!(
+            "{} has schema version {}, which is newer than this build knows about; \
+             please upgrade trakt-tv-updater",
+            database_url,
+            newer
+        );
+    }
+
+    let pending = conn
+        .pending_migrations(MIGRATIONS)
+        .map_err(|e| eyre::eyre!(e))?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    if !applied.is_empty() {
+        let mut backup = OsString::from(database_url);
+        backup.push(format!(".backup-{}", Utc::now().format("%Y%m%d-%H%M%S")));
+        let backup = PathBuf::from(backup);
+        diesel::sql_query("VACUUM INTO ?")
+            .bind::<diesel::sql_types::Text, _>(backup.to_string_lossy())
+            .execute(conn)
+            .wrap_err("could not back up the db before migrating it")?;
+        info!("Backed up {} to {}", database_url, backup.display());
+    }
+
+    // rebuilding tables can leave rows behind (e.g. duplicates, or orphans), and shows that
+    // shared a trakt id lose it
+    let before = (!applied.is_empty())
+        .then(|| Ok::<_, eyre::Report>((count_history_rows(conn)?, linked_shows(conn)?)))
+        .transpose()?;
+    conn.run_pending_migrations(MIGRATIONS)
+        .map_err(|e| eyre::eyre!(e))?;
+    info!("Applied {} migrations to {}", pending.len(), database_url);
+
+    if let Some((before, linked)) = before {
+        let unlinked: Vec<_> = linked.difference(&linked_shows(conn)?).cloned().collect();
+        if !unlinked.is_empty() {
+            warn!(
+                "Migrating un-linked {} from trakt: another show had the same trakt id",
+                unlinked.join(", ")
+            );
+        }
+
+        let after = count_history_rows(conn)?;
+        if after != before {
+            warn!(
+                "Migrating dropped {} seasons and {} episodes that were duplicates or belonged \
+                 to no show (the backup still has them)",
+                before.0.saturating_sub(after.0),
+                before.1.saturating_sub(after.1)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Number of stored (seasons, episodes).
+fn count_history_rows(conn: &mut SqliteConnection) -> eyre::Result<(i64, i64)> {
+    let seasons = seasons::table.count().get_result(conn)?;
+    let episodes = episodes::table.count().get_result(conn)?;
+    Ok((seasons, episodes))
+}
+
+/// The imdb ids of shows that have a trakt id.
+fn linked_shows(conn: &mut SqliteConnection) -> eyre::Result<HashSet<String>> {
+    let linked = trakt_shows::table
+        .filter(trakt_shows::trakt_id.is_not_null())
+        .select(trakt_shows::imdb_id)
+        .load::<String>(conn)?;
+    Ok(linked.into_iter().collect())
 }
 
 impl PersistentDb {
     pub fn connect_sync() -> eyre::Result<PersistentDb> {
         dotenv().ok();
 
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set.");
+        let database_url = database_url()?;
+        let mut conn = SqliteConnection::establish(&database_url)
+            .wrap_err_with(|| format!("could not open {}", database_url))?;
+        migrate(&mut conn, &database_url)?;
+        // sqlite leaves foreign keys unchecked unless asked, per connection
+        conn.batch_execute("PRAGMA foreign_keys = ON")?;
+
         Ok(PersistentDb {
-            conn: Arc::new(Mutex::new(SqliteConnection::establish(&database_url)?)),
+            conn: Arc::new(Mutex::new(conn)),
         })
     }
 
@@ -119,6 +525,17 @@ impl PersistentDb {
             .unwrap()
     }
 
+    /// Fresh, empty database that only lives as long as the handle (for tests).
+    #[cfg(test)]
+    pub fn in_memory() -> eyre::Result<PersistentDb> {
+        let mut conn = SqliteConnection::establish(":memory:")?;
+        migrate(&mut conn, ":memory:")?;
+        conn.batch_execute("PRAGMA foreign_keys = ON")?;
+        Ok(PersistentDb {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
     fn on_blocking_task<T, F>(&self, f: F) -> PersistentDbFuture<T>
     where
         F: 'static + Send + FnOnce(&mut SqliteConnection) -> T,
@@ -161,6 +578,7 @@ impl PersistentDb {
             .set((
                 trakt_id.eq(&show.trakt_id),
                 user_status.eq(&show.user_status),
+                status_changed_at.eq(&show.status_changed_at),
                 overview.eq(&overview),
             ))
             .execute(conn)
@@ -177,20 +595,112 @@ impl PersistentDb {
     ) -> eyre::Result<()> {
         use self::seasons::dsl::*;
 
-        let updated_season: TraktSeason = diesel::update(seasons.filter(id.eq(season.id)))
-            .set((
-                season_number.eq(&season.season_number),
-                episode_count.eq(&season.episode_count),
-                user_status.eq(&season.user_status),
-            ))
-            .get_result(conn)?;
+        conn.transaction(|conn| {
+            let previous = Self::season_impl(conn, season.id)?;
+            diesel::update(seasons.filter(id.eq(season.id)))
+                .set((
+                    season_number.eq(&season.season_number),
+                    episode_count.eq(&season.episode_count),
+                    user_status.eq(&season.user_status),
+                    watched_at.eq(&season.watched_at),
+                ))
+                .execute(conn)?;
+            let updated_season = Self::season_impl(conn, season.id)?;
 
-        info!("Updated to season: {:?}", updated_season);
+            info!("Updated to season: {:?}", updated_season);
 
-        // TODO: update the episodes in this season, if necessary
-        // (if user selects ON_RELEASE for this season)
+            // take back what the previous status stamped, episodes with a date of their own keep it
+            Self::clear_season_stamps(conn, &previous)?;
+            match updated_season.user_status {
+                UserStatusSeason::Unfilled => {}
+                UserStatusSeason::OnRelease => {
+                    let now = Utc::now().naive_utc();
+                    Self::stamp_released_episodes(conn, &updated_season, now)?;
+                }
+                UserStatusSeason::OtherDate => {
+                    Self::stamp_season_episodes(conn, &updated_season)?;
+                }
+            }
 
-        Ok(())
+            Ok(())
+        })
+    }
+
+    /// Mark the season's episodes that aired before `now` as watched when they aired.
+    /// Episodes that haven't aired yet are left for `fill_released_episodes`.
+    fn stamp_released_episodes(
+        conn: &mut SqliteConnection,
+        season: &TraktSeason,
+        now: NaiveDateTime,
+    ) -> eyre::Result<usize> {
+        use self::episodes::dsl::*;
+
+        diesel::update(
+            TraktEpisode::belonging_to(season)
+                .filter(watched_at.is_null())
+                .filter(first_aired.le(now)),
+        )
+        .set((
+            watched_at.eq(first_aired),
+            user_status.eq(UserStatusEpisode::Watched),
+        ))
+        .execute(conn)
+        .wrap_err("could not mark released episodes as watched")
+    }
+
+    /// Mark the season's episodes that had aired by the date picked for it as watched then.
+    /// Episodes that are already watched (e.g. imported from trakt) keep their date.
+    fn stamp_season_episodes(
+        conn: &mut SqliteConnection,
+        season: &TraktSeason,
+    ) -> eyre::Result<usize> {
+        use self::episodes::dsl::*;
+
+        // no date picked: trakt will use the time of the sync, so there's nothing to stamp
+        let Some(at) = season.watched_at else {
+            return Ok(0);
+        };
+
+        diesel::update(
+            TraktEpisode::belonging_to(season)
+                .filter(watched_at.is_null())
+                .filter(first_aired.le(at)),
+        )
+        .set((
+            watched_at.eq(at),
+            user_status.eq(UserStatusEpisode::Watched),
+        ))
+        .execute(conn)
+        .wrap_err("could not mark episodes as watched")
+    }
+
+    /// Unwatch the season's episodes that still hold the date its status stamped on them:
+    /// when they aired for OnRelease, the picked date for OtherDate.
+    fn clear_season_stamps(
+        conn: &mut SqliteConnection,
+        season: &TraktSeason,
+    ) -> eyre::Result<usize> {
+        use self::episodes::dsl::*;
+
+        let clear = (
+            watched_at.eq(None::<NaiveDateTime>),
+            user_status.eq(UserStatusEpisode::Unwatched),
+        );
+        let stamped = TraktEpisode::belonging_to(season);
+        match (&season.user_status, season.watched_at) {
+            (UserStatusSeason::OnRelease, _) => {
+                diesel::update(stamped.filter(watched_at.eq(first_aired)))
+                    .set(clear)
+                    .execute(conn)
+            }
+            (UserStatusSeason::OtherDate, Some(at)) => {
+                diesel::update(stamped.filter(watched_at.eq(at)))
+                    .set(clear)
+                    .execute(conn)
+            }
+            _ => Ok(0),
+        }
+        .wrap_err("could not clear episodes")
     }
 
     fn update_show_with_seasons_impl(
@@ -199,47 +709,1055 @@ impl PersistentDb {
     ) -> eyre::Result<Vec<TraktSeason>> {
         use self::seasons::dsl::*;
 
-        for season in trakt_seasons.iter() {
-            diesel::insert_into(seasons)
-                .values(season)
+        // hand back the stored rows, so statuses the user already picked are kept
+        trakt_seasons
+            .iter()
+            .map(|season| {
+                // trakt gave the season a new id: the old row goes, along with its episodes
+                diesel::delete(
+                    seasons
+                        .filter(show_imdb_id.eq(&season.show_imdb_id))
+                        .filter(season_number.eq(season.season_number))
+                        .filter(id.ne(season.id)),
+                )
+                .execute(conn)?;
+
+                diesel::insert_into(seasons)
+                    .values(NewTraktSeason::from(season))
+                    .on_conflict(id)
+                    .do_update()
+                    .set(season_number.eq(season.season_number))
+                    .execute(conn)
+                    .wrap_err("failed db insert")?;
+                Self::season_impl(conn, season.id)
+            })
+            .collect()
+    }
+
+    fn add_show_impl(conn: &mut SqliteConnection, show: &TraktShow) -> eyre::Result<TraktShow> {
+        use self::trakt_shows::dsl::*;
+
+        let stored = diesel::insert_into(trakt_shows)
+            .values(show)
+            .on_conflict(imdb_id)
+            .do_update()
+            .set(trakt_id.eq(&show.trakt_id))
+            .returning(TraktShow::as_returning())
+            .get_result(conn)
+            .wrap_err("could not add show")?;
+
+        info!("Added show: {} ({:?})", stored.imdb_id, stored.trakt_id);
+        Ok(stored)
+    }
+
+    fn shows_to_merge_impl(
+        conn: &mut SqliteConnection,
+        imdb_ids: &[String],
+    ) -> eyre::Result<Vec<TraktShow>> {
+        use self::trakt_shows::dsl::*;
+
+        trakt_shows
+            .filter(imdb_id.eq_any(imdb_ids).or(status_changed_at.is_not_null()))
+            .select(TraktShow::as_select())
+            .load(conn)
+            .wrap_err("could not load shows to merge")
+    }
+
+    fn replace_ratings_impl(conn: &mut SqliteConnection, rows: &[Rating]) -> eyre::Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(ratings::table).execute(conn)?;
+            diesel::insert_into(ratings::table)
+                .values(rows)
+                .execute(conn)?;
+            Ok::<_, diesel::result::Error>(())
+        })
+        .wrap_err("could not store ratings")
+    }
+
+    fn sync_activities_impl(conn: &mut SqliteConnection) -> eyre::Result<Vec<SyncActivity>> {
+        sync_activities::table
+            .select(SyncActivity::as_select())
+            .load(conn)
+            .wrap_err("could not load sync activities")
+    }
+
+    fn record_activity_impl(
+        conn: &mut SqliteConnection,
+        activity: &SyncActivity,
+    ) -> eyre::Result<()> {
+        use self::sync_activities::dsl::*;
+
+        diesel::insert_into(sync_activities)
+            .values(activity)
+            .on_conflict(category)
+            .do_update()
+            .set(updated_at.eq(&activity.updated_at))
+            .execute(conn)
+            .map(|_| ())
+            .wrap_err("could not record sync activity")
+    }
+
+    fn unenriched_shows_impl(
+        conn: &mut SqliteConnection,
+        after: Option<String>,
+        limit: i64,
+    ) -> eyre::Result<Vec<TraktShow>> {
+        let mut query = trakt_shows::table
+            .filter(trakt_shows::trakt_id.is_null())
+            .order_by(trakt_shows::imdb_id)
+            .limit(limit)
+            .select(TraktShow::as_select())
+            .into_boxed();
+
+        if let Some(after) = after {
+            query = query.filter(trakt_shows::imdb_id.gt(after));
+        }
+
+        query.load(conn).wrap_err("could not load unenriched shows")
+    }
+
+    fn count_unenriched_shows_impl(conn: &mut SqliteConnection) -> eyre::Result<usize> {
+        let rows: i64 = trakt_shows::table
+            .filter(trakt_shows::trakt_id.is_null())
+            .count()
+            .get_result(conn)?;
+        Ok(rows.try_into()?)
+    }
+
+    fn store_show_details_impl(
+        conn: &mut SqliteConnection,
+        show: &TraktShow,
+        trakt_seasons: Vec<TraktSeason>,
+    ) -> eyre::Result<Vec<TraktSeason>> {
+        use self::trakt_shows::dsl::*;
+
+        conn.transaction(|conn| {
+            diesel::update(trakt_shows.filter(imdb_id.eq(&show.imdb_id)))
+                .set((
+                    trakt_id.eq(&show.trakt_id),
+                    country.eq(&show.country),
+                    network.eq(&show.network),
+                    no_seasons.eq(&show.no_seasons),
+                    no_episodes.eq(&show.no_episodes),
+                    overview.eq(&show.overview),
+                ))
+                .execute(conn)
+                .wrap_err("could not store show details")?;
+
+            Self::update_show_with_seasons_impl(conn, trakt_seasons)
+        })
+    }
+
+    fn show_seasons_impl(
+        conn: &mut SqliteConnection,
+        show: &TraktShow,
+    ) -> eyre::Result<Vec<TraktSeason>> {
+        TraktSeason::belonging_to(show)
+            .inner_join(trakt_shows::table)
+            .order_by(seasons::season_number)
+            .select(TraktSeason::as_select())
+            .load(conn)
+            .wrap_err("could not load seasons")
+    }
+
+    /// A stored season, with its show's trakt id.
+    fn season_impl(conn: &mut SqliteConnection, season_id: i32) -> eyre::Result<TraktSeason> {
+        seasons::table
+            .inner_join(trakt_shows::table)
+            .filter(seasons::id.eq(season_id))
+            .select(TraktSeason::as_select())
+            .first(conn)
+            .wrap_err_with(|| format!("could not load season {}", season_id))
+    }
+
+    fn season_episodes_impl(
+        conn: &mut SqliteConnection,
+        season: &TraktSeason,
+    ) -> eyre::Result<Vec<TraktEpisode>> {
+        TraktEpisode::belonging_to(season)
+            .order_by(episodes::episode_number)
+            .select(TraktEpisode::as_select())
+            .load(conn)
+            .wrap_err("could not load episodes")
+    }
+
+    fn store_episodes_impl(
+        conn: &mut SqliteConnection,
+        season: &TraktSeason,
+        trakt_episodes: &[TraktEpisode],
+    ) -> eyre::Result<Vec<TraktEpisode>> {
+        conn.transaction(|conn| {
+            Self::upsert_episodes(conn, trakt_episodes)?;
+
+            // the season's status may have been picked before we had its episodes
+            let stored: Option<TraktSeason> = seasons::table
+                .inner_join(trakt_shows::table)
+                .filter(seasons::id.eq(season.id))
+                .select(TraktSeason::as_select())
+                .first(conn)
+                .optional()?;
+            match stored {
+                Some(s) if s.user_status == UserStatusSeason::OnRelease => {
+                    Self::stamp_released_episodes(conn, &s, Utc::now().naive_utc())?;
+                }
+                Some(s) if s.user_status == UserStatusSeason::OtherDate => {
+                    Self::stamp_season_episodes(conn, &s)?;
+                }
+                _ => {}
+            }
+
+            Self::season_episodes_impl(conn, season)
+        })
+    }
+
+    /// Insert episodes, or update what trakt knows about them (keeping their watch status).
+    fn upsert_episodes(
+        conn: &mut SqliteConnection,
+        trakt_episodes: &[TraktEpisode],
+    ) -> eyre::Result<()> {
+        use self::episodes::dsl::*;
+
+        for episode in trakt_episodes {
+            diesel::insert_into(episodes)
+                .values(episode)
                 .on_conflict(id)
                 .do_update()
-                .set(season_number.eq(season.season_number))
+                .set((
+                    title.eq(&episode.title),
+                    first_aired.eq(&episode.first_aired),
+                    season_id.eq(episode.season_id),
+                    season_number.eq(episode.season_number),
+                    episode_number.eq(episode.episode_number),
+                ))
                 .execute(conn)
-                .wrap_err("failed db insert")?;
+                .wrap_err("could not store episode")?;
         }
-
-        Ok(trakt_seasons)
+        Ok(())
     }
 
-    fn prefill_from_imdb_impl(
+    fn import_watched_impl(
         conn: &mut SqliteConnection,
-        rows: &Vec<TraktShow>,
+        history: &WatchedHistory,
     ) -> eyre::Result<()> {
-        info!("Filling db...");
-
-        use self::trakt_shows::dsl::*;
+        let show = &history.show;
 
-        for row in rows {
-            diesel::insert_into(trakt_shows)
-                .values(row)
-                .on_conflict(imdb_id)
+        conn.transaction(|conn| {
+            diesel::insert_into(trakt_shows::table)
+                .values(show)
+                .on_conflict(trakt_shows::imdb_id)
                 .do_update()
-                // update the values that might be updated in a new data dump
                 .set((
-                    release_year.eq(&row.release_year),
-                    no_seasons.eq(&row.no_seasons),
-                    no_episodes.eq(&row.no_episodes),
+                    trakt_shows::trakt_id.eq(&show.trakt_id),
+                    trakt_shows::country.eq(&show.country),
+                    trakt_shows::network.eq(&show.network),
+                    trakt_shows::no_seasons.eq(&show.no_seasons),
+                    trakt_shows::no_episodes.eq(&show.no_episodes),
+                    trakt_shows::overview.eq(&show.overview),
                 ))
                 .execute(conn)
-                .map(|_| ())
-                .wrap_err("could not insert show")?;
+                .wrap_err("could not import show")?;
+            if show.user_status == UserStatusShow::Watched {
+                diesel::update(trakt_shows::table.find(&show.imdb_id))
+                    .set(trakt_shows::user_status.eq(&show.user_status))
+                    .execute(conn)?;
+            }
+
+            Self::update_show_with_seasons_impl(conn, history.seasons.clone())?;
+            for season in history.seasons.iter() {
+                if season.user_status != UserStatusSeason::Unfilled {
+                    diesel::update(seasons::table.find(season.id))
+                        .set((
+                            seasons::user_status.eq(&season.user_status),
+                            seasons::watched_at.eq(&season.watched_at),
+                        ))
+                        .execute(conn)?;
+                }
+            }
+
+            Self::upsert_episodes(conn, &history.episodes)?;
+            for episode in history.episodes.iter() {
+                if episode.user_status == UserStatusEpisode::Watched {
+                    diesel::update(episodes::table.find(episode.id))
+                        .set((
+                            episodes::user_status.eq(&episode.user_status),
+                            episodes::watched_at.eq(&episode.watched_at),
+                        ))
+                        .execute(conn)?;
+                }
+            }
+
+            Self::record_synced_impl(conn, &history.synced)?;
+
+            info!(
+                "Imported watch history of {} ({} items)",
+                show.imdb_id,
+                history.synced.len()
+            );
+            Ok(())
+        })
+    }
+
+    fn update_episode_impl(
+        conn: &mut SqliteConnection,
+        episode: &TraktEpisode,
+    ) -> eyre::Result<()> {
+        use self::episodes::dsl::*;
+
+        diesel::update(episodes.filter(id.eq(episode.id)))
+            .set((
+                user_status.eq(&episode.user_status),
+                watched_at.eq(&episode.watched_at),
+            ))
+            .execute(conn)
+            .wrap_err("could not update episode")?;
+
+        info!("Updated episode: {:?}", episode);
+        Ok(())
+    }
+
+    fn fill_released_episodes_impl(
+        conn: &mut SqliteConnection,
+        now: NaiveDateTime,
+    ) -> eyre::Result<usize> {
+        conn.transaction(|conn| {
+            let on_release: Vec<TraktSeason> = seasons::table
+                .inner_join(trakt_shows::table)
+                .filter(seasons::user_status.eq(UserStatusSeason::OnRelease))
+                .select(TraktSeason::as_select())
+                .load(conn)?;
+
+            let mut marked = 0;
+            for season in on_release.iter() {
+                marked += Self::stamp_released_episodes(conn, season, now)?;
+            }
+            if marked > 0 {
+                info!("Marked {} newly released episodes as watched", marked);
+            }
+            Ok(marked)
+        })
+    }
 
-            info!("Inserted row: {}", &row.imdb_id);
+    fn prefill_from_imdb_impl(
+        conn: &mut SqliteConnection,
+        rows: &[TraktShow],
+    ) -> eyre::Result<usize> {
+        conn.transaction(|conn| {
+            for chunk in rows.chunks(PREFILL_STATEMENT_ROWS) {
+                Self::upsert_imdb_rows(conn, chunk)?;
+            }
+            diesel::QueryResult::Ok(())
+        })
+        .wrap_err("could not insert shows")?;
+
+        debug!("Inserted/Updated {} rows.", rows.len());
+        Ok(rows.len())
+    }
+
+    /// One multi-row upsert. diesel can't batch an `ON CONFLICT` insert on sqlite, so the
+    /// statement is built by hand; full chunks share its text, and so its cached prepared form.
+    fn upsert_imdb_rows(conn: &mut SqliteConnection, rows: &[TraktShow]) -> QueryResult<usize> {
+        use diesel::sql_types::*;
+
+        let values = vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"; rows.len()].join(", ");
+        // on conflict, only update what the dump itself knows about: everything else
+        // (e.g. season/episode counts) comes from trakt, and a dump row has it as NULL
+        let mut query = diesel::sql_query(format!(
+            "INSERT INTO trakt_shows (imdb_id, trakt_id, primary_title, original_title, country, \
+             release_year, network, no_seasons, no_episodes, overview, user_status, \
+             status_changed_at) VALUES {} ON CONFLICT (imdb_id) DO UPDATE SET \
+             release_year = excluded.release_year",
+            values
+        ))
+        .into_boxed::<Sqlite>();
+        for row in rows {
+            query = query
+                .bind::<Text, _>(&row.imdb_id)
+                .bind::<Nullable<Integer>, _>(row.trakt_id)
+                .bind::<Text, _>(&row.primary_title)
+                .bind::<Text, _>(&row.original_title)
+                .bind::<Nullable<Text>, _>(&row.country)
+                .bind::<Nullable<Integer>, _>(row.release_year)
+                .bind::<Nullable<Text>, _>(&row.network)
+                .bind::<Nullable<Integer>, _>(row.no_seasons)
+                .bind::<Nullable<Integer>, _>(row.no_episodes)
+                .bind::<Nullable<Text>, _>(&row.overview)
+                .bind::<crate::models::UserStatusShowMapping, _>(&row.user_status)
+                .bind::<Nullable<Timestamp>, _>(row.status_changed_at);
         }
+        query.execute(conn)
+    }
 
-        info!("Inserted/Updated {} rows.", rows.len());
+    fn load_token_impl(
+        conn: &mut SqliteConnection,
+        app_id: &str,
+    ) -> eyre::Result<Option<OAuthToken>> {
+        use self::oauth_tokens::dsl::*;
 
+        oauth_tokens
+            .filter(client_id.eq(app_id))
+            .select(OAuthToken::as_select())
+            .first(conn)
+            .optional()
+            .wrap_err("could not load oauth token")
+    }
+
+    fn store_token_impl(conn: &mut SqliteConnection, token: &OAuthToken) -> eyre::Result<()> {
+        use self::oauth_tokens::dsl::*;
+
+        diesel::insert_into(oauth_tokens)
+            .values(token)
+            .on_conflict(client_id)
+            .do_update()
+            .set((
+                access_token.eq(&token.access_token),
+                refresh_token.eq(&token.refresh_token),
+                created_at.eq(&token.created_at),
+                expires_at.eq(&token.expires_at),
+            ))
+            .execute(conn)
+            .map(|_| ())
+            .wrap_err("could not store oauth token")?;
+
+        info!("Stored oauth token (expires {})", token.expires_at);
         Ok(())
     }
+
+    fn local_history_impl(conn: &mut SqliteConnection) -> eyre::Result<LocalHistory> {
+        let shows = trakt_shows::table
+            .filter(trakt_shows::user_status.eq(UserStatusShow::Watched))
+            .filter(trakt_shows::trakt_id.is_not_null())
+            .select(TraktShow::as_select())
+            .load(conn)?;
+
+        let seasons = seasons::table
+            .inner_join(trakt_shows::table)
+            .filter(seasons::user_status.ne(UserStatusSeason::Unfilled))
+            .select(TraktSeason::as_select())
+            .load(conn)?;
+
+        let episodes = episodes::table
+            .filter(episodes::watched_at.is_not_null())
+            .select(TraktEpisode::as_select())
+            .load(conn)?;
+
+        Ok(LocalHistory {
+            shows,
+            seasons,
+            episodes,
+        })
+    }
+
+    fn local_state_impl(
+        conn: &mut SqliteConnection,
+        imdb_ids: &[String],
+    ) -> eyre::Result<LocalState> {
+        let shows = trakt_shows::table
+            .filter(trakt_shows::trakt_id.is_not_null())
+            .filter(
+                trakt_shows::imdb_id
+                    .eq_any(imdb_ids)
+                    .or(trakt_shows::status_changed_at.is_not_null())
+                    .or(trakt_shows::user_status.ne(UserStatusShow::Todo))
+                    .or(trakt_shows::imdb_id.eq_any(seasons::table.select(seasons::show_imdb_id))),
+            )
+            .select(TraktShow::as_select())
+            .load(conn)?;
+
+        let seasons = seasons::table
+            .inner_join(trakt_shows::table)
+            .order_by((trakt_shows::trakt_id, seasons::season_number))
+            .select(TraktSeason::as_select())
+            .load(conn)?;
+
+        let episodes = episodes::table
+            .order_by((
+                episodes::show_id,
+                episodes::season_number,
+                episodes::episode_number,
+            ))
+            .select(TraktEpisode::as_select())
+            .load(conn)?;
+
+        Ok(LocalState {
+            shows,
+            seasons,
+            episodes,
+        })
+    }
+
+    fn synced_items_impl(conn: &mut SqliteConnection) -> eyre::Result<Vec<SyncedItem>> {
+        synced_history::table
+            .select(SyncedItem::as_select())
+            .load(conn)
+            .wrap_err("could not load synced history")
+    }
+
+    fn record_synced_impl(conn: &mut SqliteConnection, items: &[SyncedItem]) -> eyre::Result<()> {
+        use self::synced_history::dsl::*;
+
+        conn.transaction(|conn| {
+            for item in items {
+                diesel::insert_into(synced_history)
+                    .values(item)
+                    .on_conflict((kind, trakt_id))
+                    .do_update()
+                    .set((
+                        watched_at.eq(&item.watched_at),
+                        synced_at.eq(&item.synced_at),
+                        pushed.eq(item.pushed),
+                    ))
+                    .execute(conn)?;
+            }
+            Ok::<_, diesel::result::Error>(())
+        })
+        .wrap_err("could not record synced history")
+    }
+
+    fn forget_synced_impl(
+        conn: &mut SqliteConnection,
+        item_kind: SyncItemKind,
+        item_id: i32,
+    ) -> eyre::Result<()> {
+        use self::synced_history::dsl::*;
+
+        diesel::delete(synced_history.filter(kind.eq(item_kind).and(trakt_id.eq(item_id))))
+            .execute(conn)
+            .map(|_| ())
+            .wrap_err("could not forget synced history")
+    }
+
+    fn record_sync_batch_impl(
+        conn: &mut SqliteConnection,
+        pushed_at: NaiveDateTime,
+        mut items: Vec<SyncBatchItem>,
+    ) -> eyre::Result<i32> {
+        conn.transaction(|conn| {
+            let batch_id = diesel::insert_into(sync_batches::table)
+                .values(sync_batches::pushed_at.eq(pushed_at))
+                .returning(sync_batches::id)
+                .get_result(conn)?;
+
+            for item in items.iter_mut() {
+                item.batch_id = batch_id;
+            }
+            diesel::insert_into(sync_batch_items::table)
+                .values(&items)
+                .execute(conn)?;
+            Ok::<_, diesel::result::Error>(batch_id)
+        })
+        .wrap_err("could not record sync batch")
+    }
+
+    fn sync_batches_impl(conn: &mut SqliteConnection) -> eyre::Result<Vec<RecordedBatch>> {
+        let batches = sync_batches::table
+            .order_by(sync_batches::id.desc())
+            .select(SyncBatch::as_select())
+            .load(conn)?;
+        let mut items: HashMap<i32, Vec<SyncBatchItem>> = HashMap::new();
+        for item in sync_batch_items::table
+            .select(SyncBatchItem::as_select())
+            .load(conn)?
+        {
+            items.entry(item.batch_id).or_default().push(item);
+        }
+
+        Ok(batches
+            .into_iter()
+            .map(|batch| {
+                let items = items.remove(&batch.id).unwrap_or_default();
+                (batch, items)
+            })
+            .collect())
+    }
+
+    fn mark_batch_reverted_impl(
+        conn: &mut SqliteConnection,
+        batch_id: i32,
+        at: NaiveDateTime,
+    ) -> eyre::Result<()> {
+        diesel::update(sync_batches::table.filter(sync_batches::id.eq(batch_id)))
+            .set(sync_batches::reverted_at.eq(at))
+            .execute(conn)
+            .map(|_| ())
+            .wrap_err("could not mark sync batch as reverted")
+    }
+
+    fn enqueue_outbox_impl(conn: &mut SqliteConnection, item: &NewOutboxItem) -> eyre::Result<()> {
+        use self::outbox::dsl::*;
+
+        let same_item = || kind.eq(item.kind).and(trakt_id.eq(item.trakt_id));
+
+        conn.transaction(|conn| {
+            if let Some(cancelled) = item.operation.cancels() {
+                let n = diesel::delete(
+                    outbox
+                        .filter(same_item())
+                        .filter(state.eq(OutboxState::Pending))
+                        .filter(operation.eq(cancelled)),
+                )
+                .execute(conn)?;
+                debug!("Outbox: dropped {} pending {:?}", n, cancelled);
+            }
+
+            if item.operation.is_idempotent() {
+                let already_pending: i64 = outbox
+                    .filter(same_item())
+                    .filter(state.eq(OutboxState::Pending))
+                    .filter(operation.eq(item.operation))
+                    .count()
+                    .get_result(conn)?;
+                if already_pending > 0 {
+                    return Ok(());
+                }
+            }
+
+            diesel::insert_into(outbox)
+                .values(item)
+                .execute(conn)
+                .map(|_| ())
+        })
+        .wrap_err("could not queue change")
+    }
+
+    fn pending_outbox_impl(conn: &mut SqliteConnection) -> eyre::Result<Vec<OutboxItem>> {
+        outbox::table
+            .filter(outbox::state.eq(OutboxState::Pending))
+            .order_by(outbox::id)
+            .select(OutboxItem::as_select())
+            .load(conn)
+            .wrap_err("could not load outbox")
+    }
+
+    fn update_outbox_impl(conn: &mut SqliteConnection, item: &OutboxItem) -> eyre::Result<()> {
+        use self::outbox::dsl::*;
+
+        diesel::update(outbox.filter(id.eq(item.id)))
+            .set((
+                state.eq(&item.state),
+                attempts.eq(&item.attempts),
+                next_attempt_at.eq(&item.next_attempt_at),
+                last_error.eq(&item.last_error),
+            ))
+            .execute(conn)
+            .map(|_| ())
+            .wrap_err("could not update outbox")
+    }
+
+    fn count_pending_outbox_impl(conn: &mut SqliteConnection) -> eyre::Result<usize> {
+        let rows: i64 = outbox::table
+            .filter(outbox::state.eq(OutboxState::Pending))
+            .count()
+            .get_result(conn)?;
+        Ok(rows.try_into()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn season() -> TraktSeason {
+        TraktSeason {
+            id: 3950,
+            title: "Season 1".to_string(),
+            first_aired: None,
+            show_id: 1388,
+            show_imdb_id: "tt0903747".to_string(),
+            season_number: 1,
+            episode_count: 2,
+            user_status: UserStatusSeason::Unfilled,
+            watched_at: None,
+        }
+    }
+
+    fn show() -> TraktShow {
+        TraktShow {
+            imdb_id: "tt0903747".to_string(),
+            trakt_id: Some(1388),
+            primary_title: "Breaking Bad".to_string(),
+            original_title: "Breaking Bad".to_string(),
+            country: None,
+            release_year: Some(2008),
+            network: None,
+            no_seasons: None,
+            no_episodes: None,
+            overview: None,
+            user_status: UserStatusShow::Todo,
+            status_changed_at: None,
+        }
+    }
+
+    /// `season()`, stored along with its show.
+    async fn stored_season(cache: &PersistentDb) -> TraktSeason {
+        let season = season();
+        cache
+            .on_blocking_task({
+                let season = season.clone();
+                move |conn| {
+                    diesel::insert_into(trakt_shows::table)
+                        .values(&show())
+                        .execute(conn)?;
+                    diesel::insert_into(seasons::table)
+                        .values(NewTraktSeason::from(&season))
+                        .execute(conn)
+                }
+            })
+            .await
+            .unwrap();
+        season
+    }
+
+    #[test]
+    fn migrates_with_backup_and_refuses_newer_dbs() {
+        let dir = env::temp_dir().join(format!("trakt-migrate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trakt.db").to_string_lossy().into_owned();
+        let mut conn = SqliteConnection::establish(&path).unwrap();
+
+        // a db from an older build: only the first migration applied
+        let migrations = MigrationSource::<Sqlite>::migrations(&MIGRATIONS).unwrap();
+        // (sets up diesel's bookkeeping table)
+        assert!(conn.applied_migrations().unwrap().is_empty());
+        conn.run_migration(&migrations[0]).unwrap();
+        migrate(&mut conn, &path).unwrap();
+        assert!(conn.pending_migrations(MIGRATIONS).unwrap().is_empty());
+        let backups = std::fs::read_dir(&dir).unwrap().count() - 1;
+        assert_eq!(backups, 1);
+
+        // up to date: nothing to back up
+        migrate(&mut conn, &path).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count() - 1, 1);
+
+        // a db from a newer build
+        diesel::sql_query(
+            "INSERT INTO __diesel_schema_migrations (version) VALUES ('20990101000000')",
+        )
+        .execute(&mut conn)
+        .unwrap();
+        let err = migrate(&mut conn, &path).unwrap_err();
+        assert!(err.to_string().contains("20990101000000"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrating_keys_resolves_duplicate_seasons() {
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        let migrations = MigrationSource::<Sqlite>::migrations(&MIGRATIONS).unwrap();
+        let at = migrations
+            .iter()
+            .position(|m| {
+                m.name()
+                    .to_string()
+                    .ends_with("fix_show_season_episode_keys")
+            })
+            .unwrap();
+        let (older, keys) = (&migrations[..at], &migrations[at]);
+        assert!(conn.applied_migrations().unwrap().is_empty());
+        for migration in older {
+            conn.run_migration(migration).unwrap();
+        }
+
+        // breaking bad twice under one trakt id (the user only set a status on the later
+        // copy), season 1 twice (the user only set a status on the older copy), and a season
+        // of a show we don't have
+        for statement in [
+            "INSERT INTO trakt_shows (imdb_id, trakt_id, primary_title, original_title, user_status)
+             VALUES ('tt0000001', 1388, 'Breaking Bad', 'Breaking Bad', 'todo'),
+                    ('tt0903747', 1388, 'Breaking Bad', 'Breaking Bad', 'watched')",
+            "INSERT INTO seasons (id, title, show_id, season_number, episode_count, user_status)
+             VALUES (3950, 'Season 1', 1388, 1, 7, 'on_release'),
+                    (3951, 'Season 1', 1388, 1, 7, 'unfilled'),
+                    (404, 'Season 1', 404, 1, 7, 'unfilled')",
+            "INSERT INTO episodes (id, show_id, season_number, episode_number, title, user_status)
+             VALUES (73482, 1388, 1, 1, 'Pilot', 'unwatched'),
+                    (1, 404, 1, 1, 'Pilot', 'unwatched')",
+        ] {
+            diesel::sql_query(statement).execute(&mut conn).unwrap();
+        }
+        let linked = linked_shows(&mut conn).unwrap();
+        conn.run_migration(keys).unwrap();
+        let unlinked: Vec<_> = linked
+            .difference(&linked_shows(&mut conn).unwrap())
+            .cloned()
+            .collect();
+        assert_eq!(unlinked, vec!["tt0000001".to_string()]);
+
+        let stored: Vec<TraktSeason> = seasons::table
+            .inner_join(trakt_shows::table)
+            .select(TraktSeason::as_select())
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, 3950);
+        assert_eq!(stored[0].show_imdb_id, "tt0903747");
+        let stored: Vec<TraktEpisode> = episodes::table
+            .select(TraktEpisode::as_select())
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].season_id, 3950);
+    }
+
+    #[tokio::test]
+    async fn prefill_upserts_in_batches() {
+        let cache = PersistentDb::in_memory().unwrap();
+        let mut watched = show();
+        watched.user_status = UserStatusShow::Watched;
+        cache.update_show(watched).await.unwrap();
+
+        // spans several multi-row statements, and updates the show we already had
+        let rows: Vec<TraktShow> = (0..2 * PREFILL_STATEMENT_ROWS + 1)
+            .map(|i| TraktShow {
+                imdb_id: format!("tt{:07}", i),
+                trakt_id: None,
+                ..show()
+            })
+            .chain(std::iter::once(TraktShow {
+                release_year: Some(2009),
+                ..show()
+            }))
+            .collect();
+        let written = cache.prefill_from_imdb(rows).await.unwrap();
+
+        assert_eq!(written, 2 * PREFILL_STATEMENT_ROWS + 2);
+        assert_eq!(cache.count_shows().await, 2 * PREFILL_STATEMENT_ROWS + 2);
+        let updated = cache
+            .filtered_shows()
+            .await
+            .into_iter()
+            .find(|s| s.trakt_id == Some(1388))
+            .unwrap();
+        assert_eq!(updated.release_year, Some(2009));
+        assert_eq!(updated.user_status, UserStatusShow::Watched);
+    }
+
+    #[tokio::test]
+    async fn prefill_keeps_trakt_details() {
+        let cache = PersistentDb::in_memory().unwrap();
+        let enriched = TraktShow {
+            no_seasons: Some(5),
+            no_episodes: Some(62),
+            network: Some("AMC".to_string()),
+            overview: Some("A chemistry teacher ...".to_string()),
+            ..show()
+        };
+        cache.update_show(enriched.clone()).await.unwrap();
+
+        // what the IMDB dump has for the same show
+        let dump_row = TraktShow {
+            trakt_id: None,
+            no_seasons: None,
+            no_episodes: None,
+            network: None,
+            overview: None,
+            release_year: Some(2009),
+            ..show()
+        };
+        cache.prefill_from_imdb(vec![dump_row]).await.unwrap();
+
+        let stored = cache.filtered_shows().await;
+        assert_eq!(
+            stored,
+            vec![TraktShow {
+                release_year: Some(2009),
+                ..enriched
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn restoring_episodes_keeps_watch_status() {
+        let cache = PersistentDb::in_memory().unwrap();
+        let season = stored_season(&cache).await;
+        let api_episodes: Vec<ApiEpisodeDetails> = serde_json::from_str(
+            r#"[{"season":1,"number":2,"title":null,"ids":{"trakt":73483},"overview":null,"first_aired":null},
+                {"season":1,"number":1,"title":"Pilot","ids":{"trakt":73482},"overview":null,"first_aired":"2008-01-21T02:00:00.000Z"}]"#,
+        )
+        .unwrap();
+
+        let stored = cache
+            .store_episodes(season.clone(), &api_episodes)
+            .await
+            .unwrap();
+        // stored in episode order
+        assert_eq!(stored[1].title, "Episode 2");
+
+        let mut watched = stored[0].clone();
+        watched.user_status = UserStatusEpisode::Watched;
+        watched.watched_at = watched.first_aired;
+        cache.update_episode(watched.clone()).await.unwrap();
+
+        cache
+            .store_episodes(season.clone(), &api_episodes)
+            .await
+            .unwrap();
+        let episodes = cache.season_episodes(season).await.unwrap();
+        assert_eq!(episodes.len(), 2);
+        assert_eq!(episodes[0], watched);
+        assert_eq!(episodes[1].user_status, UserStatusEpisode::Unwatched);
+    }
+
+    #[tokio::test]
+    async fn on_release_stamps_aired_episodes() {
+        let cache = PersistentDb::in_memory().unwrap();
+        let mut season = stored_season(&cache).await;
+        let aired = Utc::now().naive_utc() - chrono::Duration::days(7);
+        let airs = Utc::now().naive_utc() + chrono::Duration::days(7);
+        let api_episodes: Vec<ApiEpisodeDetails> = serde_json::from_value(serde_json::json!([
+            {"season": 1, "number": 1, "title": "Aired", "ids": {"trakt": 1}, "overview": null,
+             "first_aired": Utc.from_utc_datetime(&aired)},
+            {"season": 1, "number": 2, "title": "Upcoming", "ids": {"trakt": 2}, "overview": null,
+             "first_aired": Utc.from_utc_datetime(&airs)},
+        ]))
+        .unwrap();
+
+        cache
+            .store_episodes(season.clone(), &api_episodes)
+            .await
+            .unwrap();
+
+        season.user_status = UserStatusSeason::OnRelease;
+        cache.update_season(season.clone()).await.unwrap();
+        let episodes = cache.season_episodes(season.clone()).await.unwrap();
+        assert_eq!(episodes[0].watched_at, Some(aired));
+        assert_eq!(episodes[0].user_status, UserStatusEpisode::Watched);
+        assert_eq!(episodes[1].watched_at, None);
+
+        // the upcoming episode is filled in once it airs
+        let later = airs + chrono::Duration::hours(1);
+        let marked = cache
+            .on_blocking_task(move |conn| PersistentDb::fill_released_episodes_impl(conn, later))
+            .await
+            .unwrap();
+        assert_eq!(marked, 1);
+        let episodes = cache.season_episodes(season.clone()).await.unwrap();
+        assert_eq!(episodes[1].watched_at, Some(airs));
+
+        season.user_status = UserStatusSeason::Unfilled;
+        cache.update_season(season.clone()).await.unwrap();
+        let episodes = cache.season_episodes(season).await.unwrap();
+        assert!(episodes
+            .iter()
+            .all(|e| e.watched_at.is_none() && e.user_status == UserStatusEpisode::Unwatched));
+    }
+
+    #[tokio::test]
+    async fn changing_a_season_keeps_imported_episode_dates() {
+        let cache = PersistentDb::in_memory().unwrap();
+        let mut season = stored_season(&cache).await;
+        let aired = Utc::now().naive_utc() - chrono::Duration::days(30);
+        let api_episodes: Vec<ApiEpisodeDetails> = serde_json::from_value(serde_json::json!([
+            {"season": 1, "number": 1, "title": "Pilot", "ids": {"trakt": 1}, "overview": null,
+             "first_aired": Utc.from_utc_datetime(&aired)},
+            {"season": 1, "number": 2, "title": "Second", "ids": {"trakt": 2}, "overview": null,
+             "first_aired": Utc.from_utc_datetime(&aired)},
+        ]))
+        .unwrap();
+        let stored = cache
+            .store_episodes(season.clone(), &api_episodes)
+            .await
+            .unwrap();
+
+        // the pilot was watched on a day of its own, as imported from trakt
+        let imported = aired + chrono::Duration::days(2);
+        cache
+            .update_episode(TraktEpisode {
+                user_status: UserStatusEpisode::Watched,
+                watched_at: Some(imported),
+                ..stored[0].clone()
+            })
+            .await
+            .unwrap();
+
+        let dates = |season: TraktSeason| {
+            let cache = cache.clone();
+            async move {
+                let episodes = cache.season_episodes(season).await.unwrap();
+                episodes.iter().map(|e| e.watched_at).collect::<Vec<_>>()
+            }
+        };
+
+        season.user_status = UserStatusSeason::OnRelease;
+        cache.update_season(season.clone()).await.unwrap();
+        assert_eq!(
+            dates(season.clone()).await,
+            vec![Some(imported), Some(aired)]
+        );
+
+        // picking a date, then another one, only moves what the season stamped
+        let picked = aired + chrono::Duration::days(10);
+        season.user_status = UserStatusSeason::OtherDate;
+        season.watched_at = Some(picked);
+        cache.update_season(season.clone()).await.unwrap();
+        assert_eq!(
+            dates(season.clone()).await,
+            vec![Some(imported), Some(picked)]
+        );
+
+        let repicked = picked + chrono::Duration::days(1);
+        season.watched_at = Some(repicked);
+        cache.update_season(season.clone()).await.unwrap();
+        assert_eq!(
+            dates(season.clone()).await,
+            vec![Some(imported), Some(repicked)]
+        );
+
+        season.user_status = UserStatusSeason::Unfilled;
+        season.watched_at = None;
+        cache.update_season(season.clone()).await.unwrap();
+        assert_eq!(dates(season).await, vec![Some(imported), None]);
+    }
+
+    #[tokio::test]
+    async fn seasons_and_episodes_follow_their_show() {
+        let cache = PersistentDb::in_memory().unwrap();
+        let season = stored_season(&cache).await;
+        let api_episodes: Vec<ApiEpisodeDetails> = serde_json::from_str(
+            r#"[{"season":1,"number":1,"title":"Pilot","ids":{"trakt":73482},"overview":null,"first_aired":null}]"#,
+        )
+        .unwrap();
+        cache
+            .store_episodes(season.clone(), &api_episodes)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            cache.show_seasons(show()).await.unwrap(),
+            vec![season.clone()]
+        );
+        let other_show = TraktShow {
+            imdb_id: "tt11280740".to_string(),
+            trakt_id: None,
+            ..show()
+        };
+        assert!(cache.show_seasons(other_show).await.unwrap().is_empty());
+
+        let (orphan, left) = cache
+            .on_blocking_task({
+                // a season of a show we don't have
+                let orphan = TraktSeason {
+                    id: 1,
+                    show_id: 404,
+                    show_imdb_id: "tt0000404".to_string(),
+                    ..season.clone()
+                };
+                move |conn| {
+                    let orphan = diesel::insert_into(seasons::table)
+                        .values(NewTraktSeason::from(&orphan))
+                        .execute(conn);
+
+                    diesel::delete(trakt_shows::table.find("tt0903747")).execute(conn)?;
+                    let left: i64 = episodes::table.count().get_result(conn)?;
+                    Ok::<_, diesel::result::Error>((orphan, left))
+                }
+            })
+            .await
+            .unwrap();
+        assert!(orphan.is_err());
+        // the show's seasons and episodes went with it
+        assert_eq!(left, 0);
+        assert!(cache.season_episodes(season).await.unwrap().is_empty());
+    }
 }
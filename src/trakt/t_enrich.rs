@@ -0,0 +1,287 @@
+use crate::models::TraktShow;
+use crate::trakt::t_api::{self, ApiSeasonDetails, ApiShowDetails, TraktApiError, TraktClient};
+use crate::trakt::t_auth::AuthStatus;
+use crate::trakt::t_db::{Database, PersistentDb};
+
+use std::sync::Arc;
+
+use futures::{stream, StreamExt};
+use log::*;
+use tokio::sync::watch;
+
+// shows fetched from the db at a time
+const BATCH_SIZE: i64 = 50;
+// shows being fetched from trakt at once (each needs two requests).
+// the rate limiter decides how fast they actually go out
+const CONCURRENCY: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EnrichState {
+    #[default]
+    Running,
+    Paused,
+    /// every show we know of has been looked up
+    Finished,
+}
+
+/// How far the enrichment job has got. Counts are for this run only.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnrichProgress {
+    pub state: EnrichState,
+    /// shows without trakt details when the job started
+    pub total: usize,
+    pub enriched: usize,
+    /// shows trakt doesn't have (skipped until the next run)
+    pub not_found: usize,
+    pub failed: usize,
+}
+
+impl EnrichProgress {
+    pub fn processed(&self) -> usize {
+        self.enriched + self.not_found + self.failed
+    }
+
+    pub fn percent(&self) -> u16 {
+        if self.total == 0 {
+            return 100;
+        }
+        (self.processed().min(self.total) * 100 / self.total) as u16
+    }
+}
+
+enum Outcome {
+    Enriched,
+    NotFound,
+    Failed,
+    Unauthorized,
+}
+
+/// Copy what trakt knows about a show onto our (IMDB-seeded) row.
+pub fn apply_details(
+    show: &mut TraktShow,
+    details: &ApiShowDetails,
+    api_seasons: &[ApiSeasonDetails],
+) {
+    show.trakt_id = Some(details.ids.trakt as i32);
+    show.overview = details.overview.clone();
+    show.network = details.network.clone();
+    show.country = details.country.clone();
+    show.no_episodes = Some(details.aired_episodes as i32);
+    // season 0 is specials
+    show.no_seasons = Some(api_seasons.iter().filter(|s| s.number > 0).count() as i32);
+}
+
+/// Background job that fills in trakt ids, details and seasons for every show
+/// that only has what the IMDB dump gave us.
+#[derive(Clone)]
+pub struct Enricher {
+    cache: PersistentDb,
+    paused: Arc<watch::Sender<bool>>,
+    progress: Arc<watch::Sender<EnrichProgress>>,
+}
+
+impl std::fmt::Debug for Enricher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Enricher")
+            .field("progress", &*self.progress.borrow())
+            .finish()
+    }
+}
+
+impl Enricher {
+    pub fn new(cache: PersistentDb) -> Enricher {
+        Enricher {
+            cache,
+            paused: Arc::new(watch::channel(false).0),
+            progress: Arc::new(watch::channel(EnrichProgress::default()).0),
+        }
+    }
+
+    pub fn progress(&self) -> watch::Receiver<EnrichProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Stop starting new lookups. Ones already in flight still finish.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+        self.set_state(EnrichState::Paused);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+        self.set_state(EnrichState::Running);
+    }
+
+    pub fn toggle(&self) {
+        if *self.paused.borrow() {
+            self.resume();
+        } else {
+            self.pause();
+        }
+    }
+
+    fn set_state(&self, state: EnrichState) {
+        self.progress.send_modify(|p| {
+            if p.state != EnrichState::Finished {
+                p.state = state;
+            }
+        });
+    }
+
+    async fn wait_while_paused(&self) {
+        let _ = self.paused.subscribe().wait_for(|paused| !paused).await;
+    }
+
+    /// Start enriching in the background.
+    pub fn spawn(&self, client: TraktClient) {
+        let enricher = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = enricher.run(&client).await {
+                error!("Enricher: {}", e);
+            }
+        });
+    }
+
+    async fn run(&self, client: &TraktClient) -> eyre::Result<()> {
+        let total = self.cache.count_unenriched_shows().await?;
+        self.progress.send_modify(|p| p.total = total);
+        info!("Enricher: {} shows without trakt details", total);
+
+        // shows that were found get a trakt id and drop out of the query, so the cursor
+        // only has to step over the ones trakt didn't have
+        let mut after = None;
+        loop {
+            self.wait_while_paused().await;
+            let _ = client
+                .auth
+                .status()
+                .wait_for(|s| *s == AuthStatus::LoggedIn)
+                .await;
+
+            let batch = self
+                .cache
+                .unenriched_shows(after.clone(), BATCH_SIZE)
+                .await?;
+            let Some(last) = batch.last() else {
+                break;
+            };
+            let last = last.imdb_id.clone();
+
+            let outcomes: Vec<Outcome> = stream::iter(batch)
+                .map(|show| self.enrich(client, show))
+                .buffer_unordered(CONCURRENCY)
+                .collect()
+                .await;
+
+            let mut unauthorized = false;
+            self.progress.send_modify(|p| {
+                for outcome in &outcomes {
+                    match outcome {
+                        Outcome::Enriched => p.enriched += 1,
+                        Outcome::NotFound => p.not_found += 1,
+                        Outcome::Failed => p.failed += 1,
+                        Outcome::Unauthorized => unauthorized = true,
+                    }
+                }
+            });
+
+            if unauthorized {
+                // go over this batch again once we're logged in (and the user resumes)
+                warn!("Enricher: not authorized, pausing");
+                self.pause();
+            } else {
+                after = Some(last);
+            }
+        }
+
+        info!("Enricher: finished {:?}", *self.progress.borrow());
+        self.progress
+            .send_modify(|p| p.state = EnrichState::Finished);
+        Ok(())
+    }
+
+    async fn enrich(&self, client: &TraktClient, mut show: TraktShow) -> Outcome {
+        self.wait_while_paused().await;
+
+        let (details, api_seasons) = match t_api::query_detailed(client, &show.imdb_id).await {
+            Ok(found) => found,
+            Err(TraktApiError::NotFound) => {
+                debug!("Enricher: {} isn't on trakt", show.imdb_id);
+                return Outcome::NotFound;
+            }
+            Err(TraktApiError::Unauthorized) => return Outcome::Unauthorized,
+            Err(e) => {
+                warn!("Enricher: could not look up {}: {}", show.imdb_id, e);
+                return Outcome::Failed;
+            }
+        };
+
+        apply_details(&mut show, &details, &api_seasons);
+        match self.cache.store_show_details(show, &api_seasons).await {
+            Ok(_) => Outcome::Enriched,
+            Err(e) => {
+                error!("Enricher: {}", e);
+                Outcome::Failed
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::UserStatusShow;
+
+    #[test]
+    fn applies_details() {
+        let details: ApiShowDetails = serde_json::from_str(
+            r#"{"title":"Severance","year":2022,"ids":{"trakt":154997,"slug":"severance","imdb":"tt11280740"},
+                "overview":"Mark leads a team of office workers...","first_aired":"2022-02-18T02:00:00.000Z",
+                "network":"Apple TV+","country":"us","aired_episodes":19}"#,
+        )
+        .unwrap();
+        let seasons: Vec<ApiSeasonDetails> = serde_json::from_str(
+            r#"[{"number":0,"ids":{"trakt":1},"episode_count":1,"title":"Specials","first_aired":null,"network":null},
+                {"number":1,"ids":{"trakt":2},"episode_count":9,"title":"Season 1","first_aired":"2022-02-18T02:00:00.000Z","network":"Apple TV+"},
+                {"number":2,"ids":{"trakt":3},"episode_count":10,"title":"Season 2","first_aired":null,"network":"Apple TV+"}]"#,
+        )
+        .unwrap();
+
+        let mut show = TraktShow {
+            imdb_id: "tt11280740".to_string(),
+            trakt_id: None,
+            primary_title: "Severance".to_string(),
+            original_title: "Severance".to_string(),
+            country: None,
+            release_year: Some(2022),
+            network: None,
+            no_seasons: None,
+            no_episodes: None,
+            overview: None,
+            user_status: UserStatusShow::Todo,
+            status_changed_at: None,
+        };
+        apply_details(&mut show, &details, &seasons);
+
+        assert_eq!(show.trakt_id, Some(154997));
+        assert_eq!(show.network.as_deref(), Some("Apple TV+"));
+        assert_eq!(show.country.as_deref(), Some("us"));
+        assert_eq!(show.no_seasons, Some(2));
+        assert_eq!(show.no_episodes, Some(19));
+    }
+
+    #[test]
+    fn progress_percent() {
+        let mut progress = EnrichProgress {
+            total: 200,
+            ..Default::default()
+        };
+        assert_eq!(progress.percent(), 0);
+
+        progress.enriched = 40;
+        progress.not_found = 10;
+        assert_eq!(progress.percent(), 25);
+
+        assert_eq!(EnrichProgress::default().percent(), 100);
+    }
+}
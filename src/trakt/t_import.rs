@@ -0,0 +1,684 @@
+use crate::models::{
+    ActivityCategory, Rating, SyncActivity, SyncItemKind, SyncedItem, TraktEpisode, TraktSeason,
+    TraktShow, UserStatusEpisode, UserStatusSeason, UserStatusShow,
+};
+use crate::trakt::t_api::{
+    self, ApiSeasonDetails, ApiShow, ApiWatchedShow, TraktApiError, TraktClient,
+};
+use crate::trakt::t_db::{self, Database, PersistentDb};
+use crate::trakt::t_enrich;
+use crate::trakt::t_outbox::{Outbox, ShowList};
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{NaiveDateTime, Utc};
+use log::*;
+
+/// What trakt says the user has watched of one show, as rows for the local db.
+#[derive(Clone, Debug)]
+pub struct WatchedHistory {
+    /// `Watched` if every aired episode has been watched
+    pub show: TraktShow,
+    /// fully watched seasons are `OtherDate`, at their last episode's watch date
+    pub seasons: Vec<TraktSeason>,
+    pub episodes: Vec<TraktEpisode>,
+    /// everything above that's watched, so pushing history doesn't send it back
+    pub synced: Vec<SyncedItem>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub shows: usize,
+    pub watched_shows: usize,
+    pub episodes: usize,
+    /// shows trakt has no IMDB id for (we key shows by it)
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// What an incremental pull brought in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PullSummary {
+    /// categories that had changed on trakt since the last pull
+    pub pulled: Vec<ActivityCategory>,
+    pub watched: Option<ImportSummary>,
+    /// local shows changed to match the watchlist
+    pub watchlisted: usize,
+    /// local shows changed to match the hidden shows
+    pub hidden: usize,
+    pub ratings: usize,
+}
+
+/// How to bring a show's status and one of trakt's lists back in line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListChange {
+    /// trakt changed last: store this locally
+    Local(TraktShow),
+    /// we changed last: put the show on the list, or take it off
+    Remote { trakt_id: i32, listed: bool },
+}
+
+/// A show from one of the user's lists, as a row. `None` if it has no IMDB id.
+fn listed_show(
+    api_show: &ApiShow,
+    user_status: UserStatusShow,
+    status_changed_at: Option<NaiveDateTime>,
+) -> Option<TraktShow> {
+    Some(TraktShow {
+        imdb_id: api_show.ids.imdb.clone()?,
+        trakt_id: Some(api_show.ids.trakt as i32),
+        primary_title: api_show.title.clone(),
+        original_title: api_show.title.clone(),
+        country: None,
+        release_year: api_show.year.map(|y| y as i32),
+        network: None,
+        no_seasons: None,
+        no_episodes: None,
+        overview: None,
+        user_status,
+        status_changed_at,
+    })
+}
+
+/// Compare one of trakt's lists with our shows of the matching status (see [`ShowList`]),
+/// and let whichever side changed last win. Listed shows are compared by when they were
+/// listed; shows missing from the list by when it last changed at all (`updated_at`).
+pub fn merge_list(
+    list: ShowList,
+    local: &[TraktShow],
+    listed: &[(&ApiShow, NaiveDateTime)],
+    updated_at: NaiveDateTime,
+) -> Vec<ListChange> {
+    let status = list.status();
+    let by_id: HashMap<&str, &TraktShow> = local.iter().map(|s| (s.imdb_id.as_str(), s)).collect();
+    let mut changes = vec![];
+    let mut on_list = HashSet::new();
+
+    for &(api_show, listed_at) in listed {
+        let Some(show) = listed_show(api_show, status.clone(), Some(listed_at)) else {
+            continue;
+        };
+        on_list.insert(show.imdb_id.clone());
+
+        let Some(&existing) = by_id.get(show.imdb_id.as_str()) else {
+            changes.push(ListChange::Local(show));
+            continue;
+        };
+        if existing.user_status == status {
+            // already there, but remember that it's on purpose
+            if existing.status_changed_at.is_none() {
+                changes.push(ListChange::Local(TraktShow {
+                    status_changed_at: Some(listed_at),
+                    ..existing.clone()
+                }));
+            }
+            continue;
+        }
+
+        match existing.status_changed_at {
+            // watched according to trakt's own history, which lists don't override
+            None if existing.user_status == UserStatusShow::Watched => {}
+            Some(changed_at) if changed_at > listed_at => changes.push(ListChange::Remote {
+                trakt_id: api_show.ids.trakt as i32,
+                listed: false,
+            }),
+            _ => changes.push(ListChange::Local(TraktShow {
+                trakt_id: existing.trakt_id.or(show.trakt_id),
+                user_status: status.clone(),
+                status_changed_at: Some(listed_at),
+                ..existing.clone()
+            })),
+        }
+    }
+
+    for show in local {
+        let Some(changed_at) = show.status_changed_at else {
+            continue;
+        };
+        if show.user_status != status || on_list.contains(&show.imdb_id) {
+            continue;
+        }
+
+        if changed_at > updated_at {
+            if let Some(trakt_id) = show.trakt_id {
+                changes.push(ListChange::Remote {
+                    trakt_id,
+                    listed: true,
+                });
+            }
+        } else {
+            // taken off the list on trakt: back to a show we haven't decided on
+            changes.push(ListChange::Local(TraktShow {
+                user_status: UserStatusShow::Todo,
+                status_changed_at: None,
+                ..show.clone()
+            }));
+        }
+    }
+
+    changes
+}
+
+/// Map a show's watch history onto our rows. `None` if the show has no IMDB id.
+pub fn watched_history(
+    watched: &ApiWatchedShow,
+    api_seasons: &[ApiSeasonDetails],
+    now: NaiveDateTime,
+) -> Option<WatchedHistory> {
+    let details = &watched.show;
+    let imdb_id = details.ids.imdb.clone()?;
+
+    let mut show = TraktShow {
+        imdb_id,
+        trakt_id: None,
+        primary_title: details.title.clone(),
+        original_title: details.title.clone(),
+        country: None,
+        release_year: details.year.map(|y| y as i32),
+        network: None,
+        no_seasons: None,
+        no_episodes: None,
+        overview: None,
+        user_status: UserStatusShow::Todo,
+        status_changed_at: None,
+    };
+    t_enrich::apply_details(&mut show, details, api_seasons);
+
+    // (season, episode) -> last watched
+    let plays: HashMap<(usize, usize), Option<NaiveDateTime>> = watched
+        .seasons
+        .iter()
+        .flat_map(|s| {
+            s.episodes.iter().map(|e| {
+                (
+                    (s.number, e.number),
+                    e.last_watched_at.map(|d| d.naive_utc()),
+                )
+            })
+        })
+        .collect();
+
+    let mut seasons = t_db::seasons_from_api(&show, api_seasons);
+    let mut episodes = vec![];
+    let mut synced = vec![];
+    let mut all_watched = true;
+
+    for (season, api_season) in seasons.iter_mut().zip(api_seasons) {
+        let mut season_episodes = t_db::episodes_from_api(season, &api_season.episodes);
+        let mut aired = 0;
+        let mut watched_aired = 0;
+        let mut last_watched = None;
+
+        for episode in season_episodes.iter_mut() {
+            let has_aired = episode.first_aired.is_some_and(|d| d <= now);
+            aired += has_aired as usize;
+
+            let key = (
+                episode.season_number as usize,
+                episode.episode_number as usize,
+            );
+            let Some(watched_at) = plays.get(&key) else {
+                continue;
+            };
+            watched_aired += has_aired as usize;
+            // trakt always sends a date, but fall back to the air date just in case
+            episode.watched_at = watched_at.or(episode.first_aired);
+            episode.user_status = UserStatusEpisode::Watched;
+            last_watched = last_watched.max(episode.watched_at);
+            synced.push(SyncedItem {
+                kind: SyncItemKind::Episode,
+                trakt_id: episode.id,
+                watched_at: episode.watched_at,
+                synced_at: now,
+                pushed: false,
+            });
+        }
+
+        if aired > 0 && watched_aired == aired {
+            season.user_status = UserStatusSeason::OtherDate;
+            season.watched_at = last_watched;
+            synced.push(SyncedItem {
+                kind: SyncItemKind::Season,
+                trakt_id: season.id,
+                watched_at: last_watched,
+                synced_at: now,
+                pushed: false,
+            });
+        } else if season.season_number > 0 && aired > 0 {
+            // specials don't count towards having watched the show
+            all_watched = false;
+        }
+        episodes.extend(season_episodes);
+    }
+
+    if all_watched && !episodes.is_empty() {
+        show.user_status = UserStatusShow::Watched;
+        synced.push(SyncedItem {
+            kind: SyncItemKind::Show,
+            trakt_id: show.trakt_id.unwrap(),
+            watched_at: None,
+            synced_at: now,
+            pushed: false,
+        });
+    }
+
+    Some(WatchedHistory {
+        show,
+        seasons,
+        episodes,
+        synced,
+    })
+}
+
+/// Pull everything the user has watched from trakt into the local db.
+pub async fn pull_watched(
+    client: &TraktClient,
+    cache: &PersistentDb,
+) -> eyre::Result<ImportSummary> {
+    let watched_shows = t_api::query_watched_shows(client).await?;
+    info!("Importing {} watched shows from trakt", watched_shows.len());
+
+    let mut summary = ImportSummary::default();
+    for watched in watched_shows.iter() {
+        let trakt_id = watched.show.ids.trakt;
+        if watched.show.ids.imdb.is_none() {
+            debug!("Import: {} has no IMDB id", watched.show.title);
+            summary.skipped += 1;
+            continue;
+        }
+
+        let api_seasons =
+            match t_api::query_seasons_with_episodes(client, &trakt_id.to_string()).await {
+                Ok(seasons) => seasons,
+                // nothing else will work either
+                Err(e @ TraktApiError::Unauthorized) => return Err(e.into()),
+                Err(e) => {
+                    warn!(
+                        "Import: could not get seasons of {}: {}",
+                        watched.show.title, e
+                    );
+                    summary.failed += 1;
+                    continue;
+                }
+            };
+
+        let Some(history) = watched_history(watched, &api_seasons, Utc::now().naive_utc()) else {
+            continue;
+        };
+        summary.shows += 1;
+        summary.watched_shows += (history.show.user_status == UserStatusShow::Watched) as usize;
+        summary.episodes += history
+            .episodes
+            .iter()
+            .filter(|e| e.user_status == UserStatusEpisode::Watched)
+            .count();
+        cache.import_watched(history).await?;
+    }
+
+    info!("Imported watch history: {:?}", summary);
+    Ok(summary)
+}
+
+/// Merge one of trakt's lists into our shows (see [`merge_list`]), queueing our side
+/// of any show we changed more recently. Returns how many local shows changed.
+pub async fn pull_list(
+    client: &TraktClient,
+    cache: &PersistentDb,
+    outbox: &Outbox,
+    list: ShowList,
+    updated_at: NaiveDateTime,
+) -> eyre::Result<usize> {
+    let listed: Vec<(ApiShow, NaiveDateTime)> = match list {
+        ShowList::Watchlist => t_api::query_watchlist(client)
+            .await?
+            .into_iter()
+            .map(|l| (l.show, l.listed_at.naive_utc()))
+            .collect(),
+        ShowList::Hidden => t_api::query_hidden(client)
+            .await?
+            .into_iter()
+            .map(|h| (h.show, h.hidden_at.naive_utc()))
+            .collect(),
+    };
+    let listed: Vec<(&ApiShow, NaiveDateTime)> = listed.iter().map(|(s, at)| (s, *at)).collect();
+
+    let imdb_ids = listed
+        .iter()
+        .filter_map(|(s, _)| s.ids.imdb.clone())
+        .collect();
+    let local = cache.shows_to_merge(imdb_ids).await?;
+
+    let mut changed = 0;
+    for change in merge_list(list, &local, &listed, updated_at) {
+        match change {
+            ListChange::Local(show) => {
+                cache.update_show(show).await?;
+                changed += 1;
+            }
+            ListChange::Remote { trakt_id, listed } => {
+                outbox.list_changed(list, trakt_id, listed).await?
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// Replace the stored ratings with the user's ratings on trakt. Returns how many there are.
+pub async fn pull_ratings(client: &TraktClient, cache: &PersistentDb) -> eyre::Result<usize> {
+    let ratings: Vec<Rating> = t_api::query_ratings(client)
+        .await?
+        .iter()
+        .filter_map(|r| {
+            let (kind, trakt_id) = r.item()?;
+            Some(Rating {
+                kind,
+                trakt_id: trakt_id as i32,
+                rating: r.rating as i32,
+                rated_at: r.rated_at.naive_utc(),
+            })
+        })
+        .collect();
+
+    let count = ratings.len();
+    cache.replace_ratings(ratings).await?;
+    Ok(count)
+}
+
+/// Ask trakt what changed since the last pull, and only pull those categories
+/// (everything if `force` is set). Each category's activity time is stored once it's pulled.
+pub async fn pull_changes(
+    client: &TraktClient,
+    cache: &PersistentDb,
+    outbox: &Outbox,
+    force: bool,
+) -> eyre::Result<PullSummary> {
+    let activities = t_api::query_last_activities(client).await?;
+    let seen: HashMap<ActivityCategory, NaiveDateTime> = cache
+        .sync_activities()
+        .await?
+        .into_iter()
+        .map(|a| (a.category, a.updated_at))
+        .collect();
+
+    let mut summary = PullSummary::default();
+    for category in ActivityCategory::ALL {
+        // nothing has ever happened in this category
+        let Some(updated_at) = activities.updated_at(category).map(|d| d.naive_utc()) else {
+            continue;
+        };
+        if !force && seen.get(&category).is_some_and(|s| *s >= updated_at) {
+            debug!("{:?} unchanged since {}", category, updated_at);
+            continue;
+        }
+
+        let complete = match category {
+            ActivityCategory::Watched => {
+                let watched = pull_watched(client, cache).await?;
+                // try the shows that failed again next time
+                let complete = watched.failed == 0;
+                summary.watched = Some(watched);
+                complete
+            }
+            ActivityCategory::Watchlist => {
+                summary.watchlisted =
+                    pull_list(client, cache, outbox, ShowList::Watchlist, updated_at).await?;
+                true
+            }
+            ActivityCategory::Ratings => {
+                summary.ratings = pull_ratings(client, cache).await?;
+                true
+            }
+            ActivityCategory::Hidden => {
+                summary.hidden =
+                    pull_list(client, cache, outbox, ShowList::Hidden, updated_at).await?;
+                true
+            }
+        };
+
+        summary.pulled.push(category);
+        if complete {
+            cache
+                .record_activity(SyncActivity {
+                    category,
+                    updated_at,
+                })
+                .await?;
+        }
+    }
+
+    info!("Pulled from trakt: {:?}", summary);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trakt::t_mock::start_logged_in;
+    use crate::trakt::t_sync;
+
+    #[tokio::test]
+    async fn imports_watched_shows() {
+        let (_mock, cache, client) = start_logged_in().await;
+
+        let summary = pull_watched(&client, &cache).await.unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                shows: 2,
+                watched_shows: 1,
+                episodes: 29,
+                skipped: 0,
+                failed: 0,
+            }
+        );
+
+        let shows = cache.filtered_shows().await;
+        let status = |imdb_id: &str| {
+            shows
+                .iter()
+                .find(|s| s.imdb_id == imdb_id)
+                .map(|s| s.user_status.clone())
+        };
+        // severance is all caught up, breaking bad is in progress
+        assert_eq!(status("tt11280740"), Some(UserStatusShow::Watched));
+        assert_eq!(status("tt0903747"), Some(UserStatusShow::Todo));
+
+        let history = cache.local_history().await.unwrap();
+        let season_one = history.seasons.iter().find(|s| s.id == 3950).unwrap();
+        assert_eq!(season_one.user_status, UserStatusSeason::OtherDate);
+        assert_eq!(
+            season_one.watched_at.unwrap().to_string(),
+            "2019-03-07 21:00:00"
+        );
+        assert_eq!(history.episodes.len(), 29);
+
+        // none of it gets pushed back to trakt
+        let synced = cache.synced_items().await.unwrap();
+        assert!(t_sync::history_payloads(&history, &synced).is_empty());
+
+        // fetching the season's episodes again keeps the dates they were watched at
+        let dates = |episodes: &[TraktEpisode]| {
+            episodes
+                .iter()
+                .filter(|e| e.season_id == season_one.id)
+                .map(|e| (e.id, e.watched_at))
+                .collect::<Vec<_>>()
+        };
+        let imported = dates(&history.episodes);
+        let api_episodes = t_api::query_episodes(&client, "1388", 1).await.unwrap();
+        let stored = cache
+            .store_episodes(season_one.clone(), &api_episodes)
+            .await
+            .unwrap();
+        assert_eq!(dates(&stored), imported);
+    }
+
+    #[tokio::test]
+    async fn pulls_only_changed_categories() {
+        let (mock, cache, client) = start_logged_in().await;
+        let outbox = Outbox::new(cache.clone());
+
+        let summary = pull_changes(&client, &cache, &outbox, false).await.unwrap();
+        assert_eq!(summary.pulled, ActivityCategory::ALL);
+        assert_eq!(summary.watched.unwrap().shows, 2);
+        assert_eq!(summary.watchlisted, 2);
+        assert_eq!(summary.hidden, 1);
+        // the movie rating is skipped
+        assert_eq!(summary.ratings, 3);
+
+        let shows = cache.filtered_shows().await;
+        let the_bear = shows.iter().find(|s| s.imdb_id == "tt14452776").unwrap();
+        assert_eq!(the_bear.user_status, UserStatusShow::Todo);
+        // unwatched shows are left out of the list
+        assert!(!shows.iter().any(|s| s.imdb_id == "tt1520211"));
+
+        // nothing changed on trakt since
+        let summary = pull_changes(&client, &cache, &outbox, false).await.unwrap();
+        assert!(summary.pulled.is_empty());
+
+        // pushing history bumps trakt's watched activity
+        let payload = t_sync::HistoryPayload::episode(73489, None);
+        t_api::do_post(&client, "sync/history", &payload)
+            .await
+            .unwrap();
+        let summary = pull_changes(&client, &cache, &outbox, false).await.unwrap();
+        assert_eq!(summary.pulled, vec![ActivityCategory::Watched]);
+
+        // breaking bad goes on the watchlist, and comes back as a deliberate Todo
+        outbox
+            .list_changed(ShowList::Watchlist, 1388, true)
+            .await
+            .unwrap();
+        outbox.drain(&client).await.unwrap();
+        let summary = pull_changes(&client, &cache, &outbox, false).await.unwrap();
+        assert_eq!(summary.pulled, vec![ActivityCategory::Watchlist]);
+        assert_eq!(summary.watchlisted, 1);
+        let changed = cache.shows_to_merge(vec![]).await.unwrap();
+        assert!(changed.iter().any(|s| s.imdb_id == "tt0903747"));
+
+        // then it's hidden on trakt (from both sections), so we mark it Unwatched
+        outbox
+            .list_changed(ShowList::Hidden, 1388, true)
+            .await
+            .unwrap();
+        outbox.drain(&client).await.unwrap();
+        let summary = pull_changes(&client, &cache, &outbox, false).await.unwrap();
+        assert_eq!(summary.pulled, vec![ActivityCategory::Hidden]);
+        assert_eq!(summary.hidden, 1);
+        let changed = cache.shows_to_merge(vec![]).await.unwrap();
+        let breaking_bad = changed.iter().find(|s| s.imdb_id == "tt0903747").unwrap();
+        assert_eq!(breaking_bad.user_status, UserStatusShow::Unwatched);
+        assert_eq!(
+            mock.requests()
+                .iter()
+                .filter(|r| r.starts_with("POST /users/hidden/"))
+                .count(),
+            2
+        );
+
+        let summary = pull_changes(&client, &cache, &outbox, true).await.unwrap();
+        assert_eq!(summary.pulled, ActivityCategory::ALL);
+    }
+
+    fn show(imdb_id: &str, user_status: UserStatusShow, changed: Option<u32>) -> TraktShow {
+        TraktShow {
+            imdb_id: imdb_id.to_string(),
+            trakt_id: Some(imdb_id[2..].parse().unwrap()),
+            primary_title: imdb_id.to_string(),
+            original_title: imdb_id.to_string(),
+            country: None,
+            release_year: Some(2020),
+            network: None,
+            no_seasons: None,
+            no_episodes: None,
+            overview: None,
+            user_status,
+            status_changed_at: changed.map(day),
+        }
+    }
+
+    fn day(d: u32) -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(2023, 8, d)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    }
+
+    fn api_show(imdb_id: &str) -> ApiShow {
+        ApiShow {
+            title: imdb_id.to_string(),
+            year: Some(2020),
+            ids: t_api::ApiIDs {
+                trakt: imdb_id[2..].parse().unwrap(),
+                slug: None,
+                imdb: Some(imdb_id.to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn merges_watchlist_by_last_change() {
+        let local = vec![
+            // watched after it was listed: we win
+            show("tt01", UserStatusShow::Watched, Some(5)),
+            // hidden before it was listed: trakt wins
+            show("tt02", UserStatusShow::Unwatched, Some(1)),
+            // never decided on, and listed on trakt
+            show("tt03", UserStatusShow::Todo, None),
+            // set to Todo here after the watchlist last changed
+            show("tt04", UserStatusShow::Todo, Some(9)),
+            // set to Todo here, then taken off the watchlist on trakt
+            show("tt05", UserStatusShow::Todo, Some(2)),
+        ];
+        let shows = ["tt01", "tt02", "tt03", "tt06"].map(api_show);
+        let watchlist: Vec<_> = shows.iter().map(|s| (s, day(3))).collect();
+
+        let changes = merge_list(ShowList::Watchlist, &local, &watchlist, day(7));
+        assert_eq!(
+            changes,
+            vec![
+                ListChange::Remote {
+                    trakt_id: 1,
+                    listed: false
+                },
+                ListChange::Local(show("tt02", UserStatusShow::Todo, Some(3))),
+                ListChange::Local(show("tt03", UserStatusShow::Todo, Some(3))),
+                ListChange::Local(show("tt06", UserStatusShow::Todo, Some(3))),
+                ListChange::Remote {
+                    trakt_id: 4,
+                    listed: true
+                },
+                ListChange::Local(show("tt05", UserStatusShow::Todo, None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_hidden_shows() {
+        let local = vec![
+            // watched according to trakt's history
+            show("tt01", UserStatusShow::Watched, None),
+            // on the watchlist before it was hidden
+            show("tt02", UserStatusShow::Todo, Some(1)),
+            // hidden here after the hidden shows last changed
+            show("tt03", UserStatusShow::Unwatched, Some(9)),
+            // hidden here, then unhidden on trakt
+            show("tt04", UserStatusShow::Unwatched, Some(2)),
+        ];
+        let shows = ["tt01", "tt02"].map(api_show);
+        let hidden: Vec<_> = shows.iter().map(|s| (s, day(3))).collect();
+
+        let changes = merge_list(ShowList::Hidden, &local, &hidden, day(7));
+        assert_eq!(
+            changes,
+            vec![
+                ListChange::Local(show("tt02", UserStatusShow::Unwatched, Some(3))),
+                ListChange::Remote {
+                    trakt_id: 3,
+                    listed: true
+                },
+                ListChange::Local(show("tt04", UserStatusShow::Todo, None)),
+            ]
+        );
+    }
+}
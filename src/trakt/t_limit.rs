@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use log::*;
+use nonzero_ext::*;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+// trakt allows 1000 calls per 5 minutes. stay a bit under that: 3/sec -> 900 per 5min
+pub const RATE_LIMIT: u32 = 3u32;
+// POST/PUT/DELETE are additionally limited to 1/sec
+pub const WRITE_RATE_LIMIT: u32 = 1u32;
+
+pub const BUDGET: u32 = 1000;
+pub const BUDGET_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(1);
+// cap on exponential backoff (a Retry-After from trakt is always honoured)
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+// X-Ratelimit header, e.g.
+// {"name":"AUTHED_API_GET_LIMIT","period":300,"limit":1000,"remaining":998,"until":"..."}
+#[derive(Serialize, Deserialize, Debug)]
+struct RateLimitHeader {
+    limit: u32,
+    remaining: u32,
+}
+
+/// Snapshot of how much of trakt's request budget we've used.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RateUsage {
+    /// requests sent in the last `BUDGET_WINDOW`
+    pub in_window: u32,
+    pub budget: u32,
+    /// what trakt itself last reported as remaining (via X-Ratelimit)
+    pub remaining: Option<u32>,
+    pub total: u64,
+    pub retries: u64,
+    pub rate_limited: u64,
+}
+
+/// Shared limiter for every request to trakt, plus counters of what went through it.
+pub struct TraktLimiter {
+    reads: DefaultDirectRateLimiter,
+    writes: DefaultDirectRateLimiter,
+
+    total: AtomicU64,
+    retries: AtomicU64,
+    rate_limited: AtomicU64,
+    sent: Mutex<VecDeque<Instant>>,
+    remaining: Mutex<Option<u32>>,
+}
+
+impl std::fmt::Debug for TraktLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TraktLimiter")
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
+
+impl Default for TraktLimiter {
+    fn default() -> Self {
+        Self::new(nonzero!(RATE_LIMIT), nonzero!(WRITE_RATE_LIMIT))
+    }
+}
+
+impl TraktLimiter {
+    pub fn new(per_second: NonZeroU32, writes_per_second: NonZeroU32) -> TraktLimiter {
+        TraktLimiter {
+            reads: RateLimiter::direct(Quota::per_second(per_second)),
+            writes: RateLimiter::direct(Quota::per_second(writes_per_second)),
+            total: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            sent: Mutex::new(VecDeque::new()),
+            remaining: Mutex::new(None),
+        }
+    }
+
+    /// Wait until we're allowed to send another request.
+    pub async fn until_ready(&self, is_write: bool) {
+        if is_write {
+            self.writes.until_ready().await;
+        }
+        self.reads.until_ready().await;
+
+        self.total.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut sent = self.sent.lock().unwrap();
+        sent.push_back(now);
+        while sent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > BUDGET_WINDOW)
+        {
+            sent.pop_front();
+        }
+    }
+
+    /// Pick up trakt's own view of our budget from a response.
+    pub fn observe(&self, headers: &HeaderMap) {
+        let header = headers
+            .get("x-ratelimit")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| serde_json::from_str::<RateLimitHeader>(v).ok());
+
+        if let Some(header) = header {
+            debug!(
+                "trakt rate limit: {}/{} remaining",
+                header.remaining, header.limit
+            );
+            *self.remaining.lock().unwrap() = Some(header.remaining);
+        }
+    }
+
+    pub fn record_retry(&self, rate_limited: bool) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        if rate_limited {
+            self.rate_limited.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn usage(&self) -> RateUsage {
+        let now = Instant::now();
+        let in_window = self
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| now.duration_since(**t) <= BUDGET_WINDOW)
+            .count();
+
+        RateUsage {
+            in_window: in_window as u32,
+            budget: BUDGET,
+            remaining: *self.remaining.lock().unwrap(),
+            total: self.total.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How long to wait before retry number `attempt` (starting at 0): exponential backoff,
+/// or whatever trakt asked for, plus up to 50% random jitter so parallel requests spread out.
+pub fn retry_delay(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    let base = retry_after
+        .unwrap_or_else(|| (BASE_RETRY_DELAY * 2u32.pow(attempt.min(16))).min(MAX_RETRY_DELAY));
+
+    base + base.mul_f64(jitter_fraction() * 0.5)
+}
+
+// cheap randomness in [0, 1): good enough to de-synchronize retries
+fn jitter_fraction() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    f64::from(nanos % 1000) / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_rate_limit() {
+        let total = RATE_LIMIT * BUDGET_WINDOW.as_secs() as u32;
+        assert!(total < BUDGET);
+    }
+
+    #[test]
+    fn retry_delay_backs_off() {
+        let first = retry_delay(0, None);
+        assert!(first >= Duration::from_secs(1) && first <= Duration::from_millis(1500));
+
+        let third = retry_delay(2, None);
+        assert!(third >= Duration::from_secs(4) && third <= Duration::from_secs(6));
+
+        assert!(retry_delay(30, None) <= MAX_RETRY_DELAY.mul_f64(1.5));
+
+        let asked = retry_delay(0, Some(Duration::from_secs(120)));
+        assert!(asked >= Duration::from_secs(120));
+    }
+
+    #[tokio::test]
+    async fn counts_requests() {
+        let limiter = TraktLimiter::new(nonzero!(100u32), nonzero!(100u32));
+        for _ in 0..3 {
+            limiter.until_ready(false).await;
+        }
+        limiter.record_retry(true);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-ratelimit",
+            r#"{"name":"AUTHED_API_GET_LIMIT","period":300,"limit":1000,"remaining":997,"until":"2023-08-01T00:00:00Z"}"#
+                .parse()
+                .unwrap(),
+        );
+        limiter.observe(&headers);
+
+        let usage = limiter.usage();
+        assert_eq!(usage.in_window, 3);
+        assert_eq!(usage.total, 3);
+        assert_eq!(usage.retries, 1);
+        assert_eq!(usage.rate_limited, 1);
+        assert_eq!(usage.remaining, Some(997));
+    }
+}
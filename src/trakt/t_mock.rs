@@ -0,0 +1,960 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use log::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+#[cfg(test)]
+use crate::config::TraktConfig;
+#[cfg(test)]
+use crate::trakt::{t_api::TraktClient, t_db::PersistentDb};
+
+// the responses i copied over from trakt, keyed by show
+const FIXTURES: &str = include_str!("../../fixtures/trakt/shows.json");
+// the mock user's watch history
+const WATCHED: &str = include_str!("../../fixtures/trakt/watched_shows.json");
+// the mock user's activity times, watchlist, hidden shows and ratings
+const USER_LISTS: &str = include_str!("../../fixtures/trakt/user_lists.json");
+// the lists in `USER_LISTS` that can be changed
+const USER_LIST_NAMES: &[&str] = &[
+    "watchlist",
+    "hidden_progress_watched",
+    "hidden_recommendations",
+];
+
+// imdb ids that always fail the same way, so error handling can be tried out from the app
+const SCRIPTED_IDS: &[(&str, u16)] = &[
+    ("tt0000401", 401),
+    ("tt0000404", 404),
+    ("tt0000429", 429),
+    ("tt0000500", 500),
+    ("tt0000502", 502),
+];
+
+// Retry-After sent with scripted 429s
+const RETRY_AFTER_SECS: u64 = 1;
+// trakt's default page size
+const DEFAULT_LIMIT: usize = 10;
+
+#[derive(Deserialize, Debug)]
+struct Fixture {
+    show: Value,
+    seasons: Vec<Value>,
+    /// only copied over for some seasons
+    #[serde(default)]
+    episodes: Vec<Value>,
+}
+
+impl Fixture {
+    fn matches(&self, id: &str) -> bool {
+        let ids = &self.show["ids"];
+        ids["imdb"] == id
+            || ids["slug"] == id
+            || ids["trakt"].as_u64().is_some_and(|t| id.parse() == Ok(t))
+    }
+
+    /// Episodes of one season, making up weekly placeholders if none were copied over.
+    fn season_episodes(&self, number: u64) -> Option<Vec<Value>> {
+        let season = self.seasons.iter().find(|s| s["number"] == number)?;
+        let copied: Vec<Value> = self
+            .episodes
+            .iter()
+            .filter(|e| e["season"] == number)
+            .cloned()
+            .collect();
+        if !copied.is_empty() {
+            return Some(copied);
+        }
+
+        let season_id = season["ids"]["trakt"].as_u64().unwrap_or_default();
+        let premiere = season["first_aired"]
+            .as_str()
+            .and_then(|d| d.parse::<DateTime<Utc>>().ok());
+        let count = season["episode_count"].as_u64().unwrap_or_default();
+        let episodes = (1..=count)
+            .map(|n| {
+                let aired = premiere.map(|d| d + Duration::weeks(n as i64 - 1));
+                json!({
+                    "season": number,
+                    "number": n,
+                    "title": format!("Episode {}", n),
+                    "ids": { "trakt": season_id * 100 + n },
+                    "overview": null,
+                    "first_aired": aired,
+                })
+            })
+            .collect();
+        Some(episodes)
+    }
+
+    /// Numbers of the regular seasons (no specials).
+    fn regular_seasons(&self) -> Vec<u64> {
+        self.seasons
+            .iter()
+            .filter_map(|s| s["number"].as_u64())
+            .filter(|&number| number > 0)
+            .collect()
+    }
+
+    fn season_id(&self, number: u64) -> u64 {
+        self.seasons
+            .iter()
+            .find(|s| s["number"] == number)
+            .and_then(|s| s["ids"]["trakt"].as_u64())
+            .unwrap_or_default()
+    }
+
+    /// A play of one of this show's episodes (without a history id yet).
+    fn play(&self, episode: Value, watched_at: DateTime<Utc>) -> Play {
+        let number = episode["season"].as_u64().unwrap_or_default();
+        Play {
+            id: 0,
+            watched_at,
+            show: search_result(self)["show"].clone(),
+            season_id: self.season_id(number),
+            episode,
+        }
+    }
+}
+
+/// One play in the mock user's history.
+#[derive(Clone, Debug)]
+struct Play {
+    id: u64,
+    watched_at: DateTime<Utc>,
+    /// as in search results
+    show: Value,
+    season_id: u64,
+    /// as in `Fixture::season_episodes`
+    episode: Value,
+}
+
+impl Play {
+    fn show_id(&self) -> u64 {
+        self.show["ids"]["trakt"].as_u64().unwrap_or_default()
+    }
+
+    fn episode_id(&self) -> u64 {
+        self.episode["ids"]["trakt"].as_u64().unwrap_or_default()
+    }
+
+    /// As listed by sync/history (episodes only have their details with extended=full).
+    fn to_json(&self, extended: bool) -> Value {
+        let mut episode = self.episode.clone();
+        if !extended {
+            episode = json!({
+                "season": episode["season"],
+                "number": episode["number"],
+                "title": episode["title"],
+                "ids": episode["ids"],
+            });
+        }
+        json!({
+            "id": self.id,
+            "watched_at": self.watched_at,
+            "action": "watch",
+            "type": "episode",
+            "episode": episode,
+            "show": self.show,
+        })
+    }
+}
+
+/// When a play sent as `at` ("released", a date, or nothing for now) was watched.
+fn play_time(at: &Value, episode: &Value, now: DateTime<Utc>) -> DateTime<Utc> {
+    let parse = |v: &Value| v.as_str().and_then(|d| d.parse::<DateTime<Utc>>().ok());
+    match at.as_str() {
+        Some("released") => parse(&episode["first_aired"]).unwrap_or(now),
+        Some(_) => parse(at).unwrap_or(now),
+        None => now,
+    }
+}
+
+#[derive(Debug)]
+struct Fault {
+    path: String,
+    status: u16,
+    times: usize,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    body: String,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Response {
+        Response {
+            status,
+            body: body.to_string(),
+            headers: vec![],
+        }
+    }
+
+    /// One page of `items`, with trakt's X-Pagination-* headers.
+    fn paginated(items: Vec<Value>, page: usize, limit: usize) -> Response {
+        let item_count = items.len();
+        let page_count = item_count.saturating_sub(1) / limit + 1;
+        let items = items
+            .into_iter()
+            .skip((page - 1) * limit)
+            .take(limit)
+            .collect();
+
+        let mut response = Response::json(200, Value::Array(items));
+        response.headers = vec![
+            ("X-Pagination-Page", page.to_string()),
+            ("X-Pagination-Limit", limit.to_string()),
+            ("X-Pagination-Page-Count", page_count.to_string()),
+            ("X-Pagination-Item-Count", item_count.to_string()),
+        ];
+        response
+    }
+
+    fn empty(status: u16) -> Response {
+        let mut response = Response::json(status, Value::Null);
+        response.body = String::new();
+        if status == 429 {
+            response
+                .headers
+                .push(("Retry-After", RETRY_AFTER_SECS.to_string()));
+        }
+        response
+    }
+}
+
+/// Stand-in for the trakt api, serving the fixtures in `fixtures/trakt` over plain http.
+/// Covers oauth, show details and seasons, search, history sync and the user's lists,
+/// which is enough to run the whole app (`--profile local`) or tests with no network.
+///
+/// Besides the ids in `SCRIPTED_IDS`, failures can be scripted with [`MockTrakt::fail`].
+#[derive(Clone)]
+pub struct MockTrakt {
+    fixtures: Arc<Vec<Fixture>>,
+    faults: Arc<Mutex<Vec<Fault>>>,
+    requests: Arc<Mutex<Vec<String>>>,
+    /// `sync/last_activities`, bumped when history or the user's lists change
+    activities: Arc<Mutex<Value>>,
+    /// the user's lists (see `USER_LIST_NAMES`), as trakt lists their items
+    lists: Arc<Mutex<HashMap<&'static str, Vec<Value>>>>,
+    /// every play in the user's history, starting with the last plays in `WATCHED`
+    history: Arc<Mutex<Vec<Play>>>,
+}
+
+impl std::fmt::Debug for MockTrakt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MockTrakt { ... }")
+    }
+}
+
+impl MockTrakt {
+    pub fn new() -> eyre::Result<MockTrakt> {
+        let user_lists: Value = serde_json::from_str(USER_LISTS)?;
+        let fixtures: Vec<Fixture> = serde_json::from_str(FIXTURES)?;
+        let history = watched_plays(&fixtures, &serde_json::from_str(WATCHED)?);
+        Ok(MockTrakt {
+            fixtures: Arc::new(fixtures),
+            faults: Arc::new(Mutex::new(vec![])),
+            requests: Arc::new(Mutex::new(vec![])),
+            activities: Arc::new(Mutex::new(user_lists["last_activities"].clone())),
+            lists: Arc::new(Mutex::new(
+                USER_LIST_NAMES
+                    .iter()
+                    .map(|&name| (name, user_list(name)))
+                    .collect(),
+            )),
+            history: Arc::new(Mutex::new(history)),
+        })
+    }
+
+    /// Answer the next `times` requests whose path starts with `path` with `status`.
+    pub fn fail(&self, path: &str, status: u16, times: usize) {
+        self.faults.lock().unwrap().push(Fault {
+            path: path.to_string(),
+            status,
+            times,
+        });
+    }
+
+    /// Every request served so far, as "METHOD /path?query".
+    #[cfg(test)]
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Listen on `addr` (e.g. "127.0.0.1:0") in the background, returning the base url.
+    pub async fn start(&self, addr: &str) -> eyre::Result<String> {
+        let listener = TcpListener::bind(addr).await?;
+        let local = listener.local_addr()?;
+
+        let mock = self.clone();
+        tokio::spawn(async move { mock.accept(listener).await });
+
+        Ok(format!("http://{}", local))
+    }
+
+    /// Listen on `addr` until the process is stopped.
+    pub async fn serve(&self, addr: &str) -> eyre::Result<()> {
+        let url = self.start(addr).await?;
+        info!("Mock trakt listening on {}", url);
+        println!("Mock trakt listening on {} (ctrl-c to stop)", url);
+        std::future::pending().await
+    }
+
+    async fn accept(&self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((socket, peer)) => {
+                    let mock = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = mock.handle(socket, peer).await {
+                            warn!("Mock trakt: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Mock trakt: could not accept connection: {}", e),
+            }
+        }
+    }
+
+    async fn handle(&self, socket: TcpStream, peer: SocketAddr) -> eyre::Result<()> {
+        let mut reader = BufReader::new(socket);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            eyre::bail!("bad request line from {}: {:?}", peer, request_line);
+        };
+        let (method, target) = (method.to_string(), target.to_string());
+
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                content_length = value.trim().parse()?;
+            }
+        }
+
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body).await?;
+        let body = String::from_utf8_lossy(&body);
+
+        let response = self.respond(&method, &target, &body);
+        debug!("Mock trakt: {} {} -> {}", method, target, response.status);
+
+        let mut head = format!(
+            "HTTP/1.1 {} MOCK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            response.status,
+            response.body.len()
+        );
+        for (name, value) in response.headers.iter() {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        head += "\r\n";
+
+        let mut socket = reader.into_inner();
+        socket.write_all(head.as_bytes()).await?;
+        socket.write_all(response.body.as_bytes()).await?;
+        socket.shutdown().await?;
+        Ok(())
+    }
+
+    fn scripted_fault(&self, path: &str) -> Option<u16> {
+        let mut faults = self.faults.lock().unwrap();
+        let i = faults.iter().position(|f| path.starts_with(&f.path))?;
+
+        let status = faults[i].status;
+        faults[i].times -= 1;
+        if faults[i].times == 0 {
+            faults.remove(i);
+        }
+        Some(status)
+    }
+
+    fn find(&self, id: &str) -> Option<&Fixture> {
+        self.fixtures.iter().find(|f| f.matches(id))
+    }
+
+    /// The show and details of the episode with trakt id `id`.
+    fn find_episode(&self, id: &Value) -> Option<(&Fixture, Value)> {
+        self.fixtures.iter().find_map(|fixture| {
+            let episode = fixture
+                .seasons
+                .iter()
+                .filter_map(|s| s["number"].as_u64())
+                .flat_map(|number| fixture.season_episodes(number).unwrap_or_default())
+                .find(|e| e["ids"]["trakt"] == *id)?;
+            Some((fixture, episode))
+        })
+    }
+
+    fn respond(&self, method: &str, target: &str, body: &str) -> Response {
+        self.requests
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", method, target));
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = parse_query(query);
+
+        if let Some(status) = self.scripted_fault(path) {
+            return Response::empty(status);
+        }
+
+        let number = |key: &str, default: usize| {
+            query
+                .get(key)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+                .max(1)
+        };
+        let (page, limit) = (number("page", 1), number("limit", DEFAULT_LIMIT));
+
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        for (id, status) in SCRIPTED_IDS {
+            if segments.contains(id) {
+                return Response::empty(*status);
+            }
+        }
+
+        match (method, segments.as_slice()) {
+            ("POST", ["oauth", "device", "code"]) => Response::json(
+                200,
+                json!({
+                    "device_code": "mock-device-code",
+                    "user_code": "MOCK1234",
+                    "verification_url": "https://trakt.tv/activate",
+                    "expires_in": 600,
+                    "interval": 0,
+                }),
+            ),
+            ("POST", ["oauth", "device", "token"]) | ("POST", ["oauth", "token"]) => {
+                Response::json(
+                    200,
+                    json!({
+                        "access_token": "mock-access-token",
+                        "token_type": "bearer",
+                        "expires_in": 7776000,
+                        "refresh_token": "mock-refresh-token",
+                        "scope": "public",
+                        "created_at": Utc::now().timestamp(),
+                    }),
+                )
+            }
+            ("GET", ["shows", id]) => match self.find(id) {
+                Some(fixture) => Response::json(200, fixture.show.clone()),
+                None => Response::empty(404),
+            },
+            ("GET", ["shows", id, "seasons"]) => match self.find(id) {
+                Some(fixture) => {
+                    let mut seasons = fixture.seasons.clone();
+                    let extended = query.get("extended").cloned().unwrap_or_default();
+                    if extended.split(',').any(|e| e == "episodes") {
+                        for season in seasons.iter_mut() {
+                            let number = season["number"].as_u64().unwrap_or_default();
+                            season["episodes"] =
+                                Value::from(fixture.season_episodes(number).unwrap_or_default());
+                        }
+                    }
+                    Response::json(200, Value::from(seasons))
+                }
+                None => Response::empty(404),
+            },
+            ("GET", ["shows", id, "seasons", number]) => match self
+                .find(id)
+                .zip(number.parse().ok())
+                .and_then(|(fixture, number)| fixture.season_episodes(number))
+            {
+                Some(episodes) => Response::json(200, Value::from(episodes)),
+                None => Response::empty(404),
+            },
+            ("GET", ["sync", "history", item_type, id]) => {
+                let Ok(id) = id.parse::<u64>() else {
+                    return Response::empty(404);
+                };
+                let extended = query.get("extended").is_some_and(|e| e.contains("full"));
+                let mut plays: Vec<Play> = self
+                    .history
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|play| match *item_type {
+                        "shows" => play.show_id() == id,
+                        "seasons" => play.season_id == id,
+                        "episodes" => play.episode_id() == id,
+                        _ => false,
+                    })
+                    .cloned()
+                    .collect();
+                plays.sort_by_key(|p| std::cmp::Reverse(p.watched_at));
+                let plays = plays.iter().map(|p| p.to_json(extended)).collect();
+                Response::paginated(plays, page, limit)
+            }
+            ("GET", ["sync", "watched", "shows"]) => {
+                Response::json(200, serde_json::from_str(WATCHED).unwrap_or_default())
+            }
+            ("GET", ["sync", "last_activities"]) => {
+                Response::json(200, self.activities.lock().unwrap().clone())
+            }
+            ("GET", ["sync", "watchlist", "shows"]) => {
+                Response::paginated(self.list("watchlist"), page, limit)
+            }
+            ("GET", ["users", "hidden", section]) => {
+                Response::paginated(self.list(&format!("hidden_{}", section)), page, limit)
+            }
+            ("GET", ["sync", "ratings"]) => Response::json(200, Value::from(user_list("ratings"))),
+            ("GET", ["search", "show"]) => {
+                let text = query.get("query").cloned().unwrap_or_default();
+
+                let results = self
+                    .fixtures
+                    .iter()
+                    .filter(|f| {
+                        let title = f.show["title"].as_str().unwrap_or_default();
+                        title.to_lowercase().contains(&text.to_lowercase())
+                    })
+                    .map(search_result)
+                    .collect();
+                Response::paginated(results, page, limit)
+            }
+            ("GET", ["search", _id_type, id]) => {
+                let results = self.find(id).into_iter().map(search_result).collect();
+                Response::json(200, Value::Array(results))
+            }
+            ("POST", ["sync", "history"]) => self.add_history(body),
+            ("POST", ["sync", "history", "remove"]) => self.remove_history(body),
+            ("POST", ["sync", "watchlist"]) => self.sync_list("watchlist", body, true),
+            ("POST", ["sync", "watchlist", "remove"]) => self.sync_list("watchlist", body, false),
+            ("POST", ["users", "hidden", section]) => {
+                self.sync_list(&format!("hidden_{}", section), body, true)
+            }
+            ("POST", ["users", "hidden", section, "remove"]) => {
+                self.sync_list(&format!("hidden_{}", section), body, false)
+            }
+            _ => Response::empty(404),
+        }
+    }
+
+    /// Add a play for every episode in a history payload, like trakt does: whole shows
+    /// are their regular seasons, and plays go in at the given time, on release or now.
+    fn add_history(&self, body: &str) -> Response {
+        let Ok(payload) = serde_json::from_str::<Value>(body) else {
+            return Response::empty(400);
+        };
+
+        let now = Utc::now();
+        let mut plays = vec![];
+        let mut not_found_shows = vec![];
+        let mut not_found_episodes = vec![];
+        for show in payload["shows"].as_array().into_iter().flatten() {
+            let Some(fixture) = self.find(&show["ids"]["trakt"].to_string()) else {
+                not_found_shows.push(json!({ "ids": show["ids"] }));
+                continue;
+            };
+            let seasons: Vec<(u64, &Value)> = match show["seasons"].as_array() {
+                Some(seasons) if !seasons.is_empty() => seasons
+                    .iter()
+                    .filter_map(|s| {
+                        // a season without a time of its own goes by the show's
+                        let at = Some(&s["watched_at"]).filter(|at| !at.is_null());
+                        Some((s["number"].as_u64()?, at.unwrap_or(&show["watched_at"])))
+                    })
+                    .collect(),
+                _ => fixture
+                    .regular_seasons()
+                    .into_iter()
+                    .map(|number| (number, &show["watched_at"]))
+                    .collect(),
+            };
+            for (number, at) in seasons {
+                for episode in fixture.season_episodes(number).unwrap_or_default() {
+                    let watched_at = play_time(at, &episode, now);
+                    plays.push(fixture.play(episode, watched_at));
+                }
+            }
+        }
+        for sent in payload["episodes"].as_array().into_iter().flatten() {
+            match self.find_episode(&sent["ids"]["trakt"]) {
+                Some((fixture, episode)) => {
+                    let watched_at = play_time(&sent["watched_at"], &episode, now);
+                    plays.push(fixture.play(episode, watched_at));
+                }
+                None => not_found_episodes.push(json!({ "ids": sent["ids"] })),
+            }
+        }
+
+        let added = plays.len();
+        let mut history = self.history.lock().unwrap();
+        let mut next_id = history.iter().map(|p| p.id).max().unwrap_or_default();
+        for mut play in plays {
+            next_id += 1;
+            play.id = next_id;
+            history.push(play);
+        }
+        drop(history);
+        self.touch("episodes", "watched_at");
+
+        Response::json(
+            200,
+            json!({
+                "added": { "movies": 0, "episodes": added },
+                "not_found": {
+                    "movies": [],
+                    "shows": not_found_shows,
+                    "seasons": [],
+                    "episodes": not_found_episodes,
+                },
+            }),
+        )
+    }
+
+    /// Remove every play of the shows, seasons and episodes in a payload, and the plays
+    /// with the given history ids.
+    fn remove_history(&self, body: &str) -> Response {
+        let Ok(payload) = serde_json::from_str::<Value>(body) else {
+            return Response::empty(400);
+        };
+
+        let mut not_found = vec![];
+        // show -> season numbers (none: all of them)
+        let mut shows: Vec<(u64, Vec<u64>)> = vec![];
+        for show in payload["shows"].as_array().into_iter().flatten() {
+            if self.find(&show["ids"]["trakt"].to_string()).is_none() {
+                not_found.push(json!({ "ids": show["ids"] }));
+                continue;
+            }
+            let numbers = show["seasons"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|s| s["number"].as_u64())
+                .collect();
+            shows.push((show["ids"]["trakt"].as_u64().unwrap_or_default(), numbers));
+        }
+        let episodes: Vec<&Value> = payload["episodes"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|e| &e["ids"]["trakt"])
+            .collect();
+        let ids: Vec<u64> = payload["ids"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_u64)
+            .collect();
+
+        let mut history = self.history.lock().unwrap();
+        let before = history.len();
+        history.retain(|play| {
+            let season = play.episode["season"].as_u64().unwrap_or_default();
+            let in_show = shows.iter().any(|(show_id, numbers)| {
+                play.show_id() == *show_id && (numbers.is_empty() || numbers.contains(&season))
+            });
+            !in_show
+                && !episodes.contains(&&play.episode["ids"]["trakt"])
+                && !ids.contains(&play.id)
+        });
+        let deleted = before - history.len();
+        drop(history);
+        self.touch("episodes", "watched_at");
+
+        Response::json(
+            200,
+            json!({
+                "deleted": { "movies": 0, "episodes": deleted },
+                "not_found": { "movies": [], "shows": not_found, "seasons": [], "episodes": [] },
+            }),
+        )
+    }
+
+    fn list(&self, name: &str) -> Vec<Value> {
+        self.lists
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Add shows to (or remove them from) one of the user's lists. Only shows we have
+    /// fixtures for can be added.
+    fn sync_list(&self, name: &str, body: &str, add: bool) -> Response {
+        let Ok(payload) = serde_json::from_str::<Value>(body) else {
+            return Response::empty(400);
+        };
+
+        let mut lists = self.lists.lock().unwrap();
+        let Some(list) = lists.get_mut(name) else {
+            return Response::empty(404);
+        };
+        let (listed_at, activity) = if name == "watchlist" {
+            ("listed_at", "watchlisted_at")
+        } else {
+            ("hidden_at", "hidden_at")
+        };
+        let mut changed = 0;
+        let mut existing = 0;
+        let mut not_found = vec![];
+        for show in payload["shows"].as_array().into_iter().flatten() {
+            let trakt_id = &show["ids"]["trakt"];
+            let listed = list
+                .iter()
+                .position(|l| l["show"]["ids"]["trakt"] == *trakt_id);
+            match (add, listed) {
+                (true, Some(_)) => existing += 1,
+                (true, None) => match self.find(&trakt_id.to_string()) {
+                    Some(fixture) => {
+                        let mut item = json!({
+                            "type": "show",
+                            "show": search_result(fixture)["show"],
+                        });
+                        item[listed_at] = json!(Utc::now());
+                        if name == "watchlist" {
+                            item["rank"] = json!(list.len() + 1);
+                        }
+                        list.push(item);
+                        changed += 1;
+                    }
+                    None => not_found.push(json!({ "ids": show["ids"] })),
+                },
+                (false, Some(i)) => {
+                    list.remove(i);
+                    changed += 1;
+                }
+                (false, None) => {}
+            }
+        }
+        drop(lists);
+        self.touch("shows", activity);
+
+        let counted = if add { "added" } else { "deleted" };
+        let mut response = json!({
+            "existing": { "movies": 0, "shows": existing, "seasons": 0, "episodes": 0 },
+            "not_found": { "movies": [], "shows": not_found, "seasons": [], "episodes": [] },
+        });
+        response[counted] = json!({ "movies": 0, "shows": changed, "seasons": 0, "episodes": 0 });
+        Response::json(200, response)
+    }
+
+    /// Bump one of the activity times in `sync/last_activities` to now.
+    fn touch(&self, items: &str, activity: &str) {
+        let now = json!(Utc::now());
+        let mut activities = self.activities.lock().unwrap();
+        activities["all"] = now.clone();
+        activities[items][activity] = now;
+    }
+}
+
+/// A play for the last time each episode in `watched` (as in sync/watched/shows) was seen.
+fn watched_plays(fixtures: &[Fixture], watched: &Value) -> Vec<Play> {
+    let mut plays = vec![];
+    for show in watched.as_array().into_iter().flatten() {
+        let id = show["show"]["ids"]["trakt"].to_string();
+        let Some(fixture) = fixtures.iter().find(|f| f.matches(&id)) else {
+            continue;
+        };
+        for season in show["seasons"].as_array().into_iter().flatten() {
+            let number = season["number"].as_u64().unwrap_or_default();
+            let episodes = fixture.season_episodes(number).unwrap_or_default();
+            for watched in season["episodes"].as_array().into_iter().flatten() {
+                let episode = episodes.iter().find(|e| e["number"] == watched["number"]);
+                let watched_at = watched["last_watched_at"]
+                    .as_str()
+                    .and_then(|d| d.parse::<DateTime<Utc>>().ok());
+                if let Some((episode, watched_at)) = episode.zip(watched_at) {
+                    plays.push(fixture.play(episode.clone(), watched_at));
+                }
+            }
+        }
+    }
+    for (i, play) in plays.iter_mut().enumerate() {
+        play.id = i as u64 + 1;
+    }
+    plays
+}
+
+/// One of the mock user's lists from `USER_LISTS`.
+fn user_list(name: &str) -> Vec<Value> {
+    serde_json::from_str::<Value>(USER_LISTS)
+        .ok()
+        .and_then(|lists| lists[name].as_array().cloned())
+        .unwrap_or_default()
+}
+
+fn search_result(fixture: &Fixture) -> Value {
+    json!({
+        "type": "show",
+        "score": 1000,
+        "show": {
+            "title": fixture.show["title"],
+            "year": fixture.show["year"],
+            "ids": fixture.show["ids"],
+        },
+    })
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (percent_decode(k), percent_decode(v)))
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = vec![];
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = iter.by_ref().take(2).collect();
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                {
+                    Some(decoded) => bytes.push(decoded),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend(hex);
+                    }
+                }
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// A mock listening on a free port, and a client logged in to it that caches in the
+/// (fresh, in-memory) db returned alongside.
+#[cfg(test)]
+pub async fn start_logged_in() -> (MockTrakt, PersistentDb, TraktClient) {
+    let mock = MockTrakt::new().unwrap();
+    let base_url = mock.start("127.0.0.1:0").await.unwrap();
+    let cache = PersistentDb::in_memory().unwrap();
+    let client = TraktClient::new(cache.clone(), &TraktConfig::local(&base_url))
+        .await
+        .unwrap();
+    client.login().await.unwrap();
+    (mock, cache, client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trakt::t_api::{
+        self, ApiEpisodeDetails, ApiHiddenShow, ApiLastActivities, ApiListedShow, ApiMatch,
+        ApiRating, ApiSeasonDetails, ApiShowDetails, TraktApiError,
+    };
+    use crate::trakt::t_sync::{HistoryPayload, HistoryResponse};
+
+    #[test]
+    fn fixtures_decode() {
+        let mock = MockTrakt::new().unwrap();
+        for fixture in mock.fixtures.iter() {
+            serde_json::from_value::<ApiShowDetails>(fixture.show.clone()).unwrap();
+            serde_json::from_value::<Vec<ApiSeasonDetails>>(Value::from(fixture.seasons.clone()))
+                .unwrap();
+            serde_json::from_value::<ApiMatch>(search_result(fixture)).unwrap();
+            for season in fixture.seasons.iter() {
+                let number = season["number"].as_u64().unwrap();
+                let episodes = fixture.season_episodes(number).unwrap();
+                serde_json::from_value::<Vec<ApiEpisodeDetails>>(Value::from(episodes)).unwrap();
+            }
+        }
+        assert_eq!(mock.fixtures.len(), 3);
+
+        let activities = mock.activities.lock().unwrap().clone();
+        serde_json::from_value::<ApiLastActivities>(activities).unwrap();
+        serde_json::from_value::<Vec<ApiListedShow>>(Value::from(user_list("watchlist"))).unwrap();
+        for name in ["hidden_progress_watched", "hidden_recommendations"] {
+            serde_json::from_value::<Vec<ApiHiddenShow>>(Value::from(user_list(name))).unwrap();
+        }
+        serde_json::from_value::<Vec<ApiRating>>(Value::from(user_list("ratings"))).unwrap();
+    }
+
+    #[test]
+    fn decodes_query() {
+        let query = parse_query("query=breaking+bad&type=show%2Cmovie");
+        assert_eq!(query["query"], "breaking bad");
+        assert_eq!(query["type"], "show,movie");
+    }
+
+    #[tokio::test]
+    async fn end_to_end() {
+        let (mock, _, client) = start_logged_in().await;
+
+        let imdb_id = "tt0903747".to_string();
+        let (show, seasons) = t_api::query_detailed(&client, &imdb_id).await.unwrap();
+        assert_eq!(show.ids.trakt, 1388);
+        assert_eq!(seasons.len(), 6);
+
+        // a one-off server error is retried
+        mock.fail("/shows/tt11280740", 500, 1);
+        let (show, _) = t_api::query_detailed(&client, &"tt11280740".to_string())
+            .await
+            .unwrap();
+        assert_eq!(show.network.as_deref(), Some("Apple TV+"));
+        assert_eq!(client.limiter.usage().retries, 1);
+
+        let err = t_api::query_detailed(&client, &"tt0000404".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TraktApiError::NotFound));
+
+        let text = t_api::do_post(&client, "sync/history", &HistoryPayload::show(1388, None))
+            .await
+            .unwrap();
+        let response: HistoryResponse = t_api::decode(&text).unwrap();
+        assert_eq!(response.added.episodes, 62);
+
+        let requests = mock.requests();
+        assert!(requests.contains(&"GET /shows/tt0903747?extended=full".to_string()));
+        assert!(requests.contains(&"POST /sync/history".to_string()));
+    }
+
+    #[tokio::test]
+    async fn writes_are_not_retried_after_server_errors() {
+        let (mock, _, client) = start_logged_in().await;
+
+        // trakt may have applied it before failing, so sending it again could double it up
+        mock.fail("/sync/history", 503, 1);
+        let payload = HistoryPayload::show(1388, None);
+        let err = t_api::do_post(&client, "sync/history", &payload)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TraktApiError::Server(status) if status.as_u16() == 503));
+        assert_eq!(client.limiter.usage().retries, 0);
+
+        // being rate limited means it wasn't applied
+        mock.fail("/sync/history", 429, 1);
+        t_api::do_post(&client, "sync/history", &payload)
+            .await
+            .unwrap();
+        assert_eq!(client.limiter.usage().retries, 1);
+
+        let posts = mock
+            .requests()
+            .iter()
+            .filter(|r| *r == "POST /sync/history")
+            .count();
+        assert_eq!(posts, 3);
+    }
+}
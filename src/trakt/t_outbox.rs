@@ -0,0 +1,564 @@
+use crate::models::{
+    NewOutboxItem, OutboxItem, OutboxOperation, OutboxState, SyncItemKind, SyncedItem,
+    TraktEpisode, TraktSeason, TraktShow, UserStatusShow,
+};
+use crate::trakt::t_api::{self, TraktApiError, TraktClient};
+use crate::trakt::t_db::{Database, PersistentDb};
+use crate::trakt::t_sync::{self, HistoryNotFound, HistoryPayload, SyncSummary, WatchedAt};
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+// how often the worker wakes up to retry, if nothing new is queued
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BASE_BACKOFF_SECS: i64 = 10;
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+// attempts before giving up on a change that trakt keeps rejecting
+const MAX_ATTEMPTS: i32 = 8;
+
+// response to POST sync/history, sync/watchlist and their /remove
+// (we only care about what was missing)
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct OutboxResponse {
+    #[serde(default)]
+    not_found: HistoryNotFound,
+}
+
+enum Delivery {
+    Sent,
+    NotFound,
+}
+
+// shows are hidden from both, so they're gone from trakt's progress and suggestions alike
+const HIDDEN_SECTIONS: &[&str] = &[
+    "users/hidden/progress_watched",
+    "users/hidden/recommendations",
+];
+const UNHIDDEN_SECTIONS: &[&str] = &[
+    "users/hidden/progress_watched/remove",
+    "users/hidden/recommendations/remove",
+];
+
+fn endpoints(operation: OutboxOperation) -> &'static [&'static str] {
+    match operation {
+        OutboxOperation::AddHistory => &["sync/history"],
+        // sent as the history ids of our own plays (see `Outbox::send`)
+        OutboxOperation::RemoveHistory => &[],
+        OutboxOperation::AddWatchlist => &["sync/watchlist"],
+        OutboxOperation::RemoveWatchlist => &["sync/watchlist/remove"],
+        OutboxOperation::Hide => HIDDEN_SECTIONS,
+        OutboxOperation::Unhide => UNHIDDEN_SECTIONS,
+    }
+}
+
+/// The trakt lists that mirror a show status: Todo shows are on the watchlist,
+/// Unwatched ones are hidden. Shows the user never set a status on are on neither.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShowList {
+    Watchlist,
+    Hidden,
+}
+
+impl ShowList {
+    pub fn status(&self) -> UserStatusShow {
+        match self {
+            ShowList::Watchlist => UserStatusShow::Todo,
+            ShowList::Hidden => UserStatusShow::Unwatched,
+        }
+    }
+
+    fn operation(&self, listed: bool) -> OutboxOperation {
+        match (self, listed) {
+            (ShowList::Watchlist, true) => OutboxOperation::AddWatchlist,
+            (ShowList::Watchlist, false) => OutboxOperation::RemoveWatchlist,
+            (ShowList::Hidden, true) => OutboxOperation::Hide,
+            (ShowList::Hidden, false) => OutboxOperation::Unhide,
+        }
+    }
+}
+
+/// Exponential backoff after `attempts` failures, capped at an hour.
+fn backoff(attempts: i32) -> chrono::Duration {
+    let exp = attempts.clamp(1, 16) as u32 - 1;
+    chrono::Duration::seconds((BASE_BACKOFF_SECS * 2i64.pow(exp)).min(MAX_BACKOFF_SECS))
+}
+
+/// Network trouble, trakt being down, or our login having lapsed is worth retrying forever.
+/// Anything else counts towards `MAX_ATTEMPTS`.
+fn is_transient(err: &eyre::Report) -> bool {
+    match err.downcast_ref::<TraktApiError>() {
+        Some(TraktApiError::Unauthorized) => true,
+        Some(e) => e.is_retryable(),
+        None => false,
+    }
+}
+
+/// Persistent queue of local status changes that still have to reach trakt.
+/// Changes are stored in the db first, so nothing is lost if trakt is unreachable
+/// or the app is closed before they're sent.
+#[derive(Clone)]
+pub struct Outbox {
+    cache: PersistentDb,
+    notify: Arc<Notify>,
+}
+
+impl std::fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Outbox { ... }")
+    }
+}
+
+impl Outbox {
+    pub fn new(cache: PersistentDb) -> Outbox {
+        Outbox {
+            cache,
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Number of changes still waiting to be sent.
+    pub async fn pending(&self) -> eyre::Result<usize> {
+        self.cache.count_pending_outbox().await
+    }
+
+    async fn enqueue(
+        &self,
+        kind: SyncItemKind,
+        trakt_id: i32,
+        operation: OutboxOperation,
+        payload: &HistoryPayload,
+        watched_at: Option<NaiveDateTime>,
+    ) -> eyre::Result<()> {
+        let now = Utc::now().naive_utc();
+        self.cache
+            .enqueue_outbox(NewOutboxItem {
+                kind,
+                trakt_id,
+                operation,
+                watched_at,
+                payload: serde_json::to_string(payload)?,
+                state: OutboxState::Pending,
+                attempts: 0,
+                next_attempt_at: now,
+                created_at: now,
+            })
+            .await?;
+
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Queue whatever trakt needs to mirror a show's new watch status.
+    pub async fn show_changed(&self, show: &TraktShow, previous: &TraktShow) -> eyre::Result<()> {
+        let Some(trakt_id) = show.trakt_id else {
+            debug!("Outbox: {} has no trakt id yet, not queueing", show.imdb_id);
+            return Ok(());
+        };
+
+        if show.user_status == UserStatusShow::Watched {
+            let payload = HistoryPayload::show(trakt_id, Some(WatchedAt::Released));
+            self.enqueue(
+                SyncItemKind::Show,
+                trakt_id,
+                OutboxOperation::AddHistory,
+                &payload,
+                None,
+            )
+            .await?;
+        } else if previous.user_status == UserStatusShow::Watched
+            && show.user_status == UserStatusShow::Todo
+        {
+            // only the plays that marked it as watched go, a hidden show keeps its history
+            self.enqueue(
+                SyncItemKind::Show,
+                trakt_id,
+                OutboxOperation::RemoveHistory,
+                &HistoryPayload::default(),
+                None,
+            )
+            .await?;
+        }
+
+        for list in [ShowList::Watchlist, ShowList::Hidden] {
+            let status = list.status();
+            if show.user_status == status && previous.user_status != status {
+                self.list_changed(list, trakt_id, true).await?;
+            } else if previous.user_status == status
+                && show.user_status != status
+                // it was only on the list if it was put there on purpose
+                && previous.status_changed_at.is_some()
+            {
+                self.list_changed(list, trakt_id, false).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queue putting a show on (or taking it off) one of trakt's lists.
+    pub async fn list_changed(
+        &self,
+        list: ShowList,
+        trakt_id: i32,
+        listed: bool,
+    ) -> eyre::Result<()> {
+        let operation = list.operation(listed);
+        let payload = HistoryPayload::show(trakt_id, None);
+        self.enqueue(SyncItemKind::Show, trakt_id, operation, &payload, None)
+            .await
+    }
+
+    /// Queue whatever trakt needs to mirror a season's new watch status (or date).
+    pub async fn season_changed(
+        &self,
+        season: &TraktSeason,
+        previous: &TraktSeason,
+    ) -> eyre::Result<()> {
+        let old = t_sync::season_watched_at(previous);
+        let new = t_sync::season_watched_at(season);
+        if old == new {
+            return Ok(());
+        }
+
+        // replace the previous watch (if any) rather than adding a second play
+        if old.is_some() {
+            self.enqueue(
+                SyncItemKind::Season,
+                season.id,
+                OutboxOperation::RemoveHistory,
+                &HistoryPayload::default(),
+                None,
+            )
+            .await?;
+        }
+        if let Some(watched_at) = new {
+            let at = watched_at.as_ref().and_then(WatchedAt::as_naive);
+            let payload = HistoryPayload::season(season, watched_at);
+            self.enqueue(
+                SyncItemKind::Season,
+                season.id,
+                OutboxOperation::AddHistory,
+                &payload,
+                at,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Queue whatever trakt needs to mirror an episode's new watch date.
+    pub async fn episode_changed(
+        &self,
+        episode: &TraktEpisode,
+        previous: Option<NaiveDateTime>,
+    ) -> eyre::Result<()> {
+        if previous == episode.watched_at {
+            return Ok(());
+        }
+
+        // replace the previous watch (if any) rather than adding a second play
+        if previous.is_some() {
+            self.enqueue(
+                SyncItemKind::Episode,
+                episode.id,
+                OutboxOperation::RemoveHistory,
+                &HistoryPayload::default(),
+                None,
+            )
+            .await?;
+        }
+        if let Some(at) = episode.watched_at {
+            let payload = HistoryPayload::episode(episode.id, Some(WatchedAt::At(at)));
+            self.enqueue(
+                SyncItemKind::Episode,
+                episode.id,
+                OutboxOperation::AddHistory,
+                &payload,
+                Some(at),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Start sending queued changes in the background.
+    /// Anything left over from a previous run is picked up right away.
+    pub fn spawn_worker(&self, client: TraktClient) {
+        let outbox = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = outbox.drain(&client).await {
+                    error!("Outbox: {}", e);
+                }
+
+                // wake up early if something new is queued
+                let _ = tokio::time::timeout(POLL_INTERVAL, outbox.notify.notified()).await;
+            }
+        });
+    }
+
+    /// Try to send every pending change that is due, oldest first.
+    pub async fn drain(&self, client: &TraktClient) -> eyre::Result<()> {
+        if !client.auth.is_logged_in().await {
+            return Ok(());
+        }
+
+        let now = Utc::now().naive_utc();
+        // changes to the same item must reach trakt in order: if one is held back, so are
+        // the ones after it
+        let mut blocked = HashSet::new();
+
+        for mut item in self.cache.pending_outbox().await? {
+            let key = (item.kind, item.trakt_id);
+            if blocked.contains(&key) || item.next_attempt_at > now {
+                blocked.insert(key);
+                continue;
+            }
+
+            item.attempts += 1;
+            match self.send(client, &item).await {
+                Ok(Delivery::Sent) => {
+                    info!(
+                        "Outbox: sent {:?} for {:?} {}",
+                        item.operation, item.kind, item.trakt_id
+                    );
+                    item.state = OutboxState::Done;
+                    item.last_error = None;
+                }
+                Ok(Delivery::NotFound) => {
+                    warn!(
+                        "Outbox: trakt doesn't know {:?} {}",
+                        item.kind, item.trakt_id
+                    );
+                    item.state = OutboxState::Failed;
+                    item.last_error = Some("not found on trakt".to_string());
+                }
+                Err(e) => {
+                    warn!(
+                        "Outbox: attempt {} for item {} failed: {}",
+                        item.attempts, item.id, e
+                    );
+                    item.last_error = Some(e.to_string());
+                    if !is_transient(&e) && item.attempts >= MAX_ATTEMPTS {
+                        item.state = OutboxState::Failed;
+                    } else {
+                        item.next_attempt_at = now + backoff(item.attempts);
+                        blocked.insert(key);
+                    }
+                }
+            }
+
+            self.cache.update_outbox(item).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, client: &TraktClient, item: &OutboxItem) -> eyre::Result<Delivery> {
+        let body: serde_json::Value = serde_json::from_str(&item.payload)?;
+        for endpoint in endpoints(item.operation) {
+            let text = t_api::do_post(client, endpoint, &body).await?;
+            let response: OutboxResponse = t_api::decode(&text)?;
+
+            if response.not_found.contains(item.kind, item.trakt_id) {
+                return Ok(Delivery::NotFound);
+            }
+        }
+
+        match item.operation {
+            OutboxOperation::AddHistory => {
+                let synced = SyncedItem {
+                    kind: item.kind,
+                    trakt_id: item.trakt_id,
+                    watched_at: item.watched_at,
+                    synced_at: Utc::now().naive_utc(),
+                    pushed: true,
+                };
+                self.cache.record_synced(vec![synced]).await?;
+            }
+            OutboxOperation::RemoveHistory => {
+                // whatever trakt has from us for the item, as of now (an add that was still
+                // queued when this was, never went out)
+                let synced: Vec<SyncedItem> = self
+                    .cache
+                    .synced_items()
+                    .await?
+                    .into_iter()
+                    .filter(|s| s.kind == item.kind && s.trakt_id == item.trakt_id)
+                    .collect();
+                t_sync::remove_plays(client, &synced, &mut SyncSummary::default()).await?;
+                self.cache.forget_synced(item.kind, item.trakt_id).await?;
+            }
+            OutboxOperation::AddWatchlist
+            | OutboxOperation::RemoveWatchlist
+            | OutboxOperation::Hide
+            | OutboxOperation::Unhide => {}
+        }
+
+        Ok(Delivery::Sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::UserStatusEpisode;
+    use crate::trakt::t_mock::start_logged_in;
+
+    fn watched_episode(id: i32) -> TraktEpisode {
+        TraktEpisode {
+            id,
+            season_id: 3950,
+            show_id: 1388,
+            season_number: 1,
+            episode_number: id - 73481,
+            title: format!("Episode {}", id),
+            first_aired: None,
+            watched_at: Some(Utc::now().naive_utc()),
+            user_status: UserStatusEpisode::Watched,
+        }
+    }
+
+    async fn play_ids(client: &TraktClient, show_id: i32) -> HashSet<u64> {
+        let plays = t_api::query_history(client, "shows", show_id)
+            .await
+            .unwrap();
+        plays.iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        assert_eq!(backoff(1), chrono::Duration::seconds(10));
+        assert_eq!(backoff(2), chrono::Duration::seconds(20));
+        assert_eq!(backoff(5), chrono::Duration::seconds(160));
+        assert_eq!(backoff(30), chrono::Duration::seconds(MAX_BACKOFF_SECS));
+    }
+
+    #[tokio::test]
+    async fn queue_survives_a_new_outbox() {
+        let cache = PersistentDb::in_memory().unwrap();
+        let outbox = Outbox::new(cache.clone());
+        outbox
+            .episode_changed(&watched_episode(73482), None)
+            .await
+            .unwrap();
+        outbox
+            .list_changed(ShowList::Watchlist, 1388, true)
+            .await
+            .unwrap();
+        assert_eq!(outbox.pending().await.unwrap(), 2);
+        drop(outbox);
+
+        // e.g. the app was closed before they were sent
+        let outbox = Outbox::new(cache);
+        assert_eq!(outbox.pending().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn drain_marks_items_done_or_failed() {
+        let (mock, cache, client) = start_logged_in().await;
+        let outbox = Outbox::new(cache.clone());
+
+        // trakt is down for the first, takes the second, and doesn't know the show
+        mock.fail("/sync/history", 503, 1);
+        for episode in [watched_episode(73482), watched_episode(73483)] {
+            outbox.episode_changed(&episode, None).await.unwrap();
+        }
+        let unknown = TraktShow {
+            imdb_id: "tt0000404".to_string(),
+            trakt_id: Some(404),
+            primary_title: "Unknown".to_string(),
+            original_title: "Unknown".to_string(),
+            country: None,
+            release_year: None,
+            network: None,
+            no_seasons: None,
+            no_episodes: None,
+            overview: None,
+            user_status: UserStatusShow::Watched,
+            status_changed_at: None,
+        };
+        let previous = TraktShow {
+            user_status: UserStatusShow::Todo,
+            ..unknown.clone()
+        };
+        outbox.show_changed(&unknown, &previous).await.unwrap();
+        assert_eq!(outbox.pending().await.unwrap(), 3);
+
+        outbox.drain(&client).await.unwrap();
+
+        let pending = cache.pending_outbox().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].trakt_id, 73482);
+        assert_eq!(pending[0].attempts, 1);
+        assert!(pending[0].next_attempt_at > Utc::now().naive_utc());
+        assert!(pending[0].last_error.as_deref().unwrap().contains("503"));
+        // only what trakt took counts as synced
+        let synced = cache.synced_items().await.unwrap();
+        let synced: Vec<_> = synced.iter().map(|s| (s.kind, s.trakt_id)).collect();
+        assert_eq!(synced, vec![(SyncItemKind::Episode, 73483)]);
+    }
+
+    #[tokio::test]
+    async fn status_changes_only_take_off_our_own_plays() {
+        let (_mock, cache, client) = start_logged_in().await;
+        let outbox = Outbox::new(cache.clone());
+        crate::trakt::t_import::pull_watched(&client, &cache)
+            .await
+            .unwrap();
+        let state = cache.local_state(vec![]).await.unwrap();
+        let show = |trakt_id| {
+            let show = state.shows.iter().find(|s| s.trakt_id == Some(trakt_id));
+            show.unwrap().clone()
+        };
+        let with_status = |show: &TraktShow, user_status| TraktShow {
+            user_status,
+            status_changed_at: Some(Utc::now().naive_utc()),
+            ..show.clone()
+        };
+
+        // hiding severance (imported as watched) leaves its history alone
+        let severance = show(154997);
+        assert_eq!(severance.user_status, UserStatusShow::Watched);
+        let before = play_ids(&client, 154997).await;
+        let hidden = with_status(&severance, UserStatusShow::Unwatched);
+        outbox.show_changed(&hidden, &severance).await.unwrap();
+        let queued = cache.pending_outbox().await.unwrap();
+        let queued: Vec<_> = queued.iter().map(|i| i.operation).collect();
+        assert_eq!(queued, vec![OutboxOperation::Hide]);
+        outbox.drain(&client).await.unwrap();
+        assert_eq!(play_ids(&client, 154997).await, before);
+
+        // putting it back on the watchlist doesn't either, as we never sent those plays
+        let todo = with_status(&severance, UserStatusShow::Todo);
+        outbox.show_changed(&todo, &severance).await.unwrap();
+        outbox.drain(&client).await.unwrap();
+        assert_eq!(play_ids(&client, 154997).await, before);
+
+        // breaking bad, marked as watched and then put back, loses just the plays that added
+        let breaking_bad = show(1388);
+        let before = play_ids(&client, 1388).await;
+        let watched = with_status(&breaking_bad, UserStatusShow::Watched);
+        outbox.show_changed(&watched, &breaking_bad).await.unwrap();
+        outbox.drain(&client).await.unwrap();
+        assert_eq!(play_ids(&client, 1388).await.len(), before.len() + 62);
+
+        let todo = with_status(&breaking_bad, UserStatusShow::Todo);
+        outbox.show_changed(&todo, &watched).await.unwrap();
+        outbox.drain(&client).await.unwrap();
+        assert_eq!(outbox.pending().await.unwrap(), 0);
+        assert_eq!(play_ids(&client, 1388).await, before);
+        let synced = cache.synced_items().await.unwrap();
+        assert!(!synced
+            .iter()
+            .any(|s| s.kind == SyncItemKind::Show && s.trakt_id == 1388));
+    }
+}
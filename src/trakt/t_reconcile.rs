@@ -0,0 +1,605 @@
+use crate::models::{
+    SyncItemKind, SyncedItem, TraktEpisode, TraktSeason, TraktShow, UserStatusEpisode,
+    UserStatusSeason, UserStatusShow,
+};
+use crate::trakt::t_api::{
+    self, ApiHiddenShow, ApiListedShow, ApiWatchedShow, TraktApiError, TraktClient,
+};
+use crate::trakt::t_db::{Database, PersistentDb};
+use crate::trakt::t_outbox::Outbox;
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{NaiveDateTime, Utc};
+use log::*;
+
+// trakt keeps seconds, dates picked here are whole minutes
+pub const SAME_TIME_SECS: i64 = 60;
+
+/// What one side (our db or trakt) says about a show, season or episode.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ItemState {
+    /// not watched, or a show nobody decided on
+    Unset,
+    /// a show's status (Todo is the watchlist, Unwatched is hidden)
+    Status(UserStatusShow),
+    /// watched at this time (`None`: when it aired)
+    Watched(Option<NaiveDateTime>),
+}
+
+impl ItemState {
+    fn agrees(&self, other: &ItemState) -> bool {
+        match (self, other) {
+            (ItemState::Watched(Some(a)), ItemState::Watched(Some(b))) => {
+                (*a - *b).num_seconds().abs() < SAME_TIME_SECS
+            }
+            (ItemState::Watched(_), ItemState::Watched(_)) => true,
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl std::fmt::Display for ItemState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ItemState::Unset => write!(f, "-"),
+            ItemState::Status(UserStatusShow::Todo) => write!(f, "TODO"),
+            ItemState::Status(UserStatusShow::Watched) => write!(f, "WATCHED"),
+            ItemState::Status(UserStatusShow::Unwatched) => write!(f, "UNWATCHED"),
+            ItemState::Watched(Some(at)) => write!(f, "watched {}", at.format("%Y-%m-%d %H:%M")),
+            ItemState::Watched(None) => write!(f, "watched on release"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Classification {
+    Identical,
+    LocalOnly,
+    RemoteOnly,
+    /// both sides say something, and it isn't the same thing
+    Conflict,
+}
+
+impl Classification {
+    pub fn of(local: &ItemState, remote: &ItemState) -> Classification {
+        match (local, remote) {
+            (l, r) if l.agrees(r) => Classification::Identical,
+            (_, ItemState::Unset) => Classification::LocalOnly,
+            (ItemState::Unset, _) => Classification::RemoteOnly,
+            _ => Classification::Conflict,
+        }
+    }
+}
+
+/// Which side to keep when settling a conflict.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Winner {
+    Local,
+    Remote,
+}
+
+/// One show, season or episode as the db and trakt see it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconcileItem {
+    pub kind: SyncItemKind,
+    pub trakt_id: i32,
+    /// e.g. "Breaking Bad S01E03"
+    pub label: String,
+    pub local: ItemState,
+    pub remote: ItemState,
+    pub class: Classification,
+}
+
+/// The rows to compare with trakt (see `Database::local_state`).
+#[derive(Clone, Debug, Default)]
+pub struct LocalState {
+    pub shows: Vec<TraktShow>,
+    pub seasons: Vec<TraktSeason>,
+    pub episodes: Vec<TraktEpisode>,
+}
+
+/// Everything trakt knows about the user's shows.
+#[derive(Debug, Default)]
+pub struct RemoteState {
+    pub watched: Vec<ApiWatchedShow>,
+    pub watchlist: Vec<ApiListedShow>,
+    pub hidden: Vec<ApiHiddenShow>,
+}
+
+impl RemoteState {
+    pub fn imdb_ids(&self) -> Vec<String> {
+        let watched = self.watched.iter().map(|w| &w.show.ids);
+        let watchlist = self.watchlist.iter().map(|l| &l.show.ids);
+        let hidden = self.hidden.iter().map(|h| &h.show.ids);
+        watched
+            .chain(watchlist)
+            .chain(hidden)
+            .filter_map(|ids| ids.imdb.clone())
+            .collect()
+    }
+}
+
+pub async fn fetch_remote(client: &TraktClient) -> Result<RemoteState, TraktApiError> {
+    Ok(RemoteState {
+        watched: t_api::query_watched_shows(client).await?,
+        watchlist: t_api::query_watchlist(client).await?,
+        hidden: t_api::query_hidden(client).await?,
+    })
+}
+
+fn show_state(show: &TraktShow) -> ItemState {
+    match show.user_status {
+        // fresh from the IMDB dump, nobody decided anything
+        UserStatusShow::Todo if show.status_changed_at.is_none() => ItemState::Unset,
+        ref status => ItemState::Status(status.clone()),
+    }
+}
+
+fn season_state(season: &TraktSeason) -> ItemState {
+    match season.user_status {
+        UserStatusSeason::Unfilled => ItemState::Unset,
+        UserStatusSeason::OnRelease => ItemState::Watched(None),
+        UserStatusSeason::OtherDate => ItemState::Watched(season.watched_at),
+    }
+}
+
+fn episode_state(episode: &TraktEpisode) -> ItemState {
+    match episode.user_status {
+        UserStatusEpisode::Unwatched => ItemState::Unset,
+        UserStatusEpisode::Watched => ItemState::Watched(episode.watched_at),
+    }
+}
+
+/// Compare every local show, season and episode with trakt.
+/// Items that are unset on both sides are left out.
+pub fn reconcile(local: &LocalState, remote: &RemoteState) -> Vec<ReconcileItem> {
+    let watchlist: HashSet<u32> = remote.watchlist.iter().map(|l| l.show.ids.trakt).collect();
+    let hidden: HashSet<u32> = remote.hidden.iter().map(|h| h.show.ids.trakt).collect();
+    let watched: HashMap<u32, &ApiWatchedShow> = remote
+        .watched
+        .iter()
+        .map(|w| (w.show.ids.trakt, w))
+        .collect();
+    // (show, season, episode) -> last watched
+    let plays: HashMap<(u32, usize, usize), Option<NaiveDateTime>> = remote
+        .watched
+        .iter()
+        .flat_map(|w| {
+            w.seasons.iter().flat_map(move |s| {
+                s.episodes.iter().map(move |e| {
+                    (
+                        (w.show.ids.trakt, s.number, e.number),
+                        e.last_watched_at.map(|d| d.naive_utc()),
+                    )
+                })
+            })
+        })
+        .collect();
+
+    let titles: HashMap<i32, &str> = local
+        .shows
+        .iter()
+        .filter_map(|s| Some((s.trakt_id?, s.primary_title.as_str())))
+        .collect();
+    let title = |show_id: i32| titles.get(&show_id).copied().unwrap_or("?").to_string();
+
+    let mut items = vec![];
+    let mut push = |kind, trakt_id, label, local: ItemState, remote: ItemState| {
+        if local == ItemState::Unset && remote == ItemState::Unset {
+            return;
+        }
+        let class = Classification::of(&local, &remote);
+        items.push(ReconcileItem {
+            kind,
+            trakt_id,
+            label,
+            local,
+            remote,
+            class,
+        });
+    };
+
+    for show in local.shows.iter() {
+        let Some(trakt_id) = show.trakt_id else {
+            continue;
+        };
+        let id = trakt_id as u32;
+        let fully_watched = watched.get(&id).is_some_and(|w| {
+            let episodes = w.seasons.iter().map(|s| s.episodes.len()).sum::<usize>();
+            w.show.aired_episodes > 0 && episodes >= w.show.aired_episodes as usize
+        });
+
+        let remote = if fully_watched {
+            ItemState::Status(UserStatusShow::Watched)
+        } else if hidden.contains(&id) {
+            ItemState::Status(UserStatusShow::Unwatched)
+        } else if watchlist.contains(&id) {
+            ItemState::Status(UserStatusShow::Todo)
+        } else {
+            ItemState::Unset
+        };
+        push(
+            SyncItemKind::Show,
+            trakt_id,
+            show.primary_title.clone(),
+            show_state(show),
+            remote,
+        );
+    }
+
+    for season in local.seasons.iter() {
+        let show_id = season.show_id as u32;
+        let number = season.season_number as usize;
+        let dates: Vec<Option<NaiveDateTime>> = plays
+            .iter()
+            .filter(|((s, n, _), _)| *s == show_id && *n == number)
+            .map(|(_, at)| *at)
+            .collect();
+
+        let remote = if season.episode_count > 0 && dates.len() >= season.episode_count as usize {
+            ItemState::Watched(dates.into_iter().max().flatten())
+        } else {
+            ItemState::Unset
+        };
+        push(
+            SyncItemKind::Season,
+            season.id,
+            format!("{} S{:02}", title(season.show_id), season.season_number),
+            season_state(season),
+            remote,
+        );
+    }
+
+    for episode in local.episodes.iter() {
+        let key = (
+            episode.show_id as u32,
+            episode.season_number as usize,
+            episode.episode_number as usize,
+        );
+        let remote = match plays.get(&key) {
+            Some(at) => ItemState::Watched(*at),
+            None => ItemState::Unset,
+        };
+        push(
+            SyncItemKind::Episode,
+            episode.id,
+            format!(
+                "{} S{:02}E{:02}",
+                title(episode.show_id),
+                episode.season_number,
+                episode.episode_number
+            ),
+            episode_state(episode),
+            remote,
+        );
+    }
+
+    items
+}
+
+/// A show as it would be if `state` were its status.
+pub fn show_with(show: &TraktShow, state: &ItemState) -> TraktShow {
+    let (user_status, status_changed_at) = match state {
+        ItemState::Status(status) => (status.clone(), Some(Utc::now().naive_utc())),
+        _ => (UserStatusShow::Todo, None),
+    };
+    TraktShow {
+        user_status,
+        status_changed_at,
+        ..show.clone()
+    }
+}
+
+pub fn season_with(season: &TraktSeason, state: &ItemState) -> TraktSeason {
+    let (user_status, watched_at) = match state {
+        ItemState::Watched(Some(at)) => (UserStatusSeason::OtherDate, Some(*at)),
+        ItemState::Watched(None) => (UserStatusSeason::OnRelease, None),
+        _ => (UserStatusSeason::Unfilled, None),
+    };
+    TraktSeason {
+        user_status,
+        watched_at,
+        ..season.clone()
+    }
+}
+
+pub fn episode_with(episode: &TraktEpisode, state: &ItemState) -> TraktEpisode {
+    let (user_status, watched_at) = match state {
+        ItemState::Watched(at) => (UserStatusEpisode::Watched, at.or(episode.first_aired)),
+        _ => (UserStatusEpisode::Unwatched, None),
+    };
+    TraktEpisode {
+        user_status,
+        watched_at,
+        ..episode.clone()
+    }
+}
+
+/// Make both sides agree on `item`: keeping ours queues it for trakt,
+/// keeping trakt's overwrites the local row (and marks it as synced).
+pub async fn resolve(
+    cache: &PersistentDb,
+    outbox: &Outbox,
+    local: &LocalState,
+    item: &ReconcileItem,
+    winner: Winner,
+) -> eyre::Result<()> {
+    let watched_remotely = matches!(
+        item.remote,
+        ItemState::Watched(_) | ItemState::Status(UserStatusShow::Watched)
+    );
+    let synced = |watched_at| SyncedItem {
+        kind: item.kind,
+        trakt_id: item.trakt_id,
+        watched_at,
+        synced_at: Utc::now().naive_utc(),
+        // trakt had it already
+        pushed: false,
+    };
+
+    match item.kind {
+        SyncItemKind::Show => {
+            let Some(show) = local
+                .shows
+                .iter()
+                .find(|s| s.trakt_id == Some(item.trakt_id))
+            else {
+                eyre::bail!("no local show with trakt id {}", item.trakt_id);
+            };
+            let remote = show_with(show, &item.remote);
+            match winner {
+                Winner::Local => outbox.show_changed(show, &remote).await?,
+                Winner::Remote => cache.update_show(remote).await?,
+            }
+        }
+        SyncItemKind::Season => {
+            let Some(season) = local.seasons.iter().find(|s| s.id == item.trakt_id) else {
+                eyre::bail!("no local season with trakt id {}", item.trakt_id);
+            };
+            let remote = season_with(season, &item.remote);
+            match winner {
+                Winner::Local => outbox.season_changed(season, &remote).await?,
+                Winner::Remote => {
+                    let watched_at = remote.watched_at;
+                    cache.update_season(remote).await?;
+                    if watched_remotely {
+                        cache.record_synced(vec![synced(watched_at)]).await?;
+                    } else {
+                        cache.forget_synced(item.kind, item.trakt_id).await?;
+                    }
+                }
+            }
+        }
+        SyncItemKind::Episode => {
+            let Some(episode) = local.episodes.iter().find(|e| e.id == item.trakt_id) else {
+                eyre::bail!("no local episode with trakt id {}", item.trakt_id);
+            };
+            let remote = episode_with(episode, &item.remote);
+            match winner {
+                Winner::Local => outbox.episode_changed(episode, remote.watched_at).await?,
+                Winner::Remote => {
+                    let watched_at = remote.watched_at;
+                    cache.update_episode(remote).await?;
+                    if watched_remotely {
+                        cache.record_synced(vec![synced(watched_at)]).await?;
+                    } else {
+                        cache.forget_synced(item.kind, item.trakt_id).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    debug!("Resolved {} for {:?}", item.label, winner);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OutboxOperation;
+    use crate::trakt::t_mock::start_logged_in;
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(2019, 3, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn classifies_both_sides() {
+        let watched = |d| ItemState::Watched(Some(at(d, 21)));
+        let todo = ItemState::Status(UserStatusShow::Todo);
+        let hidden = ItemState::Status(UserStatusShow::Unwatched);
+
+        assert_eq!(
+            Classification::of(&watched(1), &watched(1)),
+            Classification::Identical
+        );
+        // a season watched "on release" agrees with any date
+        assert_eq!(
+            Classification::of(&ItemState::Watched(None), &watched(1)),
+            Classification::Identical
+        );
+        assert_eq!(
+            Classification::of(&watched(1), &ItemState::Unset),
+            Classification::LocalOnly
+        );
+        assert_eq!(
+            Classification::of(&ItemState::Unset, &todo),
+            Classification::RemoteOnly
+        );
+        assert_eq!(
+            Classification::of(&watched(1), &watched(2)),
+            Classification::Conflict
+        );
+        assert_eq!(Classification::of(&todo, &hidden), Classification::Conflict);
+    }
+
+    #[tokio::test]
+    async fn finds_and_resolves_conflicts() {
+        let (_mock, cache, client) = start_logged_in().await;
+        let outbox = Outbox::new(cache.clone());
+
+        // start out in line with trakt
+        crate::trakt::t_import::pull_watched(&client, &cache)
+            .await
+            .unwrap();
+        let remote = fetch_remote(&client).await.unwrap();
+        let local = cache.local_state(remote.imdb_ids()).await.unwrap();
+        let items = reconcile(&local, &remote);
+        assert!(items.iter().all(|i| i.class != Classification::Conflict));
+
+        // then watch the pilot of breaking bad on another day
+        let mut pilot = local
+            .episodes
+            .iter()
+            .find(|e| e.id == 73482)
+            .unwrap()
+            .clone();
+        pilot.watched_at = Some(at(20, 12));
+        cache.update_episode(pilot).await.unwrap();
+
+        let local = cache.local_state(remote.imdb_ids()).await.unwrap();
+        let items = reconcile(&local, &remote);
+        let conflicts: Vec<_> = items
+            .iter()
+            .filter(|i| i.class == Classification::Conflict)
+            .collect();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].label, "Breaking Bad S01E01");
+        assert_eq!(conflicts[0].remote, ItemState::Watched(Some(at(1, 21))));
+
+        // trakt wins: the pilot goes back to its original date
+        resolve(&cache, &outbox, &local, conflicts[0], Winner::Remote)
+            .await
+            .unwrap();
+        let local = cache.local_state(remote.imdb_ids()).await.unwrap();
+        let pilot = local.episodes.iter().find(|e| e.id == 73482).unwrap();
+        assert_eq!(pilot.watched_at, Some(at(1, 21)));
+        assert_eq!(outbox.pending().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn taking_an_unwatched_season_from_trakt_forgets_it() {
+        let (_mock, cache, client) = start_logged_in().await;
+        let outbox = Outbox::new(cache.clone());
+        crate::trakt::t_import::pull_watched(&client, &cache)
+            .await
+            .unwrap();
+        let remote = fetch_remote(&client).await.unwrap();
+        let local = cache.local_state(remote.imdb_ids()).await.unwrap();
+        let is_synced = |items: Vec<SyncedItem>| {
+            items
+                .iter()
+                .any(|i| i.kind == SyncItemKind::Season && i.trakt_id == 3950)
+        };
+        assert!(is_synced(cache.synced_items().await.unwrap()));
+
+        // as if breaking bad's first season had since been unwatched on trakt
+        let item = ReconcileItem {
+            kind: SyncItemKind::Season,
+            trakt_id: 3950,
+            label: "Breaking Bad S01".to_string(),
+            local: ItemState::Watched(Some(at(7, 21))),
+            remote: ItemState::Unset,
+            class: Classification::LocalOnly,
+        };
+        resolve(&cache, &outbox, &local, &item, Winner::Remote)
+            .await
+            .unwrap();
+
+        // so watching it again later gets pushed
+        assert!(!is_synced(cache.synced_items().await.unwrap()));
+        let local = cache.local_state(remote.imdb_ids()).await.unwrap();
+        let season = local.seasons.iter().find(|s| s.id == 3950).unwrap();
+        assert_eq!(season.user_status, UserStatusSeason::Unfilled);
+    }
+
+    #[tokio::test]
+    async fn keeping_ours_queues_it_for_trakt() {
+        let (_mock, cache, client) = start_logged_in().await;
+        let outbox = Outbox::new(cache.clone());
+        crate::trakt::t_import::pull_watched(&client, &cache)
+            .await
+            .unwrap();
+        let remote = fetch_remote(&client).await.unwrap();
+        let local = cache.local_state(remote.imdb_ids()).await.unwrap();
+
+        // hide severance (which trakt has as watched), and rewatch breaking bad's first season
+        let severance = local.shows.iter().find(|s| s.trakt_id == Some(154997));
+        cache
+            .update_show(TraktShow {
+                user_status: UserStatusShow::Unwatched,
+                status_changed_at: Some(Utc::now().naive_utc()),
+                ..severance.unwrap().clone()
+            })
+            .await
+            .unwrap();
+        let season_one = local.seasons.iter().find(|s| s.id == 3950).unwrap();
+        cache
+            .update_season(TraktSeason {
+                watched_at: Some(at(25, 12)),
+                ..season_one.clone()
+            })
+            .await
+            .unwrap();
+
+        let local = cache.local_state(remote.imdb_ids()).await.unwrap();
+        let items = reconcile(&local, &remote);
+        let conflict = |kind, trakt_id| {
+            items
+                .iter()
+                .find(|i| i.kind == kind && i.trakt_id == trakt_id)
+                .filter(|i| i.class == Classification::Conflict)
+                .unwrap()
+        };
+        let show = conflict(SyncItemKind::Show, 154997);
+        assert_eq!(show.label, "Severance");
+        assert_eq!(show.remote, ItemState::Status(UserStatusShow::Watched));
+        let season = conflict(SyncItemKind::Season, 3950);
+        assert_eq!(season.label, "Breaking Bad S01");
+        assert_eq!(season.local, ItemState::Watched(Some(at(25, 12))));
+        assert_eq!(season.remote, ItemState::Watched(Some(at(7, 21))));
+
+        for item in [show, season] {
+            resolve(&cache, &outbox, &local, item, Winner::Local)
+                .await
+                .unwrap();
+        }
+
+        // the show is only hidden (keeping its history), and the old play is taken off
+        // before the new one goes on
+        let queued: Vec<_> = cache
+            .pending_outbox()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|i| (i.kind, i.trakt_id, i.operation, i.watched_at))
+            .collect();
+        assert_eq!(
+            queued,
+            vec![
+                (SyncItemKind::Show, 154997, OutboxOperation::Hide, None),
+                (
+                    SyncItemKind::Season,
+                    3950,
+                    OutboxOperation::RemoveHistory,
+                    None
+                ),
+                (
+                    SyncItemKind::Season,
+                    3950,
+                    OutboxOperation::AddHistory,
+                    Some(at(25, 12))
+                ),
+            ]
+        );
+        // and the db keeps what we had
+        let after = cache.local_state(remote.imdb_ids()).await.unwrap();
+        assert_eq!(after.shows, local.shows);
+        assert_eq!(after.seasons, local.seasons);
+    }
+}
@@ -0,0 +1,1470 @@
+use crate::models::{
+    HistoryChange, OutboxItem, OutboxOperation, SyncBatch, SyncBatchItem, SyncItemKind, SyncedItem,
+    TraktEpisode, TraktSeason, TraktShow, UserStatusSeason, UserStatusShow,
+};
+use crate::trakt::t_api::{self, ApiHistoryItem, TraktClient};
+use crate::trakt::t_db::{Database, PersistentDb};
+use crate::trakt::t_reconcile::{
+    episode_with, season_with, show_with, ItemState, LocalState, SAME_TIME_SECS,
+};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use log::*;
+use serde::{Deserialize, Serialize, Serializer};
+
+// number of shows/seasons/episodes sent in a single /sync/history request
+const BATCH_SIZE: usize = 100;
+
+/// Everything in the local db that could be marked as watched on trakt.
+#[derive(Clone, Debug, Default)]
+pub struct LocalHistory {
+    pub shows: Vec<TraktShow>,
+    pub seasons: Vec<TraktSeason>,
+    pub episodes: Vec<TraktEpisode>,
+}
+
+/// When an item was watched. trakt accepts "released" to mean "on the item's release date".
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatchedAt {
+    Released,
+    At(NaiveDateTime),
+}
+
+impl WatchedAt {
+    pub fn as_naive(&self) -> Option<NaiveDateTime> {
+        match self {
+            WatchedAt::Released => None,
+            WatchedAt::At(dt) => Some(*dt),
+        }
+    }
+}
+
+impl Serialize for WatchedAt {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            WatchedAt::Released => serializer.serialize_str("released"),
+            WatchedAt::At(dt) => Utc.from_utc_datetime(dt).serialize(serializer),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryIds {
+    pub trakt: Option<u32>,
+}
+
+impl HistoryIds {
+    fn trakt(id: i32) -> Self {
+        HistoryIds {
+            trakt: Some(id as u32),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HistorySeason {
+    pub number: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watched_at: Option<WatchedAt>,
+    #[serde(skip)]
+    trakt_id: i32,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HistoryShow {
+    pub ids: HistoryIds,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watched_at: Option<WatchedAt>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub seasons: Vec<HistorySeason>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HistoryEpisode {
+    pub ids: HistoryIds,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watched_at: Option<WatchedAt>,
+}
+
+// POST sync/history
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct HistoryPayload {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shows: Vec<HistoryShow>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub episodes: Vec<HistoryEpisode>,
+}
+
+impl HistoryPayload {
+    /// Payload for a whole show.
+    pub fn show(trakt_id: i32, watched_at: Option<WatchedAt>) -> Self {
+        HistoryPayload {
+            shows: vec![HistoryShow {
+                ids: HistoryIds::trakt(trakt_id),
+                watched_at,
+                seasons: vec![],
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Payload for a single season of a show.
+    pub fn season(season: &TraktSeason, watched_at: Option<WatchedAt>) -> Self {
+        HistoryPayload {
+            shows: vec![HistoryShow {
+                ids: HistoryIds::trakt(season.show_id),
+                watched_at: None,
+                seasons: vec![HistorySeason {
+                    number: season.season_number,
+                    watched_at,
+                    trakt_id: season.id,
+                }],
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Payload for a single episode.
+    pub fn episode(trakt_id: i32, watched_at: Option<WatchedAt>) -> Self {
+        HistoryPayload {
+            episodes: vec![HistoryEpisode {
+                ids: HistoryIds::trakt(trakt_id),
+                watched_at,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Number of shows, seasons and episodes in this payload.
+    pub fn len(&self) -> usize {
+        self.shows
+            .iter()
+            .map(|s| s.seasons.len().max(1))
+            .sum::<usize>()
+            + self.episodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shows.is_empty() && self.episodes.is_empty()
+    }
+
+    /// The history entries we'd record if trakt accepts this whole payload.
+    fn items(&self) -> Vec<(SyncItemKind, i32, Option<NaiveDateTime>)> {
+        let mut items = vec![];
+        for show in self.shows.iter() {
+            if show.seasons.is_empty() {
+                let watched_at = show.watched_at.as_ref().and_then(WatchedAt::as_naive);
+                items.push((
+                    SyncItemKind::Show,
+                    show.ids.trakt.unwrap() as i32,
+                    watched_at,
+                ));
+            }
+            for season in show.seasons.iter() {
+                let watched_at = season.watched_at.as_ref().and_then(WatchedAt::as_naive);
+                items.push((SyncItemKind::Season, season.trakt_id, watched_at));
+            }
+        }
+        for episode in self.episodes.iter() {
+            let watched_at = episode.watched_at.as_ref().and_then(WatchedAt::as_naive);
+            items.push((
+                SyncItemKind::Episode,
+                episode.ids.trakt.unwrap() as i32,
+                watched_at,
+            ));
+        }
+        items
+    }
+
+    /// This payload without the `(kind, trakt_id)` items in `skipped`, if anything is left.
+    fn without(mut self, skipped: &HashSet<(SyncItemKind, i32)>) -> Option<Self> {
+        self.shows.retain_mut(|show| {
+            let trakt_id = show.ids.trakt.unwrap() as i32;
+            if show.seasons.is_empty() {
+                return !skipped.contains(&(SyncItemKind::Show, trakt_id));
+            }
+            show.seasons
+                .retain(|s| !skipped.contains(&(SyncItemKind::Season, s.trakt_id)));
+            !show.seasons.is_empty()
+        });
+        self.episodes
+            .retain(|e| !skipped.contains(&(SyncItemKind::Episode, e.ids.trakt.unwrap() as i32)));
+        (!self.is_empty()).then_some(self)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct HistoryCounts {
+    #[serde(default)]
+    pub movies: u32,
+    #[serde(default)]
+    pub episodes: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NotFoundItem {
+    pub ids: HistoryIds,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct HistoryNotFound {
+    #[serde(default)]
+    pub shows: Vec<NotFoundItem>,
+    #[serde(default)]
+    pub seasons: Vec<NotFoundItem>,
+    #[serde(default)]
+    pub episodes: Vec<NotFoundItem>,
+}
+
+impl HistoryNotFound {
+    pub fn contains(&self, kind: SyncItemKind, trakt_id: i32) -> bool {
+        let items = match kind {
+            SyncItemKind::Show => &self.shows,
+            SyncItemKind::Season => &self.seasons,
+            SyncItemKind::Episode => &self.episodes,
+        };
+        items.iter().any(|i| i.ids.trakt == Some(trakt_id as u32))
+    }
+}
+
+// response to POST sync/history
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryResponse {
+    pub added: HistoryCounts,
+    #[serde(default)]
+    pub not_found: HistoryNotFound,
+}
+
+// POST sync/history/remove, for single plays
+#[derive(Serialize, Clone, Debug, PartialEq)]
+struct PlayIds {
+    ids: Vec<u64>,
+}
+
+// response to POST sync/history/remove
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryRemoveResponse {
+    pub deleted: HistoryCounts,
+    #[serde(default)]
+    pub not_found: HistoryNotFound,
+}
+
+/// Totals over every batch of a sync.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncSummary {
+    pub batches: usize,
+    pub episodes_added: u32,
+    pub episodes_removed: u32,
+    pub accepted: usize,
+    pub not_found: usize,
+    /// the push as recorded in the db, if it changed anything (see `revert_batch`)
+    pub batch_id: Option<i32>,
+}
+
+/// When a season should be marked as watched on trakt (`None` if it isn't watched).
+pub fn season_watched_at(season: &TraktSeason) -> Option<Option<WatchedAt>> {
+    match season.user_status {
+        UserStatusSeason::Unfilled => None,
+        UserStatusSeason::OnRelease => Some(Some(WatchedAt::Released)),
+        // without a picked date, trakt will use the time of the sync
+        UserStatusSeason::OtherDate => Some(season.watched_at.map(WatchedAt::At)),
+    }
+}
+
+fn is_synced(
+    synced: &HashSet<(SyncItemKind, i32, Option<NaiveDateTime>)>,
+    kind: SyncItemKind,
+    trakt_id: i32,
+    watched_at: &Option<WatchedAt>,
+) -> bool {
+    let watched_at = watched_at.as_ref().and_then(WatchedAt::as_naive);
+    synced.contains(&(kind, trakt_id, watched_at))
+}
+
+/// Turn local watch statuses into `/sync/history` payloads of at most `BATCH_SIZE` items each.
+/// Items already recorded in `synced` (with the same watch time) are left out.
+pub fn history_payloads(
+    history: &LocalHistory,
+    synced_items: &[SyncedItem],
+) -> Vec<HistoryPayload> {
+    let synced: HashSet<_> = synced_items
+        .iter()
+        .map(|s| (s.kind, s.trakt_id, s.watched_at))
+        .collect();
+
+    let watched_shows: HashSet<i32> = history
+        .shows
+        .iter()
+        .filter(|show| show.user_status == UserStatusShow::Watched)
+        .filter_map(|show| show.trakt_id)
+        .collect();
+
+    let mut shows: BTreeMap<i32, HistoryShow> = BTreeMap::new();
+
+    for &trakt_id in watched_shows.iter() {
+        let watched_at = Some(WatchedAt::Released);
+        if is_synced(&synced, SyncItemKind::Show, trakt_id, &watched_at) {
+            continue;
+        }
+
+        shows.insert(
+            trakt_id,
+            HistoryShow {
+                ids: HistoryIds::trakt(trakt_id),
+                watched_at,
+                seasons: vec![],
+            },
+        );
+    }
+
+    for season in history.seasons.iter() {
+        let Some(watched_at) = season_watched_at(season) else {
+            continue;
+        };
+        // the whole show is already marked as watched
+        if watched_shows.contains(&season.show_id)
+            || is_synced(&synced, SyncItemKind::Season, season.id, &watched_at)
+        {
+            continue;
+        }
+
+        shows
+            .entry(season.show_id)
+            .or_insert_with(|| HistoryShow {
+                ids: HistoryIds::trakt(season.show_id),
+                watched_at: None,
+                seasons: vec![],
+            })
+            .seasons
+            .push(HistorySeason {
+                number: season.season_number,
+                watched_at,
+                trakt_id: season.id,
+            });
+    }
+
+    // episodes of seasons that are sent as a whole (e.g. stamped by ON_RELEASE) are covered
+    // by the season: all of them if it's going out now, otherwise the ones that had aired
+    // when it was synced. ones that aired since have to be sent on their own
+    let season_synced_at: HashMap<(i32, Option<NaiveDateTime>), NaiveDateTime> = synced_items
+        .iter()
+        .filter(|s| s.kind == SyncItemKind::Season)
+        .map(|s| ((s.trakt_id, s.watched_at), s.synced_at))
+        .collect();
+    let watched_seasons: HashMap<i32, Option<NaiveDateTime>> = history
+        .seasons
+        .iter()
+        .filter_map(|season| {
+            let watched_at = season_watched_at(season)?;
+            let watched_at = watched_at.as_ref().and_then(WatchedAt::as_naive);
+            let synced_at = season_synced_at.get(&(season.id, watched_at)).copied();
+            Some((season.id, synced_at))
+        })
+        .collect();
+
+    let episodes = history.episodes.iter().filter_map(|episode| {
+        let watched_at = Some(WatchedAt::At(episode.watched_at?));
+        match watched_seasons.get(&episode.season_id) {
+            Some(None) => return None,
+            Some(Some(synced_at))
+                if episode.first_aired.is_none_or(|aired| aired <= *synced_at) =>
+            {
+                return None
+            }
+            _ => {}
+        }
+        if is_synced(&synced, SyncItemKind::Episode, episode.id, &watched_at) {
+            return None;
+        }
+
+        Some(HistoryEpisode {
+            ids: HistoryIds::trakt(episode.id),
+            watched_at,
+        })
+    });
+
+    batch(shows, episodes)
+}
+
+/// Pack shows (split over several batches if they have lots of seasons) and episodes
+/// into payloads of at most `BATCH_SIZE` items each.
+fn batch(
+    shows: BTreeMap<i32, HistoryShow>,
+    episodes: impl IntoIterator<Item = HistoryEpisode>,
+) -> Vec<HistoryPayload> {
+    let mut payloads = vec![];
+    let mut current = HistoryPayload::default();
+
+    for show in shows.into_values() {
+        // a single show with lots of seasons is split up over several batches
+        let mut seasons = show.seasons.into_iter().peekable();
+        loop {
+            let room = BATCH_SIZE - current.len();
+            current.shows.push(HistoryShow {
+                ids: show.ids.clone(),
+                watched_at: show.watched_at.clone(),
+                seasons: seasons.by_ref().take(room).collect(),
+            });
+
+            if current.len() >= BATCH_SIZE {
+                payloads.push(std::mem::take(&mut current));
+            }
+            if seasons.peek().is_none() {
+                break;
+            }
+        }
+    }
+
+    for episode in episodes {
+        current.episodes.push(episode);
+        if current.len() >= BATCH_SIZE {
+            payloads.push(std::mem::take(&mut current));
+        }
+    }
+
+    if !current.is_empty() {
+        payloads.push(current);
+    }
+
+    payloads
+}
+
+/// Everything trakt has from us that the db no longer agrees with: unwatched since, or
+/// watched at another time (the new time is sent as an add). Items covered by a watched
+/// show or season are left alone, and so are hidden shows, which keep their history.
+pub fn stale_items(state: &LocalState, synced: &[SyncedItem]) -> Vec<SyncedItem> {
+    let shows: HashMap<i32, &TraktShow> = state
+        .shows
+        .iter()
+        .filter_map(|show| Some((show.trakt_id?, show)))
+        .collect();
+    let seasons: HashMap<i32, &TraktSeason> = state.seasons.iter().map(|s| (s.id, s)).collect();
+    let episodes: HashMap<i32, &TraktEpisode> = state.episodes.iter().map(|e| (e.id, e)).collect();
+
+    let show_watched = |show_id: i32| {
+        shows
+            .get(&show_id)
+            .is_some_and(|show| show.user_status == UserStatusShow::Watched)
+    };
+    let season_watched_at = |season: &TraktSeason| {
+        season_watched_at(season).map(|at| at.as_ref().and_then(WatchedAt::as_naive))
+    };
+    let watched_seasons: HashSet<(i32, i32)> = state
+        .seasons
+        .iter()
+        .filter(|season| season_watched_at(season).is_some())
+        .map(|season| (season.show_id, season.season_number))
+        .collect();
+
+    synced
+        .iter()
+        .filter(|item| match item.kind {
+            SyncItemKind::Show => shows.get(&item.trakt_id).is_some_and(|show| {
+                !matches!(
+                    show.user_status,
+                    UserStatusShow::Watched | UserStatusShow::Unwatched
+                )
+            }),
+            SyncItemKind::Season => seasons.get(&item.trakt_id).is_some_and(|season| {
+                !show_watched(season.show_id) && season_watched_at(season) != Some(item.watched_at)
+            }),
+            SyncItemKind::Episode => episodes.get(&item.trakt_id).is_some_and(|episode| {
+                !show_watched(episode.show_id)
+                    && !watched_seasons.contains(&(episode.show_id, episode.season_number))
+                    && episode.watched_at != item.watched_at
+            }),
+        })
+        .cloned()
+        .collect()
+}
+
+/// Everything a sync would send to trakt, built from the db alone.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct SyncPlan {
+    /// entries whose plays are taken off trakt first (see `remove_plays`)
+    pub remove: Vec<SyncedItem>,
+    /// POST sync/history
+    pub add: Vec<HistoryPayload>,
+}
+
+impl SyncPlan {
+    /// Items with history changes still waiting in the outbox are left to it, so they
+    /// don't reach trakt twice.
+    pub fn new(
+        history: &LocalHistory,
+        state: &LocalState,
+        synced: &[SyncedItem],
+        queued: &[OutboxItem],
+    ) -> SyncPlan {
+        let queued: HashSet<_> = queued
+            .iter()
+            .filter(|item| {
+                matches!(
+                    item.operation,
+                    OutboxOperation::AddHistory | OutboxOperation::RemoveHistory
+                )
+            })
+            .map(|item| (item.kind, item.trakt_id))
+            .collect();
+        SyncPlan {
+            remove: stale_items(state, synced)
+                .into_iter()
+                .filter(|item| !queued.contains(&(item.kind, item.trakt_id)))
+                .collect(),
+            add: history_payloads(history, synced)
+                .into_iter()
+                .filter_map(|p| p.without(&queued))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty() && self.add.is_empty()
+    }
+}
+
+/// Episodes a sync would add to / remove from one season's history.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct SeasonDiff {
+    pub number: i32,
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// Episodes a sync would add to / remove from one show's history, per season.
+/// A whole show is counted over the seasons we know about.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ShowDiff {
+    pub trakt_id: i32,
+    pub title: String,
+    pub added: usize,
+    pub removed: usize,
+    pub seasons: Vec<SeasonDiff>,
+}
+
+/// What a sync would send, as payloads and as episode counts per show and season.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct SyncPreview {
+    #[serde(flatten)]
+    pub plan: SyncPlan,
+    pub shows: Vec<ShowDiff>,
+}
+
+impl SyncPreview {
+    pub fn new(plan: SyncPlan, state: &LocalState) -> SyncPreview {
+        let titles: HashMap<i32, &str> = state
+            .shows
+            .iter()
+            .filter_map(|show| Some((show.trakt_id?, show.primary_title.as_str())))
+            .collect();
+        let seasons: HashMap<i32, &TraktSeason> = state.seasons.iter().map(|s| (s.id, s)).collect();
+        let episodes: HashMap<i32, &TraktEpisode> =
+            state.episodes.iter().map(|e| (e.id, e)).collect();
+
+        // show -> season number -> (added, removed)
+        let mut counts: BTreeMap<i32, BTreeMap<i32, (usize, usize)>> = BTreeMap::new();
+        let mut count = |show_id: i32, number: i32, episodes: usize, added: bool| {
+            let (a, r) = counts
+                .entry(show_id)
+                .or_default()
+                .entry(number)
+                .or_default();
+            *(if added { a } else { r }) += episodes;
+        };
+
+        let added = plan
+            .add
+            .iter()
+            .flat_map(|p| p.items())
+            .map(|(kind, trakt_id, _)| (kind, trakt_id, true));
+        let removed = plan
+            .remove
+            .iter()
+            .map(|item| (item.kind, item.trakt_id, false));
+        for (kind, trakt_id, added) in added.chain(removed) {
+            match kind {
+                SyncItemKind::Show => {
+                    let show_seasons = state.seasons.iter().filter(|s| s.show_id == trakt_id);
+                    for season in show_seasons {
+                        count(
+                            trakt_id,
+                            season.season_number,
+                            season.episode_count as usize,
+                            added,
+                        );
+                    }
+                }
+                SyncItemKind::Season => {
+                    if let Some(season) = seasons.get(&trakt_id) {
+                        let n = season.episode_count as usize;
+                        count(season.show_id, season.season_number, n, added);
+                    }
+                }
+                SyncItemKind::Episode => {
+                    if let Some(episode) = episodes.get(&trakt_id) {
+                        count(episode.show_id, episode.season_number, 1, added);
+                    }
+                }
+            }
+        }
+
+        let mut shows: Vec<ShowDiff> = counts
+            .into_iter()
+            .map(|(trakt_id, seasons)| {
+                let seasons: Vec<SeasonDiff> = seasons
+                    .into_iter()
+                    .map(|(number, (added, removed))| SeasonDiff {
+                        number,
+                        added,
+                        removed,
+                    })
+                    .collect();
+                ShowDiff {
+                    trakt_id,
+                    title: titles.get(&trakt_id).copied().unwrap_or("?").to_string(),
+                    added: seasons.iter().map(|s| s.added).sum(),
+                    removed: seasons.iter().map(|s| s.removed).sum(),
+                    seasons,
+                }
+            })
+            .collect();
+        shows.sort_by(|a, b| a.title.cmp(&b.title));
+
+        SyncPreview { plan, shows }
+    }
+}
+
+/// Work out what a sync would send, without contacting trakt.
+pub async fn preview_sync(cache: &PersistentDb) -> eyre::Result<SyncPreview> {
+    let history = cache.local_history().await?;
+    let state = cache.local_state(vec![]).await?;
+    let synced = cache.synced_items().await?;
+    let queued = cache.pending_outbox().await?;
+    let plan = SyncPlan::new(&history, &state, &synced, &queued);
+    Ok(SyncPreview::new(plan, &state))
+}
+
+/// Figure out which entries of a payload trakt accepted.
+fn accepted_items(payload: &HistoryPayload, response: &HistoryResponse) -> Vec<SyncedItem> {
+    let now = Utc::now().naive_utc();
+    let rejected_shows: HashSet<_> = payload
+        .shows
+        .iter()
+        .filter(|s| {
+            let id = s.ids.trakt.unwrap() as i32;
+            response.not_found.contains(SyncItemKind::Show, id)
+        })
+        .flat_map(|s| s.seasons.iter().map(|season| season.trakt_id))
+        .collect();
+
+    payload
+        .items()
+        .into_iter()
+        .filter(|(kind, trakt_id, _)| {
+            !response.not_found.contains(*kind, *trakt_id)
+                && !(*kind == SyncItemKind::Season && rejected_shows.contains(trakt_id))
+        })
+        .map(|(kind, trakt_id, watched_at)| SyncedItem {
+            kind,
+            trakt_id,
+            watched_at,
+            synced_at: now,
+            pushed: true,
+        })
+        .collect()
+}
+
+/// Whether `play` is one we'd have added for `item`: watched at the time we sent, or for
+/// one sent without a time, when the episode aired ("released") or at the time of the sync.
+fn is_our_play(item: &SyncedItem, play: &ApiHistoryItem) -> bool {
+    let near =
+        |at: NaiveDateTime| (play.watched_at.naive_utc() - at).num_seconds().abs() < SAME_TIME_SECS;
+    let aired = play
+        .episode
+        .as_ref()
+        .and_then(|e| e.first_aired)
+        .map(|d| d.naive_utc());
+    play.action == "watch"
+        && match item.watched_at {
+            Some(at) => near(at),
+            None => near(item.synced_at) || aired.is_some_and(near),
+        }
+}
+
+/// Take the plays we added for `items` off trakt, and only those: other plays of the same
+/// shows and episodes (watched before, or since) stay. The plays are looked up in the
+/// user's history and removed by id, at most one per episode of each item.
+/// Entries we didn't push ourselves are skipped.
+pub async fn remove_plays(
+    client: &TraktClient,
+    items: &[SyncedItem],
+    summary: &mut SyncSummary,
+) -> eyre::Result<()> {
+    let mut ids = vec![];
+    for item in items.iter().filter(|item| item.pushed) {
+        let item_type = match item.kind {
+            SyncItemKind::Show => "shows",
+            SyncItemKind::Season => "seasons",
+            SyncItemKind::Episode => "episodes",
+        };
+        let plays = t_api::query_history(client, item_type, item.trakt_id).await?;
+        let mut episodes = HashSet::new();
+        for play in plays.iter().filter(|play| is_our_play(item, play)) {
+            let episode_id = play.episode.as_ref().map(|e| e.ids.trakt);
+            if !ids.contains(&play.id) && episodes.insert(episode_id) {
+                ids.push(play.id);
+            }
+        }
+    }
+
+    for chunk in ids.chunks(BATCH_SIZE) {
+        let payload = PlayIds {
+            ids: chunk.to_vec(),
+        };
+        let text = t_api::do_post(client, "sync/history/remove", &payload).await?;
+        let response = t_api::decode::<HistoryRemoveResponse>(&text)?;
+        info!("Synced batch: {} plays removed", response.deleted.episodes);
+
+        summary.batches += 1;
+        summary.episodes_removed += response.deleted.episodes;
+    }
+
+    Ok(())
+}
+
+/// Push everything watched in the local db (and not yet synced) to trakt's history,
+/// and take off whatever the db no longer has as watched.
+/// The entries trakt accepted are recorded as a sync batch, even if a later request fails.
+/// Items the outbox has yet to send are left to it.
+pub async fn push_history(client: &TraktClient, cache: &PersistentDb) -> eyre::Result<SyncSummary> {
+    let history = cache.local_history().await?;
+    let state = cache.local_state(vec![]).await?;
+    let synced = cache.synced_items().await?;
+    let queued = cache.pending_outbox().await?;
+    let plan = SyncPlan::new(&history, &state, &synced, &queued);
+
+    let mut summary = SyncSummary::default();
+    let mut changes = vec![];
+    let pushed = send_plan(client, cache, &plan, &synced, &mut summary, &mut changes).await;
+
+    if !changes.is_empty() {
+        let batch_id = cache
+            .record_sync_batch(Utc::now().naive_utc(), changes)
+            .await?;
+        summary.batch_id = Some(batch_id);
+    }
+
+    pushed.map(|_| summary)
+}
+
+async fn send_plan(
+    client: &TraktClient,
+    cache: &PersistentDb,
+    plan: &SyncPlan,
+    synced: &[SyncedItem],
+    summary: &mut SyncSummary,
+    changes: &mut Vec<SyncBatchItem>,
+) -> eyre::Result<()> {
+    // what was last in line with trakt, which a revert puts back locally
+    let previous: HashMap<(SyncItemKind, i32), Option<NaiveDateTime>> = synced
+        .iter()
+        .map(|s| ((s.kind, s.trakt_id), s.watched_at))
+        .collect();
+    let change = |kind, trakt_id, change, watched_at, pushed| {
+        let previous = previous.get(&(kind, trakt_id)).copied();
+        SyncBatchItem {
+            batch_id: 0,
+            kind,
+            trakt_id,
+            change,
+            watched_at,
+            pushed,
+            previous_watched: previous.is_some(),
+            previous_watched_at: previous.flatten(),
+        }
+    };
+
+    // removals first, so a changed watch time replaces the old play instead of adding one
+    remove_plays(client, &plan.remove, summary).await?;
+    for item in plan.remove.iter() {
+        cache.forget_synced(item.kind, item.trakt_id).await?;
+        // what trakt had before, so a revert can put it back
+        changes.push(change(
+            item.kind,
+            item.trakt_id,
+            HistoryChange::Removed,
+            item.watched_at,
+            item.pushed,
+        ));
+    }
+
+    for payload in plan.add.iter() {
+        let text = t_api::do_post(client, "sync/history", payload).await?;
+        let response = t_api::decode::<HistoryResponse>(&text)?;
+
+        let accepted = accepted_items(payload, &response);
+        info!(
+            "Synced batch: {} episodes added, {}/{} items accepted",
+            response.added.episodes,
+            accepted.len(),
+            payload.len()
+        );
+
+        summary.batches += 1;
+        summary.episodes_added += response.added.episodes;
+        summary.not_found += payload.len() - accepted.len();
+        summary.accepted += accepted.len();
+
+        changes.extend(
+            accepted
+                .iter()
+                .map(|a| change(a.kind, a.trakt_id, HistoryChange::Added, a.watched_at, true)),
+        );
+        cache.record_synced(accepted).await?;
+    }
+
+    Ok(())
+}
+
+/// Payloads adding the given shows/seasons/episodes back with their watch times.
+/// Items the db doesn't have are left out.
+fn item_payloads<'a>(
+    items: impl Iterator<Item = &'a SyncBatchItem>,
+    state: &LocalState,
+) -> Vec<HistoryPayload> {
+    let seasons: HashMap<i32, &TraktSeason> = state.seasons.iter().map(|s| (s.id, s)).collect();
+    let watched_at =
+        |item: &SyncBatchItem| Some(item.watched_at.map_or(WatchedAt::Released, WatchedAt::At));
+
+    let mut shows: BTreeMap<i32, HistoryShow> = BTreeMap::new();
+    let mut episodes = vec![];
+    for item in items {
+        match item.kind {
+            SyncItemKind::Show => {
+                let show = shows.entry(item.trakt_id).or_insert_with(|| HistoryShow {
+                    ids: HistoryIds::trakt(item.trakt_id),
+                    watched_at: None,
+                    seasons: vec![],
+                });
+                show.watched_at = Some(WatchedAt::Released);
+            }
+            SyncItemKind::Season => {
+                let Some(season) = seasons.get(&item.trakt_id) else {
+                    continue;
+                };
+                shows
+                    .entry(season.show_id)
+                    .or_insert_with(|| HistoryShow {
+                        ids: HistoryIds::trakt(season.show_id),
+                        watched_at: None,
+                        seasons: vec![],
+                    })
+                    .seasons
+                    .push(HistorySeason {
+                        number: season.season_number,
+                        watched_at: watched_at(item),
+                        trakt_id: season.id,
+                    });
+            }
+            SyncItemKind::Episode => episodes.push(HistoryEpisode {
+                ids: HistoryIds::trakt(item.trakt_id),
+                watched_at: watched_at(item),
+            }),
+        }
+    }
+
+    batch(shows, episodes)
+}
+
+/// Put a local show/season/episode back the way it was before a push.
+async fn restore_local(
+    cache: &PersistentDb,
+    state: &LocalState,
+    kind: SyncItemKind,
+    trakt_id: i32,
+    watched: Option<Option<NaiveDateTime>>,
+) -> eyre::Result<()> {
+    let item_state = match (kind, watched) {
+        (_, None) => ItemState::Unset,
+        (SyncItemKind::Show, Some(_)) => ItemState::Status(UserStatusShow::Watched),
+        (_, Some(at)) => ItemState::Watched(at),
+    };
+
+    match kind {
+        SyncItemKind::Show => {
+            if let Some(show) = state.shows.iter().find(|s| s.trakt_id == Some(trakt_id)) {
+                cache.update_show(show_with(show, &item_state)).await?;
+            }
+        }
+        SyncItemKind::Season => {
+            if let Some(season) = state.seasons.iter().find(|s| s.id == trakt_id) {
+                cache
+                    .update_season(season_with(season, &item_state))
+                    .await?;
+            }
+        }
+        SyncItemKind::Episode => {
+            if let Some(episode) = state.episodes.iter().find(|e| e.id == trakt_id) {
+                cache
+                    .update_episode(episode_with(episode, &item_state))
+                    .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Undo a push: take the plays it added off trakt's history, put back what it removed,
+/// and return the local statuses to what they were before it.
+pub async fn revert_batch(
+    client: &TraktClient,
+    cache: &PersistentDb,
+    batch: &SyncBatch,
+    items: &[SyncBatchItem],
+) -> eyre::Result<SyncSummary> {
+    if batch.reverted_at.is_some() {
+        eyre::bail!("sync batch {} has already been reverted", batch.id);
+    }
+
+    let state = cache.local_state(vec![]).await?;
+    let added: Vec<SyncedItem> = items
+        .iter()
+        .filter(|i| i.change == HistoryChange::Added)
+        .map(|i| SyncedItem {
+            kind: i.kind,
+            trakt_id: i.trakt_id,
+            watched_at: i.watched_at,
+            synced_at: batch.pushed_at,
+            pushed: true,
+        })
+        .collect();
+    let (removed, forgotten): (Vec<_>, Vec<_>) = items
+        .iter()
+        .filter(|i| i.change == HistoryChange::Removed)
+        .partition(|i| i.pushed);
+
+    let mut summary = SyncSummary::default();
+    remove_plays(client, &added, &mut summary).await?;
+    for item in added.iter() {
+        cache.forget_synced(item.kind, item.trakt_id).await?;
+    }
+
+    for payload in item_payloads(removed.into_iter(), &state) {
+        let text = t_api::do_post(client, "sync/history", &payload).await?;
+        let response = t_api::decode::<HistoryResponse>(&text)?;
+
+        let accepted = accepted_items(&payload, &response);
+        summary.batches += 1;
+        summary.episodes_added += response.added.episodes;
+        summary.not_found += payload.len() - accepted.len();
+        summary.accepted += accepted.len();
+        cache.record_synced(accepted).await?;
+    }
+
+    // trakt kept these plays, the push only stopped tracking them
+    let now = Utc::now().naive_utc();
+    let forgotten = forgotten
+        .into_iter()
+        .map(|i| SyncedItem {
+            kind: i.kind,
+            trakt_id: i.trakt_id,
+            watched_at: i.watched_at,
+            synced_at: now,
+            pushed: false,
+        })
+        .collect();
+    cache.record_synced(forgotten).await?;
+
+    let mut restored = HashSet::new();
+    for item in items {
+        if restored.insert((item.kind, item.trakt_id)) {
+            restore_local(cache, &state, item.kind, item.trakt_id, item.previous()).await?;
+        }
+    }
+
+    cache
+        .mark_batch_reverted(batch.id, Utc::now().naive_utc())
+        .await?;
+    info!("Reverted sync batch {}: {:?}", batch.id, summary);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::UserStatusEpisode;
+
+    fn show(trakt_id: i32, user_status: UserStatusShow) -> TraktShow {
+        TraktShow {
+            imdb_id: format!("tt{}", trakt_id),
+            trakt_id: Some(trakt_id),
+            primary_title: "title".to_string(),
+            original_title: "title".to_string(),
+            country: None,
+            release_year: Some(2000),
+            network: None,
+            no_seasons: None,
+            no_episodes: None,
+            overview: None,
+            user_status,
+            status_changed_at: None,
+        }
+    }
+
+    fn season(id: i32, show_id: i32, user_status: UserStatusSeason) -> TraktSeason {
+        TraktSeason {
+            id,
+            title: format!("Season {}", id),
+            first_aired: None,
+            show_id,
+            show_imdb_id: format!("tt{}", show_id),
+            season_number: id,
+            episode_count: 10,
+            user_status,
+            watched_at: None,
+        }
+    }
+
+    fn episode(id: i32, watched_at: Option<NaiveDateTime>) -> TraktEpisode {
+        TraktEpisode {
+            id,
+            season_id: 1,
+            show_id: 1,
+            season_number: 1,
+            episode_number: id,
+            title: format!("Episode {}", id),
+            first_aired: None,
+            watched_at,
+            user_status: UserStatusEpisode::Watched,
+        }
+    }
+
+    #[test]
+    fn builds_history_payload() {
+        let watched = Utc
+            .with_ymd_and_hms(2023, 7, 1, 20, 0, 0)
+            .unwrap()
+            .naive_utc();
+        let history = LocalHistory {
+            shows: vec![
+                show(1, UserStatusShow::Watched),
+                show(2, UserStatusShow::Todo),
+            ],
+            seasons: vec![
+                // covered by show 1 being watched
+                season(10, 1, UserStatusSeason::OnRelease),
+                season(20, 2, UserStatusSeason::OnRelease),
+                season(21, 2, UserStatusSeason::Unfilled),
+            ],
+            episodes: vec![episode(100, Some(watched)), episode(101, None)],
+        };
+
+        let payloads = history_payloads(&history, &[]);
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&payloads[0]).unwrap(),
+            serde_json::json!({
+                "shows": [
+                    {"ids": {"trakt": 1}, "watched_at": "released"},
+                    {"ids": {"trakt": 2}, "seasons": [{"number": 20, "watched_at": "released"}]},
+                ],
+                "episodes": [
+                    {"ids": {"trakt": 100}, "watched_at": "2023-07-01T20:00:00Z"},
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn leaves_queued_changes_to_the_outbox() {
+        let watched = Utc::now().naive_utc();
+        let in_other_season = |id| TraktEpisode {
+            season_id: 2,
+            season_number: 2,
+            ..episode(id, Some(watched))
+        };
+        let history = LocalHistory {
+            seasons: vec![season(1, 1, UserStatusSeason::OnRelease)],
+            episodes: vec![in_other_season(100), in_other_season(101)],
+            ..Default::default()
+        };
+        let queued = |kind, trakt_id| OutboxItem {
+            id: trakt_id,
+            kind,
+            trakt_id,
+            operation: OutboxOperation::AddHistory,
+            watched_at: None,
+            payload: String::new(),
+            state: crate::models::OutboxState::Pending,
+            attempts: 0,
+            next_attempt_at: watched,
+            last_error: None,
+            created_at: watched,
+        };
+
+        let plan = SyncPlan::new(
+            &history,
+            &LocalState::default(),
+            &[],
+            &[
+                queued(SyncItemKind::Season, 1),
+                queued(SyncItemKind::Episode, 100),
+            ],
+        );
+        assert_eq!(plan.add.len(), 1);
+        assert!(plan.add[0].shows.is_empty());
+        assert_eq!(plan.add[0].episodes.len(), 1);
+        assert_eq!(plan.add[0].episodes[0].ids.trakt, Some(101));
+    }
+
+    #[test]
+    fn leaves_out_episodes_of_watched_seasons() {
+        let watched = Utc::now().naive_utc();
+        let mut in_other_season = episode(101, Some(watched));
+        in_other_season.season_id = 2;
+        in_other_season.season_number = 2;
+        let history = LocalHistory {
+            seasons: vec![season(1, 1, UserStatusSeason::OnRelease)],
+            episodes: vec![episode(100, Some(watched)), in_other_season],
+            ..Default::default()
+        };
+
+        let payloads = history_payloads(&history, &[]);
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].shows[0].seasons.len(), 1);
+        assert_eq!(payloads[0].episodes.len(), 1);
+        assert_eq!(payloads[0].episodes[0].ids.trakt, Some(101));
+    }
+
+    #[test]
+    fn uses_picked_season_dates() {
+        let picked = Utc
+            .with_ymd_and_hms(2023, 8, 1, 21, 30, 0)
+            .unwrap()
+            .naive_utc();
+        let mut watched = season(1, 1, UserStatusSeason::OtherDate);
+        watched.watched_at = Some(picked);
+        let history = LocalHistory {
+            seasons: vec![watched.clone(), season(2, 1, UserStatusSeason::OtherDate)],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            season_watched_at(&watched),
+            Some(Some(WatchedAt::At(picked)))
+        );
+        let payloads = history_payloads(&history, &[]);
+        assert_eq!(
+            serde_json::to_value(&payloads[0]).unwrap(),
+            serde_json::json!({
+                "shows": [{"ids": {"trakt": 1}, "seasons": [
+                    {"number": 1, "watched_at": "2023-08-01T21:30:00Z"},
+                    {"number": 2},
+                ]}],
+            })
+        );
+    }
+
+    #[test]
+    fn skips_synced_and_batches() {
+        let history = LocalHistory {
+            shows: (1..=250)
+                .map(|i| show(i, UserStatusShow::Watched))
+                .collect(),
+            ..Default::default()
+        };
+        let synced = vec![SyncedItem {
+            kind: SyncItemKind::Show,
+            trakt_id: 1,
+            watched_at: None,
+            synced_at: Utc::now().naive_utc(),
+            pushed: true,
+        }];
+
+        let payloads = history_payloads(&history, &synced);
+        let sizes: Vec<_> = payloads.iter().map(HistoryPayload::len).collect();
+        assert_eq!(sizes, vec![100, 100, 49]);
+    }
+
+    #[test]
+    fn records_accepted_items() {
+        let history = LocalHistory {
+            seasons: vec![
+                season(10, 1, UserStatusSeason::OnRelease),
+                season(20, 2, UserStatusSeason::OnRelease),
+            ],
+            episodes: vec![episode(100, Some(Utc::now().naive_utc()))],
+            ..Default::default()
+        };
+        let payload = &history_payloads(&history, &[])[0];
+
+        let response: HistoryResponse = serde_json::from_str(
+            r#"{
+                "added": {"movies": 0, "episodes": 11},
+                "not_found": {
+                    "movies": [],
+                    "shows": [{"ids": {"trakt": 2}}],
+                    "seasons": [],
+                    "episodes": [{"ids": {"trakt": 100}}]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let accepted = accepted_items(payload, &response);
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].kind, SyncItemKind::Season);
+        assert_eq!(accepted[0].trakt_id, 10);
+    }
+
+    #[test]
+    fn previews_adds_and_removals() {
+        let at = |h| {
+            Utc.with_ymd_and_hms(2023, 8, 1, h, 0, 0)
+                .unwrap()
+                .naive_utc()
+        };
+        let mut bravo = show(2, UserStatusShow::Todo);
+        bravo.primary_title = "Bravo".to_string();
+        let mut alpha = show(3, UserStatusShow::Unwatched);
+        alpha.primary_title = "Alpha".to_string();
+        let in_alpha = |id, watched_at| TraktEpisode {
+            season_id: 30,
+            show_id: 3,
+            season_number: 30,
+            ..episode(id, watched_at)
+        };
+
+        let state = LocalState {
+            shows: vec![show(1, UserStatusShow::Watched), bravo, alpha],
+            seasons: vec![
+                season(10, 1, UserStatusSeason::Unfilled),
+                season(20, 2, UserStatusSeason::OnRelease),
+                season(21, 2, UserStatusSeason::Unfilled),
+                season(30, 3, UserStatusSeason::Unfilled),
+            ],
+            episodes: vec![
+                in_alpha(300, None),
+                in_alpha(301, Some(at(21))),
+                in_alpha(302, Some(at(20))),
+            ],
+        };
+        let history = LocalHistory {
+            shows: vec![state.shows[0].clone()],
+            seasons: vec![state.seasons[1].clone()],
+            episodes: state.episodes[1..].to_vec(),
+        };
+        let synced = |kind, trakt_id, watched_at| SyncedItem {
+            kind,
+            trakt_id,
+            watched_at,
+            synced_at: at(22),
+            pushed: true,
+        };
+        let synced = vec![
+            // still watched
+            synced(SyncItemKind::Show, 1, None),
+            synced(SyncItemKind::Episode, 302, Some(at(20))),
+            // hidden since, which keeps its history
+            synced(SyncItemKind::Show, 3, None),
+            // unwatched since
+            synced(SyncItemKind::Season, 21, None),
+            synced(SyncItemKind::Episode, 300, Some(at(20))),
+            // watched at another time
+            synced(SyncItemKind::Episode, 301, Some(at(20))),
+        ];
+
+        let plan = SyncPlan::new(&history, &state, &synced, &[]);
+        let removed: Vec<_> = plan.remove.iter().map(|i| (i.kind, i.trakt_id)).collect();
+        assert_eq!(
+            removed,
+            vec![
+                (SyncItemKind::Season, 21),
+                (SyncItemKind::Episode, 300),
+                (SyncItemKind::Episode, 301),
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&plan.add).unwrap(),
+            serde_json::json!([{
+                "shows": [{"ids": {"trakt": 2}, "seasons": [{"number": 20, "watched_at": "released"}]}],
+                "episodes": [{"ids": {"trakt": 301}, "watched_at": "2023-08-01T21:00:00Z"}],
+            }])
+        );
+
+        let preview = SyncPreview::new(plan, &state);
+        let season = |number, added, removed| SeasonDiff {
+            number,
+            added,
+            removed,
+        };
+        assert_eq!(
+            preview.shows,
+            vec![
+                ShowDiff {
+                    trakt_id: 3,
+                    title: "Alpha".to_string(),
+                    added: 1,
+                    removed: 2,
+                    seasons: vec![season(30, 1, 2)],
+                },
+                ShowDiff {
+                    trakt_id: 2,
+                    title: "Bravo".to_string(),
+                    added: 10,
+                    removed: 10,
+                    seasons: vec![season(20, 10, 0), season(21, 0, 10)],
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_episodes_aired_after_their_season_synced() {
+        let cache = PersistentDb::in_memory().unwrap();
+        let now = Utc::now();
+        let show = show(1388, UserStatusShow::Todo);
+        cache.update_show(show.clone()).await.unwrap();
+        let api_seasons: Vec<t_api::ApiSeasonDetails> = serde_json::from_value(serde_json::json!([
+            {"number": 1, "ids": {"trakt": 3950}, "episode_count": 2, "title": "Season 1",
+             "first_aired": now - chrono::Duration::days(10), "overview": null, "network": null},
+        ]))
+        .unwrap();
+        let season = cache
+            .store_show_details(show, &api_seasons)
+            .await
+            .unwrap()
+            .remove(0);
+        let api_episodes = |second_aired: chrono::DateTime<Utc>| -> Vec<t_api::ApiEpisodeDetails> {
+            serde_json::from_value(serde_json::json!([
+                {"season": 1, "number": 1, "title": "Aired", "ids": {"trakt": 1}, "overview": null,
+                 "first_aired": now - chrono::Duration::days(10)},
+                {"season": 1, "number": 2, "title": "Next", "ids": {"trakt": 2}, "overview": null,
+                 "first_aired": second_aired},
+            ]))
+            .unwrap()
+        };
+        cache
+            .store_episodes(
+                season.clone(),
+                &api_episodes(now + chrono::Duration::days(7)),
+            )
+            .await
+            .unwrap();
+        let season = TraktSeason {
+            user_status: UserStatusSeason::OnRelease,
+            ..season
+        };
+        cache.update_season(season.clone()).await.unwrap();
+
+        // the season went out two days ago, covering the episode that had aired by then
+        let history = cache.local_history().await.unwrap();
+        let payloads = history_payloads(&history, &[]);
+        assert_eq!(payloads[0].shows[0].seasons.len(), 1);
+        assert!(payloads[0].episodes.is_empty());
+        cache
+            .record_synced(vec![SyncedItem {
+                kind: SyncItemKind::Season,
+                trakt_id: season.id,
+                watched_at: None,
+                synced_at: (now - chrono::Duration::days(2)).naive_utc(),
+                pushed: true,
+            }])
+            .await
+            .unwrap();
+
+        // the next episode aired yesterday, and is filled in on release
+        cache
+            .store_episodes(season, &api_episodes(now - chrono::Duration::days(1)))
+            .await
+            .unwrap();
+        cache.fill_released_episodes().await.unwrap();
+
+        let history = cache.local_history().await.unwrap();
+        let synced = cache.synced_items().await.unwrap();
+        let payloads = history_payloads(&history, &synced);
+        assert_eq!(payloads.len(), 1);
+        assert!(payloads[0].shows.is_empty());
+        let sent: Vec<_> = payloads[0].episodes.iter().map(|e| e.ids.trakt).collect();
+        assert_eq!(sent, vec![Some(2)]);
+    }
+
+    #[tokio::test]
+    async fn pushes_and_reverts_a_batch() {
+        use crate::trakt::t_mock::start_logged_in;
+
+        let (_mock, cache, client) = start_logged_in().await;
+
+        // in line with trakt, nothing to push
+        crate::trakt::t_import::pull_watched(&client, &cache)
+            .await
+            .unwrap();
+        let summary = push_history(&client, &cache).await.unwrap();
+        assert_eq!(summary.batch_id, None);
+
+        // watch breaking bad S02E01 (in a season that's only partly watched) on another day
+        let state = cache.local_state(vec![]).await.unwrap();
+        let original = state
+            .episodes
+            .iter()
+            .find(|e| e.show_id == 1388 && e.season_number == 2 && e.episode_number == 1)
+            .unwrap();
+        let episode_id = original.id;
+        let aired = original.watched_at;
+        let moved = Utc
+            .with_ymd_and_hms(2019, 3, 20, 12, 0, 0)
+            .unwrap()
+            .naive_utc();
+        cache
+            .update_episode(TraktEpisode {
+                watched_at: Some(moved),
+                ..original.clone()
+            })
+            .await
+            .unwrap();
+
+        let summary = push_history(&client, &cache).await.unwrap();
+        let batch_id = summary.batch_id.unwrap();
+        let batches = cache.sync_batches().await.unwrap();
+        let (batch, items) = &batches[0];
+        assert_eq!(batch.id, batch_id);
+        let changes: Vec<_> = items.iter().map(|i| (i.change, i.watched_at)).collect();
+        assert_eq!(
+            changes,
+            vec![
+                (HistoryChange::Removed, aired),
+                (HistoryChange::Added, Some(moved)),
+            ]
+        );
+
+        // the imported play wasn't ours, so trakt keeps it next to the new one
+        let plays = |client| async move {
+            let plays = t_api::query_history(client, "episodes", episode_id)
+                .await
+                .unwrap();
+            let mut times: Vec<_> = plays.iter().map(|p| p.watched_at.naive_utc()).collect();
+            times.sort();
+            times
+        };
+        let imported = aired.unwrap();
+        assert_eq!(plays(&client).await, vec![imported, moved]);
+
+        // undo it: only the added play goes, and the old date is back locally and in sync
+        revert_batch(&client, &cache, batch, items).await.unwrap();
+        assert_eq!(plays(&client).await, vec![imported]);
+        let state = cache.local_state(vec![]).await.unwrap();
+        let episode = state.episodes.iter().find(|e| e.id == episode_id).unwrap();
+        assert_eq!(episode.watched_at, aired);
+        let synced = cache.synced_items().await.unwrap();
+        assert!(synced
+            .iter()
+            .any(|s| s.trakt_id == episode_id && s.watched_at == aired));
+
+        let (batch, items) = &cache.sync_batches().await.unwrap()[0];
+        assert!(batch.reverted_at.is_some());
+        assert!(revert_batch(&client, &cache, batch, items).await.is_err());
+    }
+}